@@ -0,0 +1,76 @@
+use crate::error::{SrwscError, ErrorCode};
+
+use std::fmt;
+use std::fs;
+
+// What srwc logs in with: a password, asked for when it starts, or an API
+// token created with "srws token" and kept in a file.
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token(String),
+}
+
+// Keeps the secrets out of the configuration that srwc prints.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {} }}", user),
+            Credentials::Token(_) => write!(f, "Token"),
+        }
+    }
+}
+
+impl Credentials {
+    pub fn prompt(user: &str) -> Result<Self, SrwscError> {
+        if user.is_empty() || !user.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+            return Err(SrwscError::new(ErrorCode::ErrorAuth,
+                                       format!("invalid user name {:?}", user)));
+        }
+        let password = rpassword::read_password_from_tty(
+            Some(&format!("Password for {}: ", user)))
+            .map_err(|e| SrwscError::new(ErrorCode::ErrorAuth,
+                                         format!("cannot read the password: {}", e)))?;
+        check_secret(&password)?;
+        Ok(Credentials::Password {
+            user: user.to_string(),
+            password: password,
+        })
+    }
+
+    pub fn from_token_file(path: &str) -> Result<Self, SrwscError> {
+        let token = fs::read_to_string(path).map_err(|e| {
+            SrwscError::new(ErrorCode::ErrorAuth,
+                            format!("cannot read token file {}: {}", path, e))
+        })?;
+        let token = token.trim();
+        check_secret(token)?;
+        Ok(Credentials::Token(token.to_string()))
+    }
+
+    // The TCP login command.
+    pub fn login_command(&self) -> String {
+        match self {
+            Credentials::Password { user, password } => format!("login {} {}", user, password),
+            Credentials::Token(token) => format!("login -t {}", token),
+        }
+    }
+
+    // The value of the gRPC "authorization" metadata.
+    pub fn authorization(&self) -> String {
+        match self {
+            Credentials::Password { user, password } => {
+                format!("Basic {}", base64::encode(format!("{}:{}", user, password)))
+            },
+            Credentials::Token(token) => format!("Bearer {}", token),
+        }
+    }
+}
+
+// Messages of the TCP protocol are ASCII, and so are gRPC metadata values.
+fn check_secret(secret: &str) -> Result<(), SrwscError> {
+    if secret.is_empty() || !secret.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return Err(SrwscError::new(ErrorCode::ErrorAuth,
+                                   String::from("the password or token has to be printable ASCII")));
+    }
+    Ok(())
+}
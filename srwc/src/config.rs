@@ -1,3 +1,6 @@
+use crate::auth::Credentials;
+use crate::compression::Compression;
+
 use std::net::SocketAddr;
   
 pub const CLIENT_NAME: &str = "srwc";
@@ -6,16 +9,40 @@ pub const VERSION: &str = "0.1.0";
 pub const DEFAULT_TYPE: ServerType = ServerType::HTTP;
 pub const DEFAULT_ADDR: &str = "0.0.0.0:1417";
 pub const DEFAULT_STORAGE: &str = "/tmp/srwc";
-pub const BUFFER_SIZE: usize = 8;
+pub const DEFAULT_HOSTNAME: &str = "localhost";
+pub const DEFAULT_LOG_LEVEL: &str = "warn";
+// gRPC file streams are cut into messages of this size. The upper bound keeps
+// every message well below the default 4 MiB message limit of tonic.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+// Upper bound for a typed reply (a listing) received over TCP.
+pub const MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024;
 
 pub const ACK_MESSAGE: &str              = "ACK";
+pub const NAK_MESSAGE: &str              = "NAK";
 pub const PREPARE_TRANSFER_MESSAGE: &str = "prepare transfer file";
 pub const CANNOT_FIND_FILE_MESSAGE: &str = "cannot find file";
 pub const REMOVED_OK_MESSAGE: &str       = "removed ok";
-pub const REMOVED_NOK_MESSAGE: &str      = "removed nok";
+pub const MKDIR_OK_MESSAGE: &str         = "mkdir ok";
+pub const CHECKSUM_OK_MESSAGE: &str      = "checksum ok";
+pub const CHECKSUM_MISMATCH_MESSAGE: &str = "checksum mismatch";
+pub const FILE_INFO_MESSAGE: &str        = "file info";
+pub const FILE_LIST_MESSAGE: &str        = "file list";
+pub const LOGIN_OK_MESSAGE: &str         = "login ok";
+pub const LOGIN_FAILED_MESSAGE: &str     = "login failed";
+pub const LOGIN_REQUIRED_MESSAGE: &str   = "login required";
+pub const QUOTA_INFO_MESSAGE: &str       = "quota info";
+// Followed by a pb::Error frame.
+pub const ERROR_MESSAGE: &str            = "error";
 
 pub const GRPC_METADATA_FILENAME: &str  = "filename";
+pub const GRPC_METADATA_OFFSET: &str    = "offset";
+pub const GRPC_METADATA_LENGTH: &str    = "length";
+pub const GRPC_METADATA_COMPRESSION: &str = "compression";
+pub const GRPC_METADATA_ACCEPT_COMPRESSION: &str = "accept-compression";
+pub const GRPC_METADATA_AUTHORIZATION: &str = "authorization";
 pub const GRPC_URL_SCHEMA: &str = "http://";
+pub const GRPC_TLS_URL_SCHEMA: &str = "https://";
 
 #[derive(Debug)]
 pub enum ServerType {
@@ -24,11 +51,34 @@ pub enum ServerType {
     GRPC,
 }
 
+// How to log in, as set up before the credentials are read.
+#[derive(Debug)]
+pub enum Login {
+    // The password is asked for.
+    User(String),
+    TokenFile(String),
+}
+
 #[derive(Debug)]
 pub struct ClientConfig {
     pub server_type: ServerType,
     pub address: SocketAddr,
     pub storage: String,
+    pub ca: Option<String>,
+    pub hostname: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub chunk_size: usize,
+    pub compression: Compression,
+    pub login: Option<Login>,
+    pub credentials: Option<Credentials>,
+    pub log_level: String,
+    pub log_file: Option<String>,
+    // The configuration file the settings were read from, if any.
+    pub config_file: Option<String>,
+    // The command of "srwc get", "put", "rm" or "ls", run instead of the
+    // prompt.
+    pub command: Option<String>,
 }
 
 impl ClientConfig {
@@ -37,6 +87,18 @@ impl ClientConfig {
             server_type: DEFAULT_TYPE,
             address: DEFAULT_ADDR.parse().expect("Unable to parse socket address"),
             storage: DEFAULT_STORAGE.to_string(),
+            ca: None,
+            hostname: DEFAULT_HOSTNAME.to_string(),
+            cert: None,
+            key: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            compression: Compression::None,
+            login: None,
+            credentials: None,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_file: None,
+            config_file: None,
+            command: None,
         }
     }
 }
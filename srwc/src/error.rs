@@ -1,5 +1,11 @@
+use crate::pb;
+use crate::pb::ErrorKind;
+
+use prost::Message;
 use std::fmt;
 use std::error::Error;
+use std::io;
+use tonic::{Code, Status};
 
 pub struct SrwscError {
     code: ErrorCode,
@@ -12,9 +18,22 @@ impl fmt::Display for SrwscError {
             ErrorCode::ErrorAck => "Failed to get ACK message",
             ErrorCode::ErrorRequest => "Failure to request",
             ErrorCode::NotExistFile => "Not exist file",
+            ErrorCode::ErrorTls => "Failed to set up TLS",
+            ErrorCode::InvalidFileName => "Invalid file name",
+            ErrorCode::OutOfRange => "Requested range is out of the file",
+            ErrorCode::ChecksumMismatch => "Checksum does not match",
+            ErrorCode::InvalidArgument => "Invalid argument",
+            ErrorCode::ErrorAuth => "Failed to authenticate",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::PermissionDenied => "Permission denied",
+            ErrorCode::ErrorIo => "I/O error",
+            ErrorCode::ErrorProtocol => "Protocol error",
         };
 
-        write!(f, "{}", err_msg)
+        match self.message.is_empty() {
+            true => write!(f, "{}", err_msg),
+            false => write!(f, "{}: {}", err_msg, self.message),
+        }
     }
 }
 
@@ -37,11 +56,230 @@ impl SrwscError {
             message: message,
         }
     }
+
+    pub fn code(&self) -> &ErrorCode {
+        &self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<io::Error> for SrwscError {
+    fn from(e: io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => ErrorCode::NotExistFile,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidArgument,
+            io::ErrorKind::InvalidData => ErrorCode::ErrorProtocol,
+            _ => ErrorCode::ErrorIo,
+        };
+        SrwscError::new(code, e.to_string())
+    }
+}
+
+// The gRPC client returns boxed errors; the ones that are not SrwscError, like
+// a connection that cannot be made, are taken as failed requests.
+impl From<Box<dyn Error>> for SrwscError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<SrwscError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => SrwscError::from(*e),
+            Err(e) => SrwscError::new(ErrorCode::ErrorRequest, e.to_string()),
+        }
+    }
 }
 
+// The reverse of what srws does with its errors, so that gRPC failures are
+// reported like the ones of the TCP protocol.
+impl From<Status> for SrwscError {
+    fn from(status: Status) -> Self {
+        let code = ErrorCode::from_status(status.code(), status.details());
+        SrwscError::new(code, status.message().to_string())
+    }
+}
+
+// The same codes as srws; see there.
 #[derive(Debug)]
 pub enum ErrorCode {
+    // Protocol
     ErrorAck,
+    ErrorProtocol,
     ErrorRequest,
+    // Not found
     NotExistFile,
+    // Validation
+    InvalidFileName,
+    InvalidArgument,
+    OutOfRange,
+    ChecksumMismatch,
+    // Permission
+    ErrorTls,
+    ErrorAuth,
+    PermissionDenied,
+    // Quota
+    QuotaExceeded,
+    // I/O
+    ErrorIo,
+}
+
+impl ErrorCode {
+    // The code of an error frame sent by srws.
+    pub fn from_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Unknown => ErrorCode::ErrorRequest,
+            ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            ErrorKind::NotFound => ErrorCode::NotExistFile,
+            ErrorKind::InvalidFileName => ErrorCode::InvalidFileName,
+            ErrorKind::InvalidArgument => ErrorCode::InvalidArgument,
+            ErrorKind::OutOfRange => ErrorCode::OutOfRange,
+            ErrorKind::ChecksumMismatch => ErrorCode::ChecksumMismatch,
+            ErrorKind::QuotaExceeded => ErrorCode::QuotaExceeded,
+            ErrorKind::Unauthenticated => ErrorCode::ErrorAuth,
+            ErrorKind::Io => ErrorCode::ErrorIo,
+            ErrorKind::Protocol => ErrorCode::ErrorProtocol,
+        }
+    }
+
+    // The code of a gRPC status sent by srws: the kind of the pb::Error in its
+    // details, or else the closest one to the status code.
+    pub fn from_status(code: Code, details: &[u8]) -> Self {
+        let kind = match details {
+            [] => None,
+            details => pb::Error::decode(details)
+                .ok()
+                .and_then(|error| ErrorKind::from_i32(error.kind)),
+        };
+        if let Some(kind) = kind {
+            return ErrorCode::from_kind(kind);
+        }
+        match code {
+            Code::NotFound => ErrorCode::NotExistFile,
+            Code::PermissionDenied => ErrorCode::PermissionDenied,
+            Code::Unauthenticated => ErrorCode::ErrorAuth,
+            Code::ResourceExhausted => ErrorCode::QuotaExceeded,
+            Code::InvalidArgument => ErrorCode::InvalidArgument,
+            Code::OutOfRange => ErrorCode::OutOfRange,
+            Code::DataLoss => ErrorCode::ChecksumMismatch,
+            Code::Aborted => ErrorCode::ErrorProtocol,
+            Code::Internal |
+            Code::Unavailable => ErrorCode::ErrorIo,
+            _ => ErrorCode::ErrorRequest,
+        }
+    }
+
+    // The exit status of srwc when it fails with this code, so that scripts
+    // can tell the failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorCode::ErrorAck => 2,
+            ErrorCode::ErrorProtocol => 3,
+            ErrorCode::ErrorRequest => 4,
+            ErrorCode::NotExistFile => 5,
+            ErrorCode::InvalidFileName => 6,
+            ErrorCode::InvalidArgument => 7,
+            ErrorCode::OutOfRange => 8,
+            ErrorCode::ChecksumMismatch => 9,
+            ErrorCode::ErrorTls => 10,
+            ErrorCode::ErrorAuth => 11,
+            ErrorCode::PermissionDenied => 12,
+            ErrorCode::QuotaExceeded => 13,
+            ErrorCode::ErrorIo => 14,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [ErrorKind; 11] = [
+        ErrorKind::Unknown,
+        ErrorKind::PermissionDenied,
+        ErrorKind::NotFound,
+        ErrorKind::InvalidFileName,
+        ErrorKind::InvalidArgument,
+        ErrorKind::OutOfRange,
+        ErrorKind::ChecksumMismatch,
+        ErrorKind::QuotaExceeded,
+        ErrorKind::Unauthenticated,
+        ErrorKind::Io,
+        ErrorKind::Protocol,
+    ];
+
+    fn codes() -> Vec<ErrorCode> {
+        vec![ErrorCode::ErrorAck, ErrorCode::ErrorProtocol, ErrorCode::ErrorRequest,
+             ErrorCode::NotExistFile, ErrorCode::InvalidFileName, ErrorCode::InvalidArgument,
+             ErrorCode::OutOfRange, ErrorCode::ChecksumMismatch, ErrorCode::ErrorTls,
+             ErrorCode::ErrorAuth, ErrorCode::PermissionDenied, ErrorCode::QuotaExceeded,
+             ErrorCode::ErrorIo]
+    }
+
+    // The details of a status as srws sends them.
+    fn details(kind: ErrorKind) -> Vec<u8> {
+        let error = pb::Error {
+            kind: kind as i32,
+            message: String::from("why"),
+        };
+        let mut details = Vec::new();
+        error.encode(&mut details).unwrap();
+        details
+    }
+
+    fn exit_code(status: Status) -> i32 {
+        SrwscError::from(status).code().exit_code()
+    }
+
+    #[test]
+    fn grpc_errors_are_reported_like_tcp_errors() {
+        for &kind in &KINDS {
+            // The status code does not matter once the kind is known.
+            for &code in &[Code::Unknown, Code::InvalidArgument, Code::Internal] {
+                assert_eq!(ErrorCode::from_status(code, &details(kind)).exit_code(),
+                           ErrorCode::from_kind(kind).exit_code());
+            }
+        }
+    }
+
+    #[test]
+    fn grpc_errors_without_a_kind_go_by_the_status_code() {
+        assert_eq!(exit_code(Status::not_found("")), ErrorCode::NotExistFile.exit_code());
+        assert_eq!(exit_code(Status::permission_denied("")), ErrorCode::PermissionDenied.exit_code());
+        assert_eq!(exit_code(Status::unauthenticated("")), ErrorCode::ErrorAuth.exit_code());
+        assert_eq!(exit_code(Status::resource_exhausted("")), ErrorCode::QuotaExceeded.exit_code());
+        assert_eq!(exit_code(Status::invalid_argument("")), ErrorCode::InvalidArgument.exit_code());
+        assert_eq!(exit_code(Status::out_of_range("")), ErrorCode::OutOfRange.exit_code());
+        assert_eq!(exit_code(Status::data_loss("")), ErrorCode::ChecksumMismatch.exit_code());
+        assert_eq!(exit_code(Status::aborted("")), ErrorCode::ErrorProtocol.exit_code());
+        assert_eq!(exit_code(Status::internal("")), ErrorCode::ErrorIo.exit_code());
+        assert_eq!(exit_code(Status::unavailable("")), ErrorCode::ErrorIo.exit_code());
+        assert_eq!(exit_code(Status::unknown("")), ErrorCode::ErrorRequest.exit_code());
+    }
+
+    #[test]
+    fn every_code_has_its_own_exit_code() {
+        let mut exit_codes: Vec<i32> = codes().iter().map(|code| code.exit_code()).collect();
+        exit_codes.sort();
+        assert_eq!(exit_codes, (2..=14).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn exit_codes_are_stable() {
+        // Scripts depend on these.
+        assert_eq!(ErrorCode::NotExistFile.exit_code(), 5);
+        assert_eq!(ErrorCode::ChecksumMismatch.exit_code(), 9);
+        assert_eq!(ErrorCode::ErrorAuth.exit_code(), 11);
+        assert_eq!(ErrorCode::PermissionDenied.exit_code(), 12);
+        assert_eq!(ErrorCode::QuotaExceeded.exit_code(), 13);
+    }
+
+    #[test]
+    fn authentication_errors_exit_alike_over_both_protocols() {
+        assert_eq!(exit_code(Status::unauthenticated("")),
+                   ErrorCode::from_kind(ErrorKind::Unauthenticated).exit_code());
+    }
 }
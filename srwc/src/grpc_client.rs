@@ -1,85 +1,241 @@
-mod pb {
-    tonic::include_proto!("srwsc.pb");
-}
-
-extern crate console;
-
+use crate::checksum::Checksum;
+use crate::compression::{Compression, Decoder, Encoder, Output};
 use crate::config;
 use crate::config::ClientConfig;
 use crate::error::{SrwscError, ErrorCode};
 use crate::misc;
+use crate::misc::Transferred;
+use crate::pb;
+use crate::tls;
 
 use pb::srwsc_client::SrwscClient;
-use pb::{FileStream, SrwscRequest};
+use pb::{Empty, FileInfo, FileListResponse, FileStream, ListRequest, QuotaInfo, SrwscRequest};
 
+use std::io;
 use std::io::prelude::*;
-use console::style;
 use std::fs::File;
-use futures::stream;
+use futures::executor::block_on;
 use tonic::metadata::MetadataValue;
 use std::io::BufWriter;
+use std::cmp;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::{debug, info, warn};
+use std::time::Instant;
 
-async fn download(filename: &str,
+// A non-zero length asks for that many bytes from offset only; they are saved
+// as a file of their own rather than resuming the local copy.
+async fn download(remote: &str,
+                  filename: &str,
+                  offset: u64,
+                  length: u64,
+                  compression: Compression,
                   storage: &str,
                   client: &mut SrwscClient<tonic::transport::Channel>)
-                  -> Result<(), Box<dyn std::error::Error>> {
-    let mut fullname = String::from(storage);
-    fullname.push_str("/");
-    fullname.push_str(filename);
-
-    let request = tonic::Request::new(
+                  -> Result<Transferred, Box<dyn std::error::Error>> {
+    let mut request = tonic::Request::new(
         SrwscRequest {
-            filename: String::from(filename)
+            filename: String::from(remote),
+            offset: offset,
+            length: length,
         },
     );
+    if compression != Compression::None {
+        request.metadata_mut()
+            .insert(config::GRPC_METADATA_ACCEPT_COMPRESSION,
+                    MetadataValue::from_static(compression.offer()));
+    }
 
-    let mut stream = client
+    let started = Instant::now();
+    let response = client
         .get(request)
-        .await?
-        .into_inner();
+        .await
+        .map_err(SrwscError::from)?;
+    let compression = match response.metadata().get(config::GRPC_METADATA_COMPRESSION) {
+        Some(value) => value.to_str().ok()
+            .and_then(Compression::from_name)
+            .ok_or_else(|| SrwscError::new(ErrorCode::ErrorRequest,
+                                           String::from("Unknown compression")))?,
+        None => Compression::None,
+    };
+    let mut stream = response.into_inner();
+
+    let local_offset = match length {
+        0 => offset,
+        _ => 0,
+    };
+    let mut file = BufWriter::new(misc::open_local_file(filename, storage, local_offset)?);
+    let mut checksum = misc::local_checksum(filename, storage, local_offset)?;
+    let mut digest = String::new();
+    let mut received = 0;
+    let written = {
+        let output = Output::new(&mut file, &mut checksum, u64::MAX);
+        let mut decoder = Decoder::new(compression, output)?;
+        while let Some(file_stream) = stream.message().await.map_err(SrwscError::from)? {
+            decoder.write_all(&file_stream.data)?;
+            received += file_stream.data.len() as u64;
+            if !file_stream.digest.is_empty() {
+                digest = file_stream.digest;
+            }
+        }
+        decoder.finish()?.written()
+    };
+    file.flush()?;
+    drop(file);
 
-    let mut file = BufWriter::new(File::create(fullname).unwrap());
-    while let Some(file_stream) = stream.message().await? {
-        file.write(&file_stream.data).unwrap();
-        file.flush().unwrap();
+    if digest != checksum.finish() {
+        warn!(filename, "Checksum mismatch, removing the download");
+        misc::remove_local_file(filename, storage);
+        return Err(Box::new(SrwscError::new(ErrorCode::ChecksumMismatch, digest)));
+    }
+    info!(filename, bytes = written, wire_bytes = received, compression = compression.name(),
+          duration = ?started.elapsed(), "Received");
+    debug!(digest = %digest, "Verified");
+    Ok(Transferred {
+        bytes: written,
+        wire_bytes: received,
+        compression: compression,
+    })
+}
+
+// Runs on a blocking thread. A chunk is only read once the channel has room
+// for it, so the upload never holds more than a few chunks in memory. A read
+// error ends the stream without a digest, which makes the server discard it.
+// Returns the number of bytes sent, after compression.
+fn send_file(mut file: File,
+             mut checksum: Checksum,
+             length: u64,
+             chunk_size: usize,
+             compression: Compression,
+             tx: mpsc::Sender<FileStream>)
+             -> io::Result<u64> {
+    let mut encoder = Encoder::new(compression, MessageWriter::new(tx, chunk_size))?;
+    let mut buf = vec![0u8; chunk_size];
+    let mut remaining_data = length;
+    while remaining_data != 0 {
+        let len = cmp::min(remaining_data, chunk_size as u64) as usize;
+        match file.read(&mut buf[..len]) {
+            Ok(n) if n > 0 => {
+                checksum.update(&buf[..n]);
+                remaining_data -= n as u64;
+                encoder.write_all(&buf[..n])?;
+            },
+            _ => {
+                warn!("Cannot read the rest of {} bytes", remaining_data);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("file ended {} bytes early",
+                                                  remaining_data)));
+            },
+        }
+    }
+    let mut writer = encoder.finish()?;
+    writer.flush()?;
+    let digest = FileStream{data: Vec::new(), digest: checksum.finish()};
+    block_on(writer.tx.send(digest))
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "server went away"))?;
+    Ok(writer.sent)
+}
+
+// Cuts the data, compressed or not, into messages of chunk_size bytes.
+struct MessageWriter {
+    tx: mpsc::Sender<FileStream>,
+    buf: Vec<u8>,
+    chunk_size: usize,
+    sent: u64,
+}
+
+impl MessageWriter {
+    fn new(tx: mpsc::Sender<FileStream>, chunk_size: usize) -> Self {
+        MessageWriter {
+            tx: tx,
+            buf: Vec::with_capacity(chunk_size),
+            chunk_size: chunk_size,
+            sent: 0,
+        }
+    }
+
+    fn send(&mut self, len: usize) -> io::Result<()> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        block_on(self.tx.send(FileStream{data: data, digest: String::new()}))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "server went away"))?;
+        self.sent += len as u64;
+        Ok(())
+    }
+}
+
+impl Write for MessageWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= self.chunk_size {
+            self.send(self.chunk_size)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send(self.buf.len())?;
+        }
+        Ok(())
     }
-    Ok(())
 }
 
 async fn upload(filename: &str,
+                remote: &str,
+                offset: u64,
+                compression: Compression,
                 storage: &str,
+                chunk_size: usize,
                 client: &mut SrwscClient<tonic::transport::Channel>)
-                -> Result<(), Box<dyn std::error::Error>> {
+                -> Result<Transferred, Box<dyn std::error::Error>> {
     let file = misc::check_file(filename, storage);
 
-    println!("file = {:?}", file);
+    debug!(file = ?file, "Uploading");
+    let started = Instant::now();
     if file.size > 0 {
-        let mut buf = [0u8; config::BUFFER_SIZE];
-        let mut f = File::open(&file.fullpath).unwrap();
-        let mut read_size: u64 = 0;
-        let mut msg: Vec<FileStream> = Vec::new();
-        while read_size < file.size {
-            match f.read(&mut buf) {
-                Ok(n) => {
-                    read_size += n as u64;
-                    msg.push(FileStream{data: buf[..n].to_vec()});
-                }
-                _ => { break; }
-            }
-        }
-        let mut request = tonic::Request::new(stream::iter(msg));
-        let header_value = MetadataValue::from_str(&file.name).unwrap();
+        let mut f = File::open(&file.fullpath)?;
+        // The server verifies the whole file, so a resumed upload hashes the
+        // part it already has before sending the rest.
+        let mut checksum = Checksum::new();
+        checksum.update_from(&mut f, offset)?;
+        let (tx, rx) = mpsc::channel(8);
+        let length = file.size - offset;
+        let sending = task::spawn_blocking(move || {
+            send_file(f, checksum, length, chunk_size, compression, tx)
+        });
+
+        let mut request = tonic::Request::new(rx);
+        let header_value = MetadataValue::from_str(remote)?;
         request.metadata_mut()
             .insert(config::GRPC_METADATA_FILENAME, header_value.clone());
+        request.metadata_mut()
+            .insert(config::GRPC_METADATA_OFFSET,
+                    MetadataValue::from_str(&offset.to_string())?);
+        // Lets the server check the quota before any data is sent.
+        request.metadata_mut()
+            .insert(config::GRPC_METADATA_LENGTH,
+                    MetadataValue::from_str(&length.to_string())?);
+        if compression != Compression::None {
+            request.metadata_mut()
+                .insert(config::GRPC_METADATA_COMPRESSION,
+                        MetadataValue::from_static(compression.name()));
+        }
         match client.put(request).await {
-            Ok(response) => println!("response = {:?}", response.into_inner()),
-            Err(e) => println!("Something wrong: {:?}", e),
+            Ok(response) => debug!(response = ?response.into_inner()),
+            Err(e) => return Err(Box::new(SrwscError::from(e))),
         }
+        let wire_bytes = sending.await??;
+        info!(filename, bytes = length, wire_bytes, compression = compression.name(),
+              duration = ?started.elapsed(), "Sent");
+        Ok(Transferred {
+            bytes: length,
+            wire_bytes: wire_bytes,
+            compression: compression,
+        })
     } else {
-        return Err(Box::new(SrwscError::new(ErrorCode::NotExistFile,
-                                               String::from("Not exist file"))));
+        Err(Box::new(SrwscError::new(ErrorCode::NotExistFile,
+                                     String::from("Not exist file"))))
     }
-    Ok(())
 }
 
 async fn rm_file(filename: &str,
@@ -87,85 +243,293 @@ async fn rm_file(filename: &str,
                  -> Result<(), Box<dyn std::error::Error>> {
     let request = tonic::Request::new(
         SrwscRequest {
-            filename: String::from(filename)
+            filename: String::from(filename),
+            offset: 0,
+            length: 0,
         },
     );
     let response = client
         .remove(request)
-        .await?
+        .await
+        .map_err(SrwscError::from)?
         .into_inner();
-    println!("RESPONSE={:?}", response);
+    debug!(response = ?response);
     Ok(())
 }
 
-async fn ls_server(client: &mut SrwscClient<tonic::transport::Channel>)
-                 -> Result<String, Box<dyn std::error::Error>> {
+async fn make_dir(dirname: &str,
+                  client: &mut SrwscClient<tonic::transport::Channel>)
+                  -> Result<(), Box<dyn std::error::Error>> {
     let request = tonic::Request::new(
-        pb::Empty{},
+        SrwscRequest {
+            filename: String::from(dirname),
+            offset: 0,
+            length: 0,
+        },
     );
     let response = client
-        .file_list(request)
-        .await?
+        .make_dir(request)
+        .await
+        .map_err(SrwscError::from)?
         .into_inner();
-    println!("RESPONSE={:?}", response);
-    Ok(response.message)
+    debug!(response = ?response);
+    Ok(())
+}
+
+async fn rm_dir(dirname: &str,
+                client: &mut SrwscClient<tonic::transport::Channel>)
+                -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(
+        SrwscRequest {
+            filename: String::from(dirname),
+            offset: 0,
+            length: 0,
+        },
+    );
+    let response = client
+        .remove_dir(request)
+        .await
+        .map_err(SrwscError::from)?
+        .into_inner();
+    debug!(response = ?response);
+    Ok(())
+}
+
+// With a `prefix` length, the server also digests that many first bytes of
+// the file.
+async fn stat_file(filename: &str,
+                   prefix: u64,
+                   client: &mut SrwscClient<tonic::transport::Channel>)
+                   -> Result<FileInfo, Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(
+        SrwscRequest {
+            filename: String::from(filename),
+            offset: 0,
+            length: prefix,
+        },
+    );
+    let response = client
+        .stat(request)
+        .await
+        .map_err(SrwscError::from)?
+        .into_inner();
+    Ok(response)
+}
+
+async fn quota_info(client: &mut SrwscClient<tonic::transport::Channel>)
+                    -> Result<QuotaInfo, Box<dyn std::error::Error>> {
+    let response = client
+        .quota(tonic::Request::new(Empty {}))
+        .await
+        .map_err(SrwscError::from)?
+        .into_inner();
+    Ok(response)
+}
+
+// Collects one page of the listing from the messages of the List stream.
+async fn ls_server(request: &ListRequest,
+                   client: &mut SrwscClient<tonic::transport::Channel>)
+                   -> Result<FileListResponse, Box<dyn std::error::Error>> {
+    let mut stream = client
+        .list(tonic::Request::new(request.clone()))
+        .await
+        .map_err(SrwscError::from)?
+        .into_inner();
+    let mut page = FileListResponse::default();
+    while let Some(mut message) = stream.message().await.map_err(SrwscError::from)? {
+        page.files.append(&mut message.files);
+        page.next_page_token = message.next_page_token;
+    }
+    Ok(page)
+}
+
+// Shows a listing page by page. When the user asked for a page size or gave a
+// token, only that page is shown, followed by the command for the next one.
+async fn list_files(mut request: ListRequest,
+                    client: &mut SrwscClient<tonic::transport::Channel>)
+                    -> Result<(), Box<dyn std::error::Error>> {
+    let single_page = request.page_size > 0 || !request.page_token.is_empty();
+    let mut first_page = true;
+    loop {
+        let page = ls_server(&request, client).await?;
+        match first_page {
+            true => misc::print_file_list(&page.files),
+            false => misc::print_file_entries(&page.files),
+        }
+        first_page = false;
+
+        if page.next_page_token.is_empty() {
+            return Ok(());
+        }
+        if single_page {
+            misc::print_next_page(&request, &page.next_page_token);
+            return Ok(());
+        }
+        request.page_token = page.next_page_token;
+    }
 }
 
 #[tokio::main]
 pub async fn run(c: ClientConfig)
     -> Result<(), Box<dyn std::error::Error>> {
-    let mut addr = String::from(config::GRPC_URL_SCHEMA);
+    let tls_config = tls::grpc_client_config(&c)?;
+    let mut addr = match tls_config {
+        Some(_) => String::from(config::GRPC_TLS_URL_SCHEMA),
+        None => String::from(config::GRPC_URL_SCHEMA),
+    };
     addr.push_str(&c.address.to_string());
-    println!("Connecting on address: {}", style(&addr).green());
-    let channel = tonic::transport::Channel::from_shared(
+    info!(server = %addr, "Connecting");
+    let mut endpoint = tonic::transport::Channel::from_shared(
         addr.into_bytes())
-        .unwrap()
+        .map_err(|e| SrwscError::new(ErrorCode::InvalidArgument, e.to_string()))?;
+    match tls_config {
+        Some(tls_config) => endpoint = endpoint.tls_config(tls_config),
+        None if c.credentials.is_some() => {
+            warn!("The password or token is sent in clear text without TLS");
+        },
+        None => {},
+    }
+    let channel = endpoint
         .connect()
-        .await?;
+        .await
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                     format!("could not connect to the server {}: {}",
+                                             c.address, e)))?;
 
-    let mut client = SrwscClient::new(channel);
+    let mut client = match &c.credentials {
+        Some(credentials) => {
+            let authorization = MetadataValue::from_str(&credentials.authorization())?;
+            SrwscClient::with_interceptor(channel, move |mut request: tonic::Request<()>| {
+                request.metadata_mut()
+                    .insert(config::GRPC_METADATA_AUTHORIZATION, authorization.clone());
+                Ok(request)
+            })
+        },
+        None => SrwscClient::new(channel),
+    };
+    let mut cwd = String::new();
+    if let Some(command) = &c.command {
+        return run_command(&c, &mut cwd, command, &mut client).await;
+    }
 
     loop {
-        misc::srwc_prompt();
-        let cmd = std::io::stdin();
-        for line in cmd.lock().lines() {
-            let command = line.unwrap();
-
-            if command.starts_with("get ") {
-                match download(&command[4..], &c.storage, &mut client).await {
-                    Ok(_) => println!("Download is completed"),
-                    Err(err) => println!("Download error: {}", err),
-                }
-            } else if command.starts_with("put ") {
-                match upload(&command[4..], &c.storage, &mut client).await {
-                    Ok(_) => println!("Upload is completed"),
-                    Err(err) => println!("An error occurred: {}", err),
-                }
-            } else if command.starts_with("rm ") {
-                match rm_file(&command[3..], &mut client).await {
-                    Ok(_) => println!("Remove is Ok"),
-                    Err(err) => println!("An error occurred: {}", err),
-                }
-            } else {
-                match command.as_ref() {
-                    "ls" => {
-                        match ls_server(&mut client).await {
-                            Ok(msg) => {
-                                let res = misc::file_list_response(&msg);
-                                println!("{}", style("Server files: ").magenta());
-                                for entry in res.iter() {
-                                    println!("{}  [{} bytes]", style(&entry.name).green(),
-                                             style(&entry.size).cyan());
-                                }
-                            },
-                            Err(err) => println!("An error occurred: {}", err),
-                        }
-                    },
-                    "help" => misc::srwc_help(),
-                    _ => println!("Unknown command: {}", command),
-                }
-            }
-            break;
+        misc::srwc_prompt(&cwd);
+        let mut command = String::new();
+        if std::io::stdin().read_line(&mut command)? == 0 {
+            return Ok(());
         }
+        if let Err(err) = run_command(&c, &mut cwd, command.trim_end(), &mut client).await {
+            println!("An error occurred: {}", err);
+        }
+    }
+}
+
+// Looks up the remote copy to find out how much of a previous transfer can
+// be reused. A download asks for the digest of as much of the file as there
+// is locally.
+async fn resume_offset(cmd: &str,
+                       local: &str,
+                       remote: &str,
+                       storage: &str,
+                       client: &mut SrwscClient<tonic::transport::Channel>)
+                       -> u64 {
+    let local_size = misc::local_file_size(local, storage);
+    let prefix = match cmd {
+        "get" => local_size.unwrap_or(0),
+        _ => 0,
+    };
+    let file = stat_file(remote, prefix, client).await.ok();
+
+    let local_digest = |length| misc::local_digest(local, storage, length);
+    match cmd {
+        "get" => misc::download_offset(local_size, file.as_ref(), local_digest),
+        _ => misc::upload_offset(local_size.unwrap_or(0), file.as_ref(), local_digest),
     }
-}
\ No newline at end of file
+}
+
+async fn run_command(c: &ClientConfig,
+                     cwd: &mut String,
+                     command: &str,
+                     client: &mut SrwscClient<tonic::transport::Channel>)
+                     -> Result<(), Box<dyn std::error::Error>> {
+    let (cmd, arg) = misc::split_command(command);
+    let (mut list_request, path) = match cmd {
+        "ls" => misc::ls_args(arg)
+            .map_err(|e| SrwscError::new(ErrorCode::InvalidArgument, e))?,
+        _ => (ListRequest::default(), arg),
+    };
+    let (path, range) = match cmd {
+        "get" => misc::range_args(path),
+        _ => (path, None),
+    };
+    let remote = misc::remote_path(cwd, path).ok_or_else(|| {
+        SrwscError::new(ErrorCode::InvalidFileName,
+                        format!("{} is outside of the server root", path))
+    })?;
+
+    let local = match range {
+        Some((start, length)) => misc::range_name(&misc::local_name(path), start, length),
+        None => misc::local_name(path),
+    };
+    let offset = match (cmd, range) {
+        (_, Some((start, _))) => start,
+        ("get", None) | ("put", None) => resume_offset(cmd, &local, &remote, &c.storage, client).await,
+        _ => 0,
+    };
+    let length = range.map_or(0, |(_, length)| length);
+    if offset > 0 && range.is_none() {
+        println!("Resuming {} from {} bytes", path, offset);
+    }
+
+    match cmd {
+        "get" => {
+            let transferred = download(&remote, &local, offset, length, c.compression,
+                                       &c.storage, client).await?;
+            println!("Download is completed: {}", transferred);
+        },
+        "put" => {
+            let transferred = upload(&local, &remote, offset, c.compression, &c.storage,
+                                     c.chunk_size, client).await?;
+            println!("Upload is completed: {}", transferred);
+        },
+        "rm" => {
+            rm_file(&remote, client).await?;
+            println!("Remove is Ok");
+        },
+        "mkdir" => {
+            make_dir(&remote, client).await?;
+            println!("Directory is created");
+        },
+        "rmdir" => {
+            rm_dir(&remote, client).await?;
+            println!("Remove is Ok");
+        },
+        "cd" => {
+            if remote.is_empty() {
+                cwd.clear();
+                return Ok(());
+            }
+            list_request.path = remote.clone();
+            list_request.page_size = 1;
+            ls_server(&list_request, client).await?;
+            *cwd = remote;
+        },
+        "pwd" => println!("/{}", cwd),
+        "stat" => misc::print_file_list(&[stat_file(&remote, 0, client).await?]),
+        "ls" => {
+            list_request.path = remote;
+            list_files(list_request, client).await?;
+        },
+        "quota" | "df" => {
+            let info = quota_info(client).await?;
+            match cmd {
+                "quota" => misc::print_quota(&info),
+                _ => misc::print_df(&info),
+            }
+        },
+        "help" => misc::srwc_help(),
+        _ => return Err(Box::new(SrwscError::new(ErrorCode::InvalidArgument,
+                                                 format!("unknown command {}", command)))),
+    }
+    Ok(())
+}
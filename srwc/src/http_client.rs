@@ -1,275 +1,330 @@
 extern crate encoding;
-extern crate regex;
 extern crate console;
 extern crate pbr;
 
-use crate::config::{ClientConfig, BUFFER_SIZE};
+use crate::config::{ClientConfig, ServerType, MAX_FRAME_SIZE};
 use crate::config::{ACK_MESSAGE,
+                    NAK_MESSAGE,
                     PREPARE_TRANSFER_MESSAGE,
                     CANNOT_FIND_FILE_MESSAGE,
                     REMOVED_OK_MESSAGE,
-                    REMOVED_NOK_MESSAGE};
+                    MKDIR_OK_MESSAGE,
+                    CHECKSUM_OK_MESSAGE,
+                    CHECKSUM_MISMATCH_MESSAGE,
+                    FILE_INFO_MESSAGE,
+                    FILE_LIST_MESSAGE,
+                    LOGIN_OK_MESSAGE,
+                    LOGIN_FAILED_MESSAGE,
+                    LOGIN_REQUIRED_MESSAGE,
+                    QUOTA_INFO_MESSAGE,
+                    ERROR_MESSAGE};
+use crate::auth::Credentials;
+use crate::checksum::Checksum;
+use crate::compression;
+use crate::compression::Compression;
 use crate::error::{SrwscError, ErrorCode};
 use crate::misc;
+use crate::misc::Transferred;
+use crate::pb::{Error, ErrorKind, FileInfo, FileListResponse, ListRequest, QuotaInfo};
+use crate::tls;
+use crate::transfer;
 
 use encoding::{Encoding, EncoderTrap};
 use encoding::all::ASCII;
+use prost::Message;
+use rustls::StreamOwned;
 use std::net::TcpStream;
-use std::io::BufWriter;
 use std::str;
+use std::io;
 use std::io::prelude::*;
-use std::fs;
 use std::fs::File;
-use console::style;
-
-fn encoded_message_size(cmd: &str)
-    -> Result<Vec<u8>, SrwscError> {
-    let mut message_size = cmd.len();
-    message_size = message_size + 1;
-    let message_size_str = message_size.to_string();
-    let mut message_size_bytes = ASCII
-        .encode(&message_size_str, EncoderTrap::Strict)
-        .map_err(|x| x.into_owned())
-        .unwrap();
-    message_size_bytes.push('\r' as u8);
-
-    Ok(message_size_bytes)
-}
+use std::time::Instant;
+use tracing::{debug, info, warn};
 
+// Messages are ASCII, and so the commands with the file names in them.
 fn encoded_message(cmd: &str)
-    -> Result <Vec<u8>, SrwscError> {
-    let message_str = cmd.to_string();
+    -> Result<Vec<u8>, SrwscError> {
     let mut message_bytes = ASCII
-        .encode(&message_str, EncoderTrap::Strict)
-        .map_err(|x| x.into_owned())
-        .unwrap();
-    message_bytes.push('\r' as u8);
+        .encode(cmd, EncoderTrap::Strict)
+        .map_err(|_| SrwscError::new(ErrorCode::InvalidArgument,
+                                     format!("{:?} is not ASCII", cmd)))?;
+    message_bytes.push(b'\r');
 
     Ok(message_bytes)
 }
 
-fn send_ack_message(stream: &mut TcpStream) {
-    let ack = encoded_message("ACK").unwrap();
-    stream.write_all(&ack).unwrap();
+fn send_ack_message<S: Read + Write>(stream: &mut S)
+    -> Result<(), SrwscError> {
+    stream.write_all(&encoded_message(ACK_MESSAGE)?)?;
+    Ok(())
 }
 
-fn send_normal_message(msg: &str,
-                       stream: &mut TcpStream)
+fn send_normal_message<S: Read + Write>(msg: &str,
+                                        stream: &mut S)
     -> Result<(), SrwscError> {
-    let mut buf = [0u8; BUFFER_SIZE];
-    let send_msg_size = encoded_message_size(msg).unwrap();
-    let send_msg = encoded_message(msg).unwrap();
+    let send_msg = encoded_message(msg)?;
 
-    stream.write_all(&send_msg_size).unwrap();
-
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_normal_message] check_ack for size: {}", e);
+    stream.write_all(&encoded_message(&send_msg.len().to_string())?)?;
+    if let Err(e) = check_ack(&read_field(stream)?) {
+        debug!("No ACK for the size of {:?}: {}", msg, e);
         return Err(e);
     }
 
-    stream.write_all(&send_msg).unwrap();
-
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_normal_message] check_ack for message: {}", e);
+    stream.write_all(&send_msg)?;
+    if let Err(e) = check_ack(&read_field(stream)?) {
+        debug!("No ACK for {:?}: {}", msg, e);
         return Err(e);
     }
 
     Ok(())
 }
 
-fn decoded_message_len(mut msg: &mut [u8])
-    -> String {
-    let mut msg_len = str::from_utf8(&mut msg)
-        .unwrap()
-        .to_string();
-
-    let mut index = 0;
-    for c in msg_len.chars() {
-        if c.is_numeric() == true {
-            index = index + 1;
+// Reads one "...\r" field of the protocol, without the terminator. It is read
+// a byte at a time, so that nothing the server sends next is taken with it.
+fn read_field<S: Read + Write>(stream: &mut S)
+    -> Result<String, SrwscError> {
+    let mut field = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => return Err(SrwscError::new(ErrorCode::ErrorIo,
+                                                String::from("connection closed by the server"))),
+            Ok(_) if byte[0] == b'\r' => break,
+            Ok(_) => field.push(byte[0]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
         }
-    }
-    msg_len.truncate(index);
-    msg_len
-}
-
-fn decoded_message(msg_len: String,
-                   stream: &mut TcpStream)
-    -> String {
-    let mut remaining_data = msg_len.parse::<i32>().unwrap();
-    let mut msg: String = String::new();
-    let mut buf = [0u8; BUFFER_SIZE];
-
-    while remaining_data > 0 {
-        match stream.read(&mut buf) {
-            Ok(n) => {
-                msg.push_str(str::from_utf8(&mut buf).unwrap());
-                remaining_data = remaining_data - n as i32;
-            },
-            _ => { break; },
+        if field.len() as u64 > MAX_FRAME_SIZE {
+            return Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                       String::from("message is too large")));
         }
     }
-
-    let index = msg.rfind('\r').unwrap();
-    msg.truncate(index);
-    msg
+    String::from_utf8(field)
+        .map_err(|_| SrwscError::new(ErrorCode::ErrorProtocol,
+                                     String::from("message is not UTF-8")))
 }
 
-fn get_message(stream: &mut TcpStream) -> String {
-    let mut buf = [0u8; BUFFER_SIZE];
-    stream.read(&mut buf).unwrap();
-    let msg_len = decoded_message_len(&mut buf);
-    send_ack_message(stream);
-    let msg = decoded_message(msg_len, stream);
-    send_ack_message(stream);
-    msg
+// A message comes as its size, counting the terminator, and then itself; the
+// client acknowledges both.
+fn get_message<S: Read + Write>(stream: &mut S)
+    -> Result<String, SrwscError> {
+    let size = read_field(stream)?;
+    let size = size.parse::<usize>().map_err(|_| {
+        SrwscError::new(ErrorCode::ErrorProtocol,
+                        format!("message size {:?} is not a number", size))
+    })?;
+    send_ack_message(stream)?;
+    let msg = read_field(stream)?;
+    if msg.len() + 1 != size {
+        return Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                   format!("message of {} bytes announced as {}",
+                                           msg.len() + 1, size)));
+    }
+    send_ack_message(stream)?;
+    Ok(msg)
 }
 
-fn check_ack(mut buf: &mut [u8]) -> Result<(), SrwscError> {
-    let ack_slice: &str = str::from_utf8(&mut buf).unwrap();
-    let mut ack_str = ack_slice.to_string();
-    let index: usize = ack_str.rfind('\r').unwrap();
-    ack_str.truncate(index);
-    if ack_str != ACK_MESSAGE {
+fn check_ack(reply: &str) -> Result<(), SrwscError> {
+    if reply != ACK_MESSAGE {
         return Err(SrwscError::new(ErrorCode::ErrorAck,
                                    String::from("ACK failed")));
     }
     Ok(())
 }
 
-fn receive_file(file_name: &str,
-                storage: &str,
-                stream: &mut TcpStream) {
-    println!("[receive_file] file_name = {}", file_name);
-    let mut buf = [0u8; BUFFER_SIZE];
-
-    stream.read(&mut buf).unwrap();
-    let file_size = decoded_message_len(&mut buf);
-    println!("[receive_file] file_size = {}", file_size);
-
-    send_ack_message(stream);
-
-    let mut fullname = String::from(storage);
-    fullname.push_str("/");
-    fullname.push_str(&file_name);
-
-    let mut file_buffer = BufWriter::new(File::create(fullname).unwrap());
-    let mut remaining_data = file_size.parse::<u64>().unwrap();
-    let mut written: i32;
-    while remaining_data > 0 {
-        match stream.read(&mut buf) {
-            Ok(n) => {
-                if remaining_data < BUFFER_SIZE as u64 {
-                    let sbuf = &buf[0 .. remaining_data as usize];
-                    file_buffer.write(sbuf).unwrap();
-                    written = remaining_data as i32;
-                } else {
-                    file_buffer.write(&mut buf).unwrap();
-                    written = n as i32;
-                }
-                file_buffer.flush().unwrap();
-                remaining_data = remaining_data - written as u64;
-                println!("wrote {} bytes, remaining {} bytes",
-                         written, remaining_data);
-            }
-            _ => { break; }
-        }
+// Reads a typed reply: the protobuf encoding of `M`, preceded by its size and
+// acknowledged on both ends.
+fn receive_frame<M: Message + Default, S: Read + Write>(stream: &mut S)
+    -> Result<M, SrwscError> {
+    let frame_size = transfer::read_size(stream)?;
+    if frame_size > MAX_FRAME_SIZE {
+        return Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                   format!("Reply of {} bytes is too large", frame_size)));
     }
+    send_ack_message(stream)?;
+
+    let mut bytes = vec![0u8; frame_size as usize];
+    stream.read_exact(&mut bytes)?;
+    send_ack_message(stream)?;
+
+    M::decode(&bytes[..])
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorProtocol, e.to_string()))
 }
 
-fn send_file(fullpath: &str,
-             file_size: u64,
-             stream: &mut TcpStream)
-    -> Result<(), SrwscError> {
-    let send_file_size = encoded_message(&file_size.to_string())
-        .unwrap();
-    stream.write_all(&send_file_size)
-        .unwrap();
-
-    let mut buf = [0u8; BUFFER_SIZE];
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_file] check_ack: {}", e);
-        return Err(e);
-    }
+// Reads the frame that follows an "error" reply.
+fn receive_error<S: Read + Write>(stream: &mut S) -> SrwscError {
+    let error = match receive_frame::<Error, S>(stream) {
+        Ok(error) => error,
+        Err(e) => return e,
+    };
+    let code = ErrorKind::from_i32(error.kind)
+        .map_or(ErrorCode::ErrorRequest, ErrorCode::from_kind);
+    SrwscError::new(code, error.message)
+}
 
-    let mut remaining_data = file_size;
-    let mut file = File::open(fullpath).unwrap();
-    while remaining_data != 0 {
-        match file.read(&mut buf) {
-            Ok(n) => {
-                stream.write_all(&buf).unwrap();
-                println!("sent {} file bytes", n);
-                remaining_data = remaining_data - n as u64;
-            }
-            _ => { break; }
-        }
+fn receive_file<S: Read + Write>(file_name: &str,
+                                 offset: u64,
+                                 compression: Compression,
+                                 storage: &str,
+                                 stream: &mut S)
+    -> Result<Transferred, SrwscError> {
+    debug!(filename = file_name, offset, "Downloading");
+    let file_size = transfer::read_size(stream)?;
+    debug!(size = file_size, "Download announced");
+
+    send_ack_message(stream)?;
+    let compression = match compression {
+        Compression::None => Compression::None,
+        _ => accepted_compression(stream)?,
+    };
+
+    let mut file = misc::open_local_file(file_name, storage, offset)?;
+    let mut checksum = misc::local_checksum(file_name, storage, offset)?;
+    let started = Instant::now();
+    let received = match compression {
+        Compression::None => transfer::receive_data(stream, &mut file, file_size, &mut checksum)
+            .map(|_| file_size),
+        _ => compression::receive_compressed(stream, &mut file, file_size, &mut checksum,
+                                             compression),
+    };
+    let wire_bytes = match received {
+        Ok(wire_bytes) => wire_bytes,
+        Err(e) => {
+            warn!("Transfer cut off: {}", e);
+            return Err(e.into());
+        },
+    };
+    info!(filename = file_name, bytes = file_size, wire_bytes, compression = compression.name(),
+          duration = ?started.elapsed(), "Received");
+    drop(file);
+
+    let digest = get_message(stream)?;
+    if digest != checksum.finish() {
+        warn!(filename = file_name, "Checksum mismatch, removing the download");
+        misc::remove_local_file(file_name, storage);
+        send_normal_message(CHECKSUM_MISMATCH_MESSAGE, stream)?;
+        return Err(SrwscError::new(ErrorCode::ChecksumMismatch, digest));
     }
-    Ok(())
+    debug!(digest = %digest, "Verified");
+    send_normal_message(CHECKSUM_OK_MESSAGE, stream)?;
+    Ok(Transferred {
+        bytes: file_size,
+        wire_bytes: wire_bytes,
+        compression: compression,
+    })
 }
 
-fn download(command: &str,
-            storage: &str,
-            mut stream: &mut TcpStream)
-    -> Result<(), SrwscError> {
-    match get_message(stream).as_ref() {
-        CANNOT_FIND_FILE_MESSAGE => {
-            println!("[download] Cannot transfer file");
-            return Err(SrwscError::new(ErrorCode::NotExistFile,
-                                       String::from("Not exist file")));
+// After an offer, the server names the compression it picked right before the
+// data.
+fn accepted_compression<S: Read + Write>(stream: &mut S)
+    -> Result<Compression, SrwscError> {
+    let name = get_message(stream)?;
+    Compression::from_name(&name).ok_or_else(|| {
+        SrwscError::new(ErrorCode::ErrorRequest, format!("Unknown compression {:?}", name))
+    })
+}
+
+fn send_file<S: Read + Write>(fullpath: &str,
+                              offset: u64,
+                              file_size: u64,
+                              compression: Compression,
+                              stream: &mut S)
+    -> Result<Transferred, SrwscError> {
+    stream.write_all(&encoded_message(&(file_size - offset).to_string())?)?;
+
+    let reply = read_field(stream)?;
+    if reply == NAK_MESSAGE {
+        send_ack_message(stream)?;
+        let reason = get_message(stream)?;
+        debug!("Rejected by the server: {}", reason);
+        return match reason.as_ref() {
+            ERROR_MESSAGE => Err(receive_error(stream)),
+            _ => Err(SrwscError::new(ErrorCode::ErrorRequest, reason)),
+        };
+    }
+    if let Err(e) = check_ack(&reply) {
+        debug!("No ACK for the file size: {}", e);
+        return Err(e);
+    }
+    let compression = match compression {
+        Compression::None => Compression::None,
+        _ => accepted_compression(stream)?,
+    };
+
+    // The server verifies the whole file, so a resumed upload hashes the part
+    // it already has before sending the rest.
+    let mut file = File::open(fullpath)?;
+    let mut checksum = Checksum::new();
+    checksum.update_from(&mut file, offset)?;
+    let length = file_size - offset;
+    let started = Instant::now();
+    let sent = match compression {
+        Compression::None => transfer::send_data(&mut file, length, &mut checksum, stream)
+            .map(|_| length),
+        _ => compression::send_compressed(&mut file, length, &mut checksum, compression,
+                                          stream),
+    };
+    let wire_bytes = match sent {
+        Ok(wire_bytes) => wire_bytes,
+        Err(e) => {
+            warn!("Transfer cut off: {}", e);
+            return Err(e.into());
         },
+    };
+    info!(filename = fullpath, bytes = length, wire_bytes, compression = compression.name(),
+          duration = ?started.elapsed(), "Sent");
+
+    send_normal_message(&checksum.finish(), stream)?;
+    match get_message(stream)?.as_ref() {
+        CHECKSUM_OK_MESSAGE => Ok(Transferred {
+            bytes: length,
+            wire_bytes: wire_bytes,
+            compression: compression,
+        }),
+        ERROR_MESSAGE => Err(receive_error(stream)),
+        _ => Err(SrwscError::new(ErrorCode::ErrorRequest,
+                                 String::from("Unknown message"))),
+    }
+}
+
+fn download<S: Read + Write>(filename: &str,
+                             offset: u64,
+                             compression: Compression,
+                             storage: &str,
+                             mut stream: &mut S)
+    -> Result<Transferred, SrwscError> {
+    match get_message(stream)?.as_ref() {
         PREPARE_TRANSFER_MESSAGE => {
-            let filename = String::from(&command[4..]);
-            println!("[download] Try to download as {}", filename);
-            receive_file(&filename, storage, &mut stream);
+            debug!(filename, "Download accepted");
+            receive_file(filename, offset, compression, storage, &mut stream)
         },
+        ERROR_MESSAGE => Err(receive_error(stream)),
         _ => {
-            println!("[download] Unknown message");
-            return Err(SrwscError::new(ErrorCode::ErrorRequest,
-                                       String::from("Unknown message")));
+            warn!("Unexpected reply to a download");
+            Err(SrwscError::new(ErrorCode::ErrorRequest,
+                                String::from("Unknown message")))
         },
     }
-    Ok(())
 }
 
-fn upload(command: &str,
-          storage: &str,
-          stream: &mut TcpStream)
-    -> Result<(), SrwscError> {
-    let filename = String::from(&command[4..]);
-    println!("Try to upload as {}", filename);
-
-    let mut file_exists = false;
-    let mut file_size = 0;
-    let mut fullpath = String::new();
-
-    for entry in fs::read_dir(storage).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if !path.is_dir() {
-            fullpath = String::from(entry.path().to_string_lossy());
-            let file_name = String::from(str::replace(&fullpath, storage, ""));
-
-            if &file_name[1..] == filename {
-                let file = File::open(&fullpath).unwrap();
-                file_size = file.metadata().unwrap().len();
-                file_exists = true;
-            }
-        }
-    }
+fn upload<S: Read + Write>(filename: &str,
+                           offset: u64,
+                           compression: Compression,
+                           storage: &str,
+                           stream: &mut S)
+    -> Result<Transferred, SrwscError> {
+    debug!(filename, "Uploading");
+
+    let file = misc::check_file(filename, storage);
+    let file_exists = !file.fullpath.is_empty();
 
     match file_exists {
         true => {
-            match send_normal_message(PREPARE_TRANSFER_MESSAGE, stream) {
-                Err(e) => return Err(e),
-                _ => {},
-            }
-            return send_file(&fullpath, file_size, stream);
+            send_normal_message(PREPARE_TRANSFER_MESSAGE, stream)?;
+            return send_file(&file.fullpath, offset, file.size, compression, stream);
         },
         false => {
-            println!("File not found");
+            warn!(filename, "Local file not found");
             let _ = send_normal_message(CANNOT_FIND_FILE_MESSAGE, stream);
         }
     }
@@ -278,29 +333,89 @@ fn upload(command: &str,
                         String::from("File not found")))
 }
 
-fn ls_server(stream: &mut TcpStream)
-    -> Result<String, SrwscError> {
-    Ok(get_message(stream))
+fn ls_server<S: Read + Write>(stream: &mut S)
+    -> Result<FileListResponse, SrwscError> {
+    let msg = get_message(stream)?;
+    match msg.as_ref() {
+        FILE_LIST_MESSAGE => receive_frame(stream),
+        ERROR_MESSAGE => Err(receive_error(stream)),
+        _ => Err(SrwscError::new(ErrorCode::ErrorRequest, msg)),
+    }
+}
+
+fn stat_file<S: Read + Write>(stream: &mut S)
+    -> Result<FileInfo, SrwscError> {
+    let msg = get_message(stream)?;
+    match msg.as_ref() {
+        FILE_INFO_MESSAGE => receive_frame(stream),
+        ERROR_MESSAGE => Err(receive_error(stream)),
+        _ => Err(SrwscError::new(ErrorCode::ErrorRequest, msg)),
+    }
 }
 
-fn rm_file(stream: &mut TcpStream)
+fn quota_info<S: Read + Write>(stream: &mut S)
+    -> Result<QuotaInfo, SrwscError> {
+    send_normal_message("quota", stream)?;
+    let msg = get_message(stream)?;
+    match msg.as_ref() {
+        QUOTA_INFO_MESSAGE => receive_frame(stream),
+        ERROR_MESSAGE => Err(receive_error(stream)),
+        _ => Err(SrwscError::new(ErrorCode::ErrorRequest, msg)),
+    }
+}
+
+// Shows a listing page by page. When the user asked for a page size or gave a
+// token, only that page is shown, followed by the command for the next one.
+fn list_files<S: Read + Write>(mut request: ListRequest,
+                               stream: &mut S)
     -> Result<(), SrwscError> {
-    match get_message(stream).as_ref() {
-        REMOVED_OK_MESSAGE => {
-            println!("[rm_file] Removed successfully");
+    let single_page = request.page_size > 0 || !request.page_token.is_empty();
+    let mut first_page = true;
+    loop {
+        send_normal_message(&misc::ls_command(&request), stream)?;
+        let page = ls_server(stream)?;
+        match first_page {
+            true => misc::print_file_list(&page.files),
+            false => misc::print_file_entries(&page.files),
+        }
+        first_page = false;
+
+        if page.next_page_token.is_empty() {
+            return Ok(());
+        }
+        if single_page {
+            misc::print_next_page(&request, &page.next_page_token);
+            return Ok(());
+        }
+        request.page_token = page.next_page_token;
+    }
+}
+
+fn make_dir<S: Read + Write>(stream: &mut S)
+    -> Result<(), SrwscError> {
+    match get_message(stream)?.as_ref() {
+        MKDIR_OK_MESSAGE => {
+            debug!("Created");
         },
-        REMOVED_NOK_MESSAGE => {
-            println!("[rm_file] Removed unsuccessfully");
+        ERROR_MESSAGE => return Err(receive_error(stream)),
+        _ => {
+            warn!("Unexpected reply to mkdir");
             return Err(SrwscError::new(ErrorCode::ErrorRequest,
-                                       String::from("Removed unsuccessflly")))
+                                       String::from("Unknown message")))
         },
-        CANNOT_FIND_FILE_MESSAGE => {
-            println!("[rm_file] Could not file file");
-            return Err(SrwscError::new(ErrorCode::NotExistFile,
-                                       String::from("File not found")))
+    }
+    Ok(())
+}
+
+fn rm_file<S: Read + Write>(stream: &mut S)
+    -> Result<(), SrwscError> {
+    match get_message(stream)?.as_ref() {
+        REMOVED_OK_MESSAGE => {
+            debug!("Removed");
         },
+        ERROR_MESSAGE => return Err(receive_error(stream)),
         _ => {
-            println!("[rm_file] Unknown message");
+            warn!("Unexpected reply to rm");
             return Err(SrwscError::new(ErrorCode::ErrorRequest,
                                        String::from("Unknown message")))
         },
@@ -308,59 +423,208 @@ fn rm_file(stream: &mut TcpStream)
     Ok(())
 }
 
-pub fn run(c: ClientConfig) {
-    let mut stream = TcpStream::connect(&c.address)
-        .expect("Could not connect to the server...");
-    println!("Successful connection to server({})", style(&c.address).yellow());
+pub fn run(c: ClientConfig) -> Result<(), SrwscError> {
+    let mut stream = TcpStream::connect(c.address).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorIo,
+                        format!("could not connect to the server {}: {}", c.address, e))
+    })?;
+    info!(server = %c.address, "Connected");
+    match c.server_type {
+        ServerType::HTTPS => {
+            let session = tls::client_config(&c.ca)
+                .and_then(|config| tls::client_session(&config, &c.hostname))?;
+            let mut tls_stream = StreamOwned::new(session, stream);
+            session_loop(&c, &mut tls_stream)
+        },
+        _ => session_loop(&c, &mut stream),
+    }
+}
+
+// Looks up the remote copy to find out how much of a previous transfer can
+// be reused. A download asks for the digest of as much of the file as there
+// is locally, with "stat PREFIX -- NAME".
+fn resume_offset<S: Read + Write>(cmd: &str,
+                                  local: &str,
+                                  remote: &str,
+                                  storage: &str,
+                                  mut stream: &mut S)
+    -> Result<u64, SrwscError> {
+    let local_size = misc::local_file_size(local, storage);
+    let stat = match (cmd, local_size) {
+        ("get", Some(prefix)) if prefix > 0 => format!("stat {} -- {}", prefix, remote),
+        _ => format!("stat -- {}", remote),
+    };
+    send_normal_message(&stat, &mut stream)?;
+    // A file the server cannot stat is just not resumed, but a broken
+    // connection ends the command.
+    let file = match stat_file(&mut stream) {
+        Ok(file) => Some(file),
+        Err(e) => match e.code() {
+            ErrorCode::ErrorAck | ErrorCode::ErrorProtocol | ErrorCode::ErrorIo => return Err(e),
+            _ => None,
+        },
+    };
+
+    let local_digest = |length| misc::local_digest(local, storage, length);
+    Ok(match cmd {
+        "get" => misc::download_offset(local_size, file.as_ref(), local_digest),
+        _ => misc::upload_offset(local_size.unwrap_or(0), file.as_ref(), local_digest),
+    })
+}
+
+// Logs in with the credentials, or without any just asks the server whether
+// it needs a login.
+fn login<S: Read + Write>(credentials: &Option<Credentials>,
+                          stream: &mut S)
+    -> Result<(), SrwscError> {
+    let command = match credentials {
+        Some(credentials) => credentials.login_command(),
+        None => String::from("login"),
+    };
+    send_normal_message(&command, stream)?;
+    match get_message(stream)?.as_ref() {
+        LOGIN_OK_MESSAGE => Ok(()),
+        LOGIN_FAILED_MESSAGE => Err(SrwscError::new(ErrorCode::ErrorAuth,
+                                                    String::from("Wrong user, password or token"))),
+        LOGIN_REQUIRED_MESSAGE => Err(SrwscError::new(ErrorCode::ErrorAuth,
+                                                      String::from("The server requires a login, \
+                                                                    use -u [USER] or -p [TOKEN FILE]"))),
+        _ => Err(SrwscError::new(ErrorCode::ErrorRequest,
+                                 String::from("Unknown message"))),
+    }
+}
+
+// Runs the command of "srwc get", "put", "rm" or "ls", or else the ones typed
+// at the prompt.
+fn session_loop<S: Read + Write>(c: &ClientConfig,
+                                 mut stream: &mut S)
+    -> Result<(), SrwscError> {
+    login(&c.credentials, &mut stream)?;
+    if let (Some(_), ServerType::HTTP) = (&c.credentials, &c.server_type) {
+        warn!("The password or token was sent in clear text without TLS");
+    }
+    let mut cwd = String::new();
+    if let Some(command) = &c.command {
+        return run_command(c, &mut cwd, command, &mut stream);
+    }
     loop {
-        misc::srwc_prompt();
-        let cmd = std::io::stdin();
-        for line in cmd.lock().lines() {
-            let command = line.unwrap();
-
-            match send_normal_message(&command, &mut stream) {
-                Err(e) => {
-                    println!("Error occured during sending command: {:?}", e);
-                    continue;
+        misc::srwc_prompt(&cwd);
+        let mut command = String::new();
+        if io::stdin().read_line(&mut command)? == 0 {
+            return Ok(());
+        }
+        if let Err(err) = run_command(c, &mut cwd, command.trim_end(), &mut stream) {
+            println!("An error occurred: {}", err);
+        }
+    }
+}
+
+fn run_command<S: Read + Write>(c: &ClientConfig,
+                                cwd: &mut String,
+                                command: &str,
+                                mut stream: &mut S)
+    -> Result<(), SrwscError> {
+    let (cmd, arg) = misc::split_command(command);
+    match cmd {
+        "help" => misc::srwc_help(),
+        "pwd" => println!("/{}", cwd),
+        "quota" | "df" => {
+            let info = quota_info(&mut stream)?;
+            match cmd {
+                "quota" => misc::print_quota(&info),
+                _ => misc::print_df(&info),
+            }
+        },
+        "cd" | "get" | "put" | "rm" | "mkdir" | "rmdir" | "ls" | "stat" => {
+            let (mut list_request, path) = match cmd {
+                "ls" => misc::ls_args(arg)
+                    .map_err(|e| SrwscError::new(ErrorCode::InvalidArgument, e))?,
+                _ => (ListRequest::default(), arg),
+            };
+            let (path, range) = match cmd {
+                "get" => misc::range_args(path),
+                _ => (path, None),
+            };
+            let remote = misc::remote_path(cwd, path).ok_or_else(|| {
+                SrwscError::new(ErrorCode::InvalidFileName,
+                                format!("{} is outside of the server root", path))
+            })?;
+            if cmd == "ls" {
+                list_request.path = remote;
+                return list_files(list_request, &mut stream);
+            }
+
+            let local = match range {
+                Some((start, length)) => misc::range_name(&misc::local_name(path), start, length),
+                None => misc::local_name(path),
+            };
+            let offset = match (cmd, range) {
+                ("get", None) | ("put", None) => resume_offset(cmd, &local, &remote, &c.storage, &mut stream)?,
+                _ => 0,
+            };
+            if offset > 0 {
+                println!("Resuming {} from {} bytes", path, offset);
+            }
+
+            // "-z OFFER" in front of the range and the path asks for compressed
+            // data.
+            let transfer_options = match c.compression {
+                Compression::None => String::new(),
+                compression => format!("-z {} ", compression.offer()),
+            };
+            let wire_command = match cmd {
+                "cd" => {
+                    list_request.path = remote.clone();
+                    list_request.page_size = 1;
+                    misc::ls_command(&list_request)
                 },
-                _ => {},
+                "get" if range.is_some() => {
+                    let (start, length) = range.unwrap_or_default();
+                    format!("get {}{} {} -- {}", transfer_options, start, length, remote)
+                },
+                "get" | "put" if offset > 0 => {
+                    format!("{} {}{} -- {}", cmd, transfer_options, offset, remote)
+                },
+                "get" | "put" => format!("{} {}-- {}", cmd, transfer_options, remote),
+                "stat" => format!("stat -- {}", remote),
+                _ => format!("{} {}", cmd, remote),
+            };
+            if cmd == "cd" && remote.is_empty() {
+                cwd.clear();
+                return Ok(());
             }
 
-            if command.starts_with("get ") {
-                match download(&command, &c.storage, &mut stream) {
-                    Ok(_) => println!("Download is completed"),
-                    Err(err) => println!("Download error: {}", err),
-                }
-            } else if command.starts_with("put ") {
-                match upload(&command, &c.storage, &mut stream) {
-                    Ok(_) => println!("Upload is completed"),
-                    Err(err) => println!("An error occurred: {}", err),
-                }
-            } else if command.starts_with("rm ") {
-                match rm_file(&mut stream) {
-                    Ok(_) => println!("Remove is completed"),
-                    Err(err) => println!("An error occurred: {}", err),
-                }
-            } else {
-                match command.as_ref() {
-                    "ls" => {
-                        match ls_server(&mut stream) {
-                            Ok(response) => {
-                                let res = misc::file_list_response(&response);
-                                println!("{}", style("Server files: ").magenta());
-                                for entry in res.iter() {
-                                    println!("{}  [{} bytes]", style(&entry.name).green(),
-                                                               style(&entry.size).cyan());
-                                }
-                            },
-                            Err(err) => println!("{}", err),
-                        }
-                    },
-                    "help" => misc::srwc_help(),
-                    _ => println!("Unknown command: {}", command),
-                }
+            send_normal_message(&wire_command, &mut stream)?;
+
+            match cmd {
+                "get" => {
+                    let transferred = download(&local, offset, c.compression, &c.storage,
+                                               &mut stream)?;
+                    println!("Download is completed: {}", transferred);
+                },
+                "put" => {
+                    let transferred = upload(&local, offset, c.compression, &c.storage,
+                                             &mut stream)?;
+                    println!("Upload is completed: {}", transferred);
+                },
+                "rm" | "rmdir" => {
+                    rm_file(&mut stream)?;
+                    println!("Remove is completed");
+                },
+                "mkdir" => {
+                    make_dir(&mut stream)?;
+                    println!("Directory is created");
+                },
+                "cd" => {
+                    ls_server(&mut stream)?;
+                    *cwd = remote;
+                },
+                "stat" => misc::print_file_list(&[stat_file(&mut stream)?]),
+                _ => {},
             }
-            break;
-        }
+        },
+        _ => return Err(SrwscError::new(ErrorCode::InvalidArgument,
+                                        format!("unknown command {}", command))),
     }
+    Ok(())
 }
@@ -0,0 +1,54 @@
+use crate::config::ClientConfig;
+use crate::error::{SrwscError, ErrorCode};
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+
+// Events up to log.level go to stderr, so that they stay apart from what srwc
+// shows on stdout, and with log.file are also appended to that file as JSON
+// lines.
+pub fn init(c: &ClientConfig) -> Result<(), SrwscError> {
+    let level = c.log_level.parse::<LevelFilter>()
+        .map_err(|e| SrwscError::new(ErrorCode::InvalidArgument,
+                                     format!("log level {:?}: {}", c.log_level, e)))?;
+    let stderr = fmt::layer().with_target(false).with_writer(io::stderr);
+    let installed = match &c.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                             format!("cannot open {}: {}", path, e)))?;
+            let file = Arc::new(file);
+            let json = fmt::layer()
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+                .with_ansi(false)
+                .with_writer(move || LogFile(file.clone()));
+            tracing::subscriber::set_global_default(
+                tracing_subscriber::registry().with(level).with(stderr).with(json))
+        },
+        None => tracing::subscriber::set_global_default(
+            tracing_subscriber::registry().with(level).with(stderr)),
+    };
+    installed.map_err(|e| SrwscError::new(ErrorCode::ErrorRequest, e.to_string()))
+}
+
+struct LogFile(Arc<File>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
@@ -1,26 +1,41 @@
+// The code spells out `field: field` in struct literals, and names the server
+// types after the protocols.
+#![allow(clippy::redundant_field_names, clippy::upper_case_acronyms)]
+
+mod auth;
 mod config;
 mod error;
 mod http_client;
+mod logging;
 mod misc;
+mod pb;
+mod settings;
 mod grpc_client;
+mod tls;
+
+use srwsc_common::{checksum, compression, transfer};
+
+use auth::Credentials;
+use config::Login;
+use error::{ErrorCode, SrwscError};
 
 use std::process;
 use ace::App;
+use tracing::{debug, error, info};
 
 fn main() {
     let c = get_start_info();
     match c {
         Some(info) => {
-            println!("{:?}", info);
-            match &info.server_type {
-                config::ServerType::HTTP => { http_client::run(info); },
-                config::ServerType::GRPC => {
-                    match grpc_client::run(info) {
-                        Err(e) => println!("Grpc Client error with: {}", e),
-                        _ => {},
-                    }
-                },
-                _ => println!("Not implement"),
+            debug!(config = ?info);
+            let result = match &info.server_type {
+                config::ServerType::HTTP |
+                config::ServerType::HTTPS => http_client::run(info),
+                config::ServerType::GRPC => grpc_client::run(info).map_err(SrwscError::from),
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                process::exit(e.code().exit_code());
             }
         },
         None => println!("argument is none"),
@@ -31,68 +46,41 @@ fn get_start_info() -> Option<config::ClientConfig> {
     let app = App::new()
         .config(config::CLIENT_NAME, config::VERSION)
         .cmd("start", "Start client with user config")
+        .cmd("get", "Download [FILE] and exit")
+        .cmd("put", "Upload [FILE] and exit")
+        .cmd("rm", "Remove [FILE] on the server and exit")
+        .cmd("ls", "List the files of the server, or of [DIR], and exit")
         .cmd("help", "Print help information")
         .cmd("version", "Print version information")
+        .opt("-C", "Set the configuration file (default: srwc.toml, ~/.config/srwc/srwc.toml, /etc/srwc/srwc.toml)")
         .opt("-t", "Set server type (Use one of http, https, grpc)")
         .opt("-a", "Set the connecting address and port on server")
-        .opt("-r", "Set the root directory");
+        .opt("-r", "Set the root directory")
+        .opt("-c", "Set the CA certificate file (PEM) to verify the server")
+        .opt("-n", "Set the server hostname to verify (default: localhost)")
+        .opt("-i", "Set the client certificate file (PEM) for grpc mutual TLS")
+        .opt("-k", "Set the client private key file (PEM) for grpc mutual TLS")
+        .opt("-s", "Set the chunk size in bytes for grpc file streams")
+        .opt("-z", "Set the compression for file transfers (Use one of none, gzip, zstd)")
+        .opt("-u", "Set the user name to log in with; the password is asked for")
+        .opt("-p", "Set the file holding an API token to log in with");
 
     if let Some(cmd) = app.command() {
         match cmd.as_str() {
-            "start" => {
-                let mut c = config::ClientConfig::new();
-                let server_type = app
-                    .value("-t")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-t value: [SERVER TYPE(http, https, grpc)]");
-                            process::exit(-1);
-                        }
-                        values[0].clone()
-                    });
-                match server_type {
-                    Some(t) => {
-                        match t.as_str() {
-                            "http" => c.server_type = config::ServerType::HTTP,
-                            "https" => c.server_type = config::ServerType::HTTPS,
-                            "grpc" => c.server_type = config::ServerType::GRPC,
-                            _ => {
-                                println!("1 -t value: [SERVER TYPE(http, https, grpc)]");
-                                process::exit(-1);
-                            }
-                        }
+            "start" => Some(load_config(&app)),
+            "get" | "put" | "rm" | "ls" => {
+                let command = match (cmd.as_str(), command_argument(&app, cmd)) {
+                    ("ls", Some(dir)) => format!("ls -- {}", dir),
+                    ("ls", None) => String::from("ls"),
+                    ("get", Some(file)) => format!("get -- {}", file),
+                    (cmd, Some(file)) => format!("{} {}", cmd, file),
+                    (cmd, None) => {
+                        println!("{} needs [FILE]", cmd);
+                        process::exit(ErrorCode::InvalidArgument.exit_code());
                     },
-                    None => println!("Use default value for server type"),
-                }
-
-                let addr = app
-                    .value("-a")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-a value: [ADDRESS:PORT]");
-                            process::exit(-1);
-                        }
-                        values[0].clone()
-                    });
-                match addr {
-                    Some(a) => c.address = a.parse()
-                                            .expect("Unable to parse socket address"),
-                    None => println!("Use default value for connecting address"),
-                }
-
-                let storage = app
-                    .value("-r")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-r value: [DIR]");
-                            process::exit(-1);
-                        }
-                        values[0].clone()
-                    });
-                match storage {
-                    Some(s) => c.storage = s,
-                    None => println!("Use default value for storage"),
-                }
+                };
+                let mut c = load_config(&app);
+                c.command = Some(command);
                 Some(c)
             }
             "help" => {
@@ -112,3 +100,57 @@ fn get_start_info() -> Option<config::ClientConfig> {
         None
     }
 }
+
+// The settings, with the credentials read; exits when they are not usable.
+fn load_config(app: &App) -> config::ClientConfig {
+    let config_file = single_value(app, "-C", "[CONFIG FILE]");
+    let mut c = config::ClientConfig::new();
+    let loaded = settings::load(&mut c, config_file)
+        .and_then(|_| settings::load_options(&mut c, |opt| {
+            app.value(opt).map(|values| values.into_iter().cloned().collect())
+        }));
+    if let Err(e) = loaded {
+        println!("Invalid configuration: {}", e.message());
+        process::exit(e.code().exit_code());
+    }
+    if let Err(e) = logging::init(&c) {
+        println!("Unable to set up logging: {}", e);
+        process::exit(e.code().exit_code());
+    }
+
+    let credentials = match &c.login {
+        Some(Login::User(user)) => Some(Credentials::prompt(user)),
+        Some(Login::TokenFile(path)) => Some(Credentials::from_token_file(path)),
+        None => None,
+    };
+    match credentials {
+        Some(Ok(credentials)) => c.credentials = Some(credentials),
+        Some(Err(e)) => {
+            println!("Unable to get credentials: {}", e);
+            process::exit(e.code().exit_code());
+        },
+        None => info!("No credentials, not logging in"),
+    }
+    c
+}
+
+// The value of `opt`, which may be given once.
+fn single_value(app: &App, opt: &str, usage: &str) -> Option<String> {
+    app.value(opt).map(|values| {
+        if values.len() != 1 {
+            println!("{} value: {}", opt, usage);
+            process::exit(ErrorCode::InvalidArgument.exit_code());
+        }
+        values[0].clone()
+    })
+}
+
+// The argument right after `cmd`, as in "srwc get [FILE]"; there is at most one.
+fn command_argument(app: &App, cmd: &str) -> Option<String> {
+    let values = app.value(cmd).unwrap_or_default();
+    if values.len() > 1 {
+        println!("{} takes one [FILE]", cmd);
+        process::exit(ErrorCode::InvalidArgument.exit_code());
+    }
+    values.first().map(|value| value.to_string())
+}
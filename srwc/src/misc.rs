@@ -1,61 +1,475 @@
+use crate::checksum;
+use crate::checksum::Checksum;
+use crate::compression::Compression;
 use crate::config::ServerFile;
+use crate::pb::{FileInfo, ListRequest, QuotaInfo, SortKey};
 
 use console::{Term, style};
-use regex::Regex;
+use std::fmt;
 use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::path::{Component, Path};
 
-pub fn srwc_prompt() {
+pub fn srwc_prompt(cwd: &str) {
     let term = Term::stdout();
-    term.write_str("SRWC> ").unwrap();
+    if cwd.is_empty() {
+        term.write_str("SRWC> ").unwrap();
+    } else {
+        term.write_str(&format!("SRWC:/{}> ", cwd)).unwrap();
+    }
 }
 
 pub fn srwc_help(){
     println!("{}", style("Available SRWC commands:").magenta());
     println!("{} {}\t-> {}", style("get").green(), style("\"filename\"").blue(), style("Download file from server").cyan());
+    println!("{} {}\t-> {}", style("get").green(), style("start length -- \"filename\"").blue(), style("Download part of a file as filename.start-end").cyan());
     println!("{} {}\t-> {}", style("put").green(), style("\"filename\"").blue(), style("Upload file to server").cyan());
+    println!("{} {}\t-> {}", style("stat").green(), style("\"filename\"").blue(), style("Show size and checksum of file in server").cyan());
     println!("{} {}\t-> {}", style("rm").green(), style("\"filename\"").blue(), style("Remove file in server").cyan());
-    println!("{}\t\t-> {}", style("ls").green(), style("Show files in server").cyan());
+    println!("{} {}\t-> {}", style("mkdir").green(), style("\"dir\"").blue(), style("Create directory in server").cyan());
+    println!("{} {}\t-> {}", style("rmdir").green(), style("\"dir\"").blue(), style("Remove empty directory in server").cyan());
+    println!("{} {}\t-> {}", style("cd").green(), style("\"dir\"").blue(), style("Change remote working directory").cyan());
+    println!("{}\t\t-> {}", style("pwd").green(), style("Show remote working directory").cyan());
+    println!("{} {}\t-> {}", style("ls").green(), style("[-r] [dir]").blue(), style("Show files in server").cyan());
+    println!("\t{}\t-> {}", style("-p pattern").blue(), style("Only names matching a glob like *.txt").cyan());
+    println!("\t{}\t-> {}", style("-s name|size|mtime").blue(), style("Sort by name (default), size or time").cyan());
+    println!("\t{}\t\t-> {}", style("-d").blue(), style("Sort in descending order").cyan());
+    println!("\t{}\t-> {}", style("-n count -c token").blue(), style("Show one page, starting at token").cyan());
+    println!("{}\t\t-> {}", style("quota").green(), style("Show used space and the limits of the server").cyan());
+    println!("{}\t\t-> {}", style("df").green(), style("Show used and available space in server").cyan());
     println!("{}\t\t-> {}", style("help").green(), style("Show available commands").cyan());
     println!("{}\t\t-> {}", style("quit").green(), style("Quit SRWC").cyan());
 }
 
-pub fn file_list_response(response: &String) -> Vec<ServerFile> {
-    let mut file_list: Vec<ServerFile> = Vec::new();
-    let file_name_regex: Regex = Regex::new(r"(.*)(?:\s\s\[.*\sbytes\][\n\r])").unwrap();
-    let file_size_regex: Regex = Regex::new(r"(\d+)(?:\sbytes\][\n\r])").unwrap();
+pub fn check_file(file_name: &str, storage: &str) -> ServerFile {
+    let mut f = ServerFile::new();
+    let fullpath = Path::new(storage).join(file_name);
 
-    for cp in file_name_regex.captures_iter(response).enumerate() {
-        let mut f = ServerFile::new();
-        f.name = String::from(&cp.1[1]);
-        file_list.push(f);
+    if let Ok(metadata) = fs::metadata(&fullpath) {
+        if metadata.is_file() {
+            f.size = metadata.len();
+            f.name = file_name.to_string();
+            f.fullpath = String::from(fullpath.to_string_lossy());
+        }
     }
 
-    for (i, cap) in file_size_regex.captures_iter(response).enumerate() {
-        file_list[i].size = cap[1].parse::<u64>().unwrap();
+    f
+}
+
+pub fn print_file_list(files: &[FileInfo]) {
+    println!("{}", style("Server files: ").magenta());
+    print_file_entries(files);
+}
+
+pub fn print_file_entries(files: &[FileInfo]) {
+    for entry in files.iter() {
+        let details = format!("{:04o}  {}", entry.mode, format_mtime(entry.mtime));
+        if entry.is_dir {
+            println!("{}  {}", style(format!("{}/", entry.name)).blue(),
+                               style(details).dim());
+        } else if !entry.digest.is_empty() {
+            println!("{}  [{} bytes]  {}  {}", style(&entry.name).green(),
+                                              style(&entry.size).cyan(),
+                                              style(details).dim(),
+                                              style(format!("{}:{}", checksum::ALGORITHM,
+                                                            entry.digest)).yellow());
+        } else {
+            println!("{}  [{} bytes]  {}", style(&entry.name).green(),
+                                           style(&entry.size).cyan(),
+                                           style(details).dim());
+        }
+        if entry.partial_size > 0 {
+            println!("  {} bytes of an interrupted upload kept to resume",
+                     style(entry.partial_size).cyan());
+        }
     }
+}
 
-    file_list
+pub fn print_quota(info: &QuotaInfo) {
+    println!("{}", style("Quota: ").magenta());
+    println!("bytes: {} used of {}", style(info.used_bytes).cyan(),
+             limit(info.used_bytes, info.quota_bytes));
+    println!("files: {} used of {}", style(info.used_files).cyan(),
+             limit(info.used_files, info.quota_files));
 }
 
-pub fn check_file(file_name: &str, storage: &str) -> ServerFile {
-    let mut f = ServerFile::new();
+pub fn print_df(info: &QuotaInfo) {
+    println!("{:>16}  {:>16}  {:>4}", "Used", "Available", "Use%");
+    match info.available_bytes {
+        u64::MAX => println!("{:>16}  {:>16}  {:>4}", info.used_bytes, "unlimited", "-"),
+        available => println!("{:>16}  {:>16}  {:>3}%", info.used_bytes, available,
+                              percent(info.used_bytes, info.used_bytes.saturating_add(available))),
+    }
+}
 
-    for entry in fs::read_dir(storage).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if !path.is_dir() {
-            let fullpath = String::from(entry.path().to_string_lossy());
-            let filename = String::from(str::replace(&fullpath, storage, ""));
+// A quota of 0 is no limit.
+fn limit(used: u64, quota: u64) -> String {
+    match quota {
+        0 => String::from("no limit"),
+        quota => format!("{} ({}%)", quota, percent(used, quota)),
+    }
+}
+
+fn percent(used: u64, total: u64) -> u64 {
+    match total {
+        0 => 0,
+        total => (used as u128 * 100 / total as u128) as u64,
+    }
+}
 
-            if &filename[1..] == file_name {
-                let file = fs::File::open(&fullpath).unwrap();
-                f.size = file.metadata().unwrap().len();
-                f.name = file_name.to_string();
-                f.fullpath = fullpath;
-                break;
-            }
+// Formats seconds since the Unix epoch as a UTC date and time, following the
+// days-to-civil algorithm of the proleptic Gregorian calendar.
+pub fn format_mtime(mtime: i64) -> String {
+    let days = mtime.div_euclid(86400);
+    let seconds = mtime.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+pub fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(' ') {
+        Some(index) => (&line[..index], line[index + 1..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+// Parses "ls [-r] [-d] [-s name|size|mtime] [-p PATTERN] [-n COUNT]
+// [-c TOKEN] [DIR]" into the request, less its path, and the directory as
+// typed. Options come first, up to an optional "--".
+pub fn ls_args(args: &str) -> Result<(ListRequest, &str), String> {
+    let mut request = ListRequest::default();
+    let mut rest = args;
+    while rest.starts_with('-') {
+        let (option, tail) = split_command(rest);
+        rest = tail;
+        match option {
+            "--" => break,
+            "-r" => request.recursive = true,
+            "-d" => request.descending = true,
+            "-s" | "-p" | "-n" | "-c" => {
+                let (value, tail) = split_command(rest);
+                if value.is_empty() {
+                    return Err(format!("{} needs a value", option));
+                }
+                rest = tail;
+                match option {
+                    "-s" => request.sort = match value {
+                        "name" => SortKey::Name,
+                        "size" => SortKey::Size,
+                        "mtime" => SortKey::Mtime,
+                        _ => return Err(format!("Unknown sort key {}", value)),
+                    } as i32,
+                    "-p" => request.pattern = value.to_string(),
+                    "-n" => request.page_size = match value.parse() {
+                        Ok(count) if count > 0 => count,
+                        _ => return Err(format!("Invalid page size {}", value)),
+                    },
+                    _ => request.page_token = value.to_string(),
+                }
+            },
+            _ => return Err(format!("Unknown option {}", option)),
         }
     }
+    Ok((request, rest))
+}
 
-    f
+// Builds the ls command sent over TCP, which takes the same options.
+pub fn ls_command(request: &ListRequest) -> String {
+    let mut command = String::from("ls");
+    if request.recursive {
+        command.push_str(" -r");
+    }
+    if request.descending {
+        command.push_str(" -d");
+    }
+    match SortKey::from_i32(request.sort) {
+        Some(SortKey::Size) => command.push_str(" -s size"),
+        Some(SortKey::Mtime) => command.push_str(" -s mtime"),
+        _ => {},
+    }
+    if !request.pattern.is_empty() {
+        command.push_str(&format!(" -p {}", request.pattern));
+    }
+    if request.page_size > 0 {
+        command.push_str(&format!(" -n {}", request.page_size));
+    }
+    if !request.page_token.is_empty() {
+        command.push_str(&format!(" -c {}", request.page_token));
+    }
+    if request.path.starts_with('-') {
+        command.push_str(" --");
+    }
+    if !request.path.is_empty() {
+        command.push(' ');
+        command.push_str(&request.path);
+    }
+    command
+}
+
+// Tells how to get the page after one that was asked for explicitly.
+pub fn print_next_page(request: &ListRequest, next_page_token: &str) {
+    let mut next = request.clone();
+    next.path = format!("/{}", request.path);
+    next.page_token = next_page_token.to_string();
+    println!("{} {}", style("More files:").magenta(), ls_command(&next));
+}
+
+// Splits "get" arguments into the path and an optional byte range, given in
+// front of it as "START LENGTH -- PATH". A path that starts like a range is
+// typed as "-- PATH".
+pub fn range_args(args: &str) -> (&str, Option<(u64, u64)>) {
+    if let Some(path) = args.strip_prefix("-- ") {
+        return (path, None);
+    }
+    let words: Vec<&str> = args.splitn(4, ' ').collect();
+    if let [start, length, "--", path] = words[..] {
+        if let (Ok(start), Ok(length)) = (start.parse::<u64>(), length.parse::<u64>()) {
+            return (path, Some((start, length)));
+        }
+    }
+    (args, None)
+}
+
+// A ranged download is kept apart from the full copy of the file, so that it
+// is never mistaken for a partial download to resume.
+pub fn range_name(local: &str, start: u64, length: u64) -> String {
+    format!("{}.{}-{}", local, start, start + length)
+}
+
+// Resolves a path typed in the REPL against the remote working directory.
+// The result is relative to the server root ("" is the root itself), or
+// None when the path climbs above the root.
+pub fn remote_path(cwd: &str, path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        parts.extend(cwd.split('/').filter(|p| !p.is_empty()));
+    }
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop()?;
+            },
+            p => parts.push(p),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+// The local copy of a remote file keeps the relative path typed by the user,
+// unless that path would leave the local storage directory.
+pub fn local_name(path: &str) -> String {
+    let p = Path::new(path);
+    if p.components().all(|c| matches!(c, Component::Normal(_))) {
+        return path.to_string();
+    }
+    match p.file_name() {
+        Some(name) => String::from(name.to_string_lossy()),
+        None => String::new(),
+    }
+}
+
+// Where a download continues: a local file is resumed when the server's
+// digest of as many first bytes of the remote file matches its own digest;
+// anything else is downloaded again from the start. `local_digest` hashes the
+// first bytes of the local file.
+pub fn download_offset<F>(local_size: Option<u64>, remote: Option<&FileInfo>, local_digest: F)
+    -> u64
+    where F: FnOnce(u64) -> Option<String> {
+    match (local_size, remote) {
+        (Some(local), Some(remote)) if local > 0 && local <= remote.size
+            && !remote.prefix_digest.is_empty()
+            && local_digest(local).as_deref() == Some(remote.prefix_digest.as_str()) => local,
+        _ => 0,
+    }
+}
+
+// Where an upload continues: what the server kept of an interrupted upload,
+// which it throws away if the digest of the whole file does not match in the
+// end, so that the next try starts over; or else a remote file whose digest
+// matches as many first bytes of the local one. Anything else is uploaded
+// again from the start.
+pub fn upload_offset<F>(local_size: u64, remote: Option<&FileInfo>, local_digest: F) -> u64
+    where F: FnOnce(u64) -> Option<String> {
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return 0,
+    };
+    if remote.partial_size > 0 && remote.partial_size <= local_size {
+        return remote.partial_size;
+    }
+    match remote.size {
+        size if size > 0 && size <= local_size && !remote.digest.is_empty()
+            && local_digest(size).as_deref() == Some(remote.digest.as_str()) => size,
+        _ => 0,
+    }
+}
+
+// The digest of the first `length` bytes of a local file.
+pub fn local_digest(file_name: &str, storage: &str, length: u64) -> Option<String> {
+    local_checksum(file_name, storage, length).ok().map(|checksum| checksum.finish())
+}
+
+pub fn local_file_size(file_name: &str, storage: &str) -> Option<u64> {
+    match fs::metadata(Path::new(storage).join(file_name)) {
+        Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+        _ => None,
+    }
+}
+
+// Opens the local copy of a download, keeping the first `offset` bytes of an
+// earlier partial download.
+pub fn open_local_file(file_name: &str, storage: &str, offset: u64)
+    -> io::Result<File> {
+    let fullname = Path::new(storage).join(file_name);
+    if let Some(parent) = fullname.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if offset == 0 {
+        return File::create(fullname);
+    }
+
+    let mut file = OpenOptions::new().write(true).open(fullname)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(file)
+}
+
+// Starts the checksum of a download with the part of the local copy that is
+// being kept.
+pub fn local_checksum(file_name: &str, storage: &str, offset: u64)
+    -> io::Result<Checksum> {
+    let mut checksum = Checksum::new();
+    if offset > 0 {
+        let mut file = File::open(Path::new(storage).join(file_name))?;
+        checksum.update_from(&mut file, offset)?;
+    }
+    Ok(checksum)
+}
+
+// The size of a transferred file and what it took on the wire.
+pub struct Transferred {
+    pub bytes: u64,
+    pub wire_bytes: u64,
+    pub compression: Compression,
+}
+
+impl fmt::Display for Transferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.compression {
+            Compression::None => write!(f, "{} bytes", self.bytes),
+            compression => write!(f, "{} bytes, {} bytes with {}",
+                                  self.bytes, self.wire_bytes, compression.name()),
+        }
+    }
+}
+
+pub fn remove_local_file(file_name: &str, storage: &str) {
+    let _ = fs::remove_file(Path::new(storage).join(file_name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn ranges_come_before_the_path() {
+        assert_eq!(range_args("a.txt"), ("a.txt", None));
+        assert_eq!(range_args("5 10 -- a.txt"), ("a.txt", Some((5, 10))));
+        assert_eq!(range_args("5 10 -- a 1 2"), ("a 1 2", Some((5, 10))));
+    }
+
+    #[test]
+    fn paths_ending_in_numbers_are_paths() {
+        assert_eq!(range_args("a 1 2"), ("a 1 2", None));
+        assert_eq!(range_args("report 2024"), ("report 2024", None));
+        assert_eq!(range_args("-- 5 10 -- a"), ("5 10 -- a", None));
+    }
+
+    fn remote(size: u64, digest: &str, prefix_digest: &str, partial_size: u64) -> FileInfo {
+        FileInfo {
+            size: size,
+            digest: digest.to_string(),
+            prefix_digest: prefix_digest.to_string(),
+            partial_size: partial_size,
+            ..FileInfo::default()
+        }
+    }
+
+    fn digest(digest: &str) -> impl FnOnce(u64) -> Option<String> + '_ {
+        move |_| Some(digest.to_string())
+    }
+
+    #[test]
+    fn download_resumes_a_matching_prefix_of_the_remote_file() {
+        assert_eq!(download_offset(Some(10), Some(&remote(100, "d", "p", 0)), digest("p")), 10);
+        assert_eq!(download_offset(Some(100), Some(&remote(100, "d", "d", 0)), digest("d")), 100);
+    }
+
+    #[test]
+    fn download_starts_over_otherwise() {
+        // The local file was changed, or belongs to another version.
+        assert_eq!(download_offset(Some(10), Some(&remote(100, "d", "p", 0)), digest("x")), 0);
+        assert_eq!(download_offset(Some(10), Some(&remote(100, "d", "p", 0)), |_| None), 0);
+        // The server did not digest a prefix.
+        assert_eq!(download_offset(Some(10), Some(&remote(100, "d", "", 0)), digest("")), 0);
+        assert_eq!(download_offset(Some(101), Some(&remote(100, "d", "", 0)), digest("")), 0);
+        assert_eq!(download_offset(Some(0), Some(&remote(100, "d", "p", 0)), digest("p")), 0);
+        assert_eq!(download_offset(None, Some(&remote(100, "d", "p", 0)), digest("p")), 0);
+        assert_eq!(download_offset(Some(10), None, digest("p")), 0);
+    }
+
+    #[test]
+    fn upload_resumes_a_remote_file_that_matches_the_local_prefix() {
+        assert_eq!(upload_offset(100, Some(&remote(10, "d", "", 0)), digest("d")), 10);
+        assert_eq!(upload_offset(100, Some(&remote(100, "d", "", 0)), digest("d")), 100);
+    }
+
+    #[test]
+    fn upload_of_a_changed_file_starts_over() {
+        // Same size or larger, other content: the remote file is no prefix.
+        assert_eq!(upload_offset(100, Some(&remote(100, "d", "", 0)), digest("x")), 0);
+        assert_eq!(upload_offset(100, Some(&remote(10, "d", "", 0)), digest("x")), 0);
+        assert_eq!(upload_offset(100, Some(&remote(10, "", "", 0)), digest("")), 0);
+        assert_eq!(upload_offset(100, Some(&remote(101, "d", "", 0)), digest("d")), 0);
+        assert_eq!(upload_offset(100, None, digest("d")), 0);
+    }
+
+    #[test]
+    fn upload_prefers_an_interrupted_upload() {
+        assert_eq!(upload_offset(100, Some(&remote(10, "d", "", 50)), digest("x")), 50);
+        assert_eq!(upload_offset(100, Some(&remote(0, "", "", 50)), digest("x")), 50);
+        assert_eq!(upload_offset(100, Some(&remote(10, "d", "", 150)), digest("d")), 10);
+        assert_eq!(upload_offset(100, Some(&remote(0, "", "", 150)), digest("d")), 0);
+    }
+
+    #[test]
+    fn resumed_download_keeps_only_the_offset() {
+        let storage = std::env::temp_dir().join(format!("srwc-misc-{}", std::process::id()));
+        let storage = storage.to_str().unwrap();
+        fs::create_dir_all(storage).unwrap();
+        fs::write(Path::new(storage).join("file"), b"0123456789").unwrap();
+
+        let mut file = open_local_file("file", storage, 4).unwrap();
+        file.write_all(b"abc").unwrap();
+        drop(file);
+        let mut content = String::new();
+        File::open(Path::new(storage).join("file")).unwrap()
+            .read_to_string(&mut content).unwrap();
+        fs::remove_dir_all(storage).unwrap();
+        assert_eq!(content, "0123abc");
+    }
 }
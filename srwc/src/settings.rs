@@ -0,0 +1,278 @@
+use crate::compression::Compression;
+use crate::config::{ClientConfig, Login, ServerType, CLIENT_NAME, MAX_CHUNK_SIZE};
+use crate::error::{SrwscError, ErrorCode};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// srwc takes its settings, each one overriding the ones before, from
+//   1. the defaults in config.rs,
+//   2. the configuration file,
+//   3. SRWC_* environment variables,
+//   4. the options of "srwc start", or of the one-shot get, put, rm and ls.
+// The configuration file is the one given with -C or in $SRWC_CONFIG, which
+// then has to exist, or else the first one found of
+//   ./srwc.toml
+//   $XDG_CONFIG_HOME/srwc/srwc.toml, by default ~/.config/srwc/srwc.toml
+//   /etc/srwc/srwc.toml
+// It is TOML; every key is optional and shown here with the variable and the
+// option that override it:
+//   [server]
+//   type = "https"                    # SRWC_TYPE, -t: http, https or grpc
+//   address = "192.168.0.10:1417"     # SRWC_ADDRESS, -a
+//   hostname = "files.example.com"    # SRWC_HOSTNAME, -n
+//   [client]
+//   storage = "/home/alice/srwc"      # SRWC_STORAGE, -r
+//   [tls]
+//   ca = "/etc/srwc/ca.pem"           # SRWC_CA, -c
+//   cert = "/etc/srwc/cert.pem"       # SRWC_CERT, -i
+//   key = "/etc/srwc/key.pem"         # SRWC_KEY, -k
+//   [auth]
+//   user = "alice"                    # SRWC_USER, -u
+//   token_file = "/etc/srwc/token"    # SRWC_TOKEN_FILE, -p
+//   [limits]
+//   chunk_size = 65536                # SRWC_CHUNK_SIZE, -s
+//   compression = "zstd"              # SRWC_COMPRESSION, -z: none, gzip or zstd
+//   [log]
+//   level = "warn"                    # SRWC_LOG_LEVEL: error, warn, info, debug or trace
+//   file = "/tmp/srwc.json"           # SRWC_LOG_FILE, written as JSON lines
+// A user and a token file replace each other, so that a token file in the
+// environment wins over a user in the file.
+const CONFIG_ENV: &str = "SRWC_CONFIG";
+const CONFIG_FILE: &str = "srwc.toml";
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    opt: Option<&'static str>,
+    // What the value has to look like, for error messages.
+    usage: &'static str,
+    // False when the value is invalid.
+    apply: fn(&mut ClientConfig, &str) -> bool,
+}
+
+static SETTINGS: &[Setting] = &[
+    Setting {
+        key: "server.type", env: "SRWC_TYPE", opt: Some("-t"),
+        usage: "[SERVER TYPE(http, https, grpc)]",
+        apply: |c, value| {
+            c.server_type = match value {
+                "http" => ServerType::HTTP,
+                "https" => ServerType::HTTPS,
+                "grpc" => ServerType::GRPC,
+                _ => return false,
+            };
+            true
+        },
+    },
+    Setting {
+        key: "server.address", env: "SRWC_ADDRESS", opt: Some("-a"),
+        usage: "[ADDRESS:PORT]",
+        apply: |c, value| value.parse().map(|address| c.address = address).is_ok(),
+    },
+    Setting {
+        key: "server.hostname", env: "SRWC_HOSTNAME", opt: Some("-n"),
+        usage: "[HOSTNAME]",
+        apply: |c, value| set_value(&mut c.hostname, value),
+    },
+    Setting {
+        key: "client.storage", env: "SRWC_STORAGE", opt: Some("-r"),
+        usage: "[DIR]",
+        apply: |c, value| set_value(&mut c.storage, value),
+    },
+    Setting {
+        key: "tls.ca", env: "SRWC_CA", opt: Some("-c"),
+        usage: "[CA FILE]",
+        apply: |c, value| set_optional_value(&mut c.ca, value),
+    },
+    Setting {
+        key: "tls.cert", env: "SRWC_CERT", opt: Some("-i"),
+        usage: "[CERT FILE]",
+        apply: |c, value| set_optional_value(&mut c.cert, value),
+    },
+    Setting {
+        key: "tls.key", env: "SRWC_KEY", opt: Some("-k"),
+        usage: "[KEY FILE]",
+        apply: |c, value| set_optional_value(&mut c.key, value),
+    },
+    Setting {
+        key: "auth.user", env: "SRWC_USER", opt: Some("-u"),
+        usage: "[USER]",
+        apply: |c, value| {
+            if value.is_empty() {
+                return false;
+            }
+            c.login = Some(Login::User(value.to_string()));
+            true
+        },
+    },
+    Setting {
+        key: "auth.token_file", env: "SRWC_TOKEN_FILE", opt: Some("-p"),
+        usage: "[TOKEN FILE]",
+        apply: |c, value| {
+            if value.is_empty() {
+                return false;
+            }
+            c.login = Some(Login::TokenFile(value.to_string()));
+            true
+        },
+    },
+    Setting {
+        key: "limits.chunk_size", env: "SRWC_CHUNK_SIZE", opt: Some("-s"),
+        usage: "[BYTES] between 1 and 1048576",
+        apply: |c, value| match value.parse::<usize>() {
+            Ok(size) if size > 0 && size <= MAX_CHUNK_SIZE => {
+                c.chunk_size = size;
+                true
+            },
+            _ => false,
+        },
+    },
+    Setting {
+        key: "limits.compression", env: "SRWC_COMPRESSION", opt: Some("-z"),
+        usage: "[COMPRESSION(none, gzip, zstd)]",
+        apply: |c, value| Compression::from_name(value)
+            .map(|compression| c.compression = compression)
+            .is_some(),
+    },
+    Setting {
+        key: "log.level", env: "SRWC_LOG_LEVEL", opt: None,
+        usage: "[LEVEL(error, warn, info, debug, trace)]",
+        apply: |c, value| {
+            if !LOG_LEVELS.contains(&value) {
+                return false;
+            }
+            c.log_level = value.to_string();
+            true
+        },
+    },
+    Setting {
+        key: "log.file", env: "SRWC_LOG_FILE", opt: None,
+        usage: "[LOG FILE]",
+        apply: |c, value| set_optional_value(&mut c.log_file, value),
+    },
+];
+
+fn set_value(field: &mut String, value: &str) -> bool {
+    if value.is_empty() {
+        return false;
+    }
+    *field = value.to_string();
+    true
+}
+
+fn set_optional_value(field: &mut Option<String>, value: &str) -> bool {
+    if value.is_empty() {
+        return false;
+    }
+    *field = Some(value.to_string());
+    true
+}
+
+fn invalid(message: String) -> SrwscError {
+    SrwscError::new(ErrorCode::InvalidArgument, message)
+}
+
+// Applies the configuration file, `path` or else the first one on the search
+// path, and then the environment.
+pub fn load(c: &mut ClientConfig, path: Option<String>) -> Result<(), SrwscError> {
+    let path = path.or_else(|| env::var(CONFIG_ENV).ok());
+    let file = match path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => search_path().into_iter().find(|path| path.is_file()),
+    };
+    if let Some(file) = file {
+        load_file(c, &file)?;
+        c.config_file = Some(file.to_string_lossy().into_owned());
+    }
+    load_env(c)
+}
+
+fn search_path() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join(CLIENT_NAME).join(CONFIG_FILE));
+    }
+    paths.push(PathBuf::from("/etc").join(CLIENT_NAME).join(CONFIG_FILE));
+    paths
+}
+
+fn load_file(c: &mut ClientConfig, path: &PathBuf) -> Result<(), SrwscError> {
+    let name = path.display();
+    let content = fs::read_to_string(path)
+        .map_err(|e| invalid(format!("cannot read {}: {}", name, e)))?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(invalid(format!("{}: expected a table", name))),
+        Err(e) => return Err(invalid(format!("{}: {}", name, e))),
+    };
+
+    for (section, values) in &table {
+        let values = match values {
+            toml::Value::Table(values) => values,
+            _ => return Err(invalid(format!("{}: {} is not a section, expected one of \
+                                             [server], [client], [tls], [auth], [limits], [log]",
+                                            name, section))),
+        };
+        for (key, value) in values {
+            let key = format!("{}.{}", section, key);
+            let setting = SETTINGS.iter()
+                .find(|setting| setting.key == key)
+                .ok_or_else(|| invalid(format!("{}: unknown setting {}", name, key)))?;
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                _ => return Err(invalid(format!("{}: {} has to be a string or a number",
+                                                name, key))),
+            };
+            if !(setting.apply)(c, &value) {
+                return Err(invalid(format!("{}: {} = {:?}, expected {}",
+                                           name, key, value, setting.usage)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_env(c: &mut ClientConfig) -> Result<(), SrwscError> {
+    for setting in SETTINGS {
+        let value = match env::var(setting.env) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => continue,
+            Err(e) => return Err(invalid(format!("{}: {}", setting.env, e))),
+        };
+        if !(setting.apply)(c, &value) {
+            return Err(invalid(format!("{}={:?}, expected {}",
+                                       setting.env, value, setting.usage)));
+        }
+    }
+    Ok(())
+}
+
+// Applies the options of "srwc start" and the one-shot commands; `values`
+// returns the values given for an option.
+pub fn load_options<F>(c: &mut ClientConfig, values: F) -> Result<(), SrwscError>
+    where F: Fn(&str) -> Option<Vec<String>> {
+    if values("-u").is_some() && values("-p").is_some() {
+        return Err(invalid(String::from("use either -u [USER] or -p [TOKEN FILE]")));
+    }
+    for setting in SETTINGS {
+        let opt = match setting.opt {
+            Some(opt) => opt,
+            None => continue,
+        };
+        let values = match values(opt) {
+            Some(values) => values,
+            None => continue,
+        };
+        if values.len() != 1 || !(setting.apply)(c, &values[0]) {
+            return Err(invalid(format!("{} value: {}", opt, setting.usage)));
+        }
+    }
+    Ok(())
+}
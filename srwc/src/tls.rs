@@ -0,0 +1,74 @@
+use crate::config;
+use crate::error::{SrwscError, ErrorCode};
+
+use rustls::{ClientConfig, ClientSession};
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+use webpki::DNSNameRef;
+
+pub fn client_config(ca: &Option<String>)
+    -> Result<Arc<ClientConfig>, SrwscError> {
+    let mut config = ClientConfig::new();
+    match ca {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| {
+                SrwscError::new(ErrorCode::ErrorTls,
+                                format!("cannot open CA file {}: {}", path, e))
+            })?;
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((valid, _)) if valid > 0 => {},
+                _ => return Err(SrwscError::new(ErrorCode::ErrorTls,
+                                                format!("no valid CA certificate in {}", path))),
+            }
+        },
+        None => {
+            config.root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        },
+    }
+    Ok(Arc::new(config))
+}
+
+pub fn client_session(config: &Arc<ClientConfig>, hostname: &str)
+    -> Result<ClientSession, SrwscError> {
+    let dns_name = DNSNameRef::try_from_ascii_str(hostname).map_err(|_| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("invalid server hostname {}", hostname))
+    })?;
+    Ok(ClientSession::new(config, dns_name))
+}
+
+fn read_pem(path: &str)
+    -> Result<Vec<u8>, SrwscError> {
+    fs::read(path).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("cannot read {}: {}", path, e))
+    })
+}
+
+// gRPC runs over TLS as soon as a CA or a client certificate is configured.
+pub fn grpc_client_config(c: &config::ClientConfig)
+    -> Result<Option<ClientTlsConfig>, SrwscError> {
+    if c.ca.is_none() && c.cert.is_none() {
+        return Ok(None);
+    }
+
+    let mut tls_config = ClientTlsConfig::new()
+        .domain_name(c.hostname.clone());
+    if let Some(ca) = &c.ca {
+        tls_config = tls_config.ca_certificate(Certificate::from_pem(read_pem(ca)?));
+    }
+    match (&c.cert, &c.key) {
+        (Some(cert), Some(key)) => {
+            tls_config = tls_config.identity(
+                Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        },
+        (None, None) => {},
+        _ => return Err(SrwscError::new(ErrorCode::ErrorTls,
+                                        String::from("client certificate requires both -i [CERT] and -k [KEY]"))),
+    }
+    Ok(Some(tls_config))
+}
@@ -0,0 +1,109 @@
+// Throughput of the TCP bulk data path on loopback, from a file on disk to the
+// socket, next to the 8-byte loop it replaced:
+//
+//     cargo bench --bench loopback
+//
+// The file is written once up front, so it is read from the page cache like a
+// file that is downloaded often. Every path computes the digest, as srws does.
+use srwsc_common::checksum::Checksum;
+use srwsc_common::transfer;
+
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIB: u64 = 1024 * 1024;
+
+// Reads what the server sends and throws it away.
+fn receiver(address: SocketAddr, size: u64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut buf = vec![0u8; transfer::CHUNK_SIZE];
+        let mut remaining_data = size;
+        while remaining_data > 0 {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed early");
+            remaining_data -= n as u64;
+        }
+    })
+}
+
+fn copy_path(path: &Path, size: u64, listener: &TcpListener) -> Duration {
+    let receiving = receiver(listener.local_addr().unwrap(), size);
+    let (mut stream, _) = listener.accept().unwrap();
+    let started = Instant::now();
+    let mut file = File::open(path).unwrap();
+    let mut checksum = Checksum::new();
+    transfer::send_data(&mut file, size, &mut checksum, &mut stream).unwrap();
+    receiving.join().unwrap();
+    started.elapsed()
+}
+
+// The kernel copies the file to the socket while another thread reads it
+// again for the digest.
+#[cfg(target_os = "linux")]
+fn sendfile_path(path: &Path, size: u64, listener: &TcpListener) -> Duration {
+    let receiving = receiver(listener.local_addr().unwrap(), size);
+    let (stream, _) = listener.accept().unwrap();
+    let started = Instant::now();
+    let mut file = File::open(path).unwrap();
+    let sendable = File::open(path).unwrap();
+    let hashing = thread::spawn(move || {
+        let mut checksum = Checksum::new();
+        checksum.update_from(&mut file, size).unwrap();
+        checksum.finish()
+    });
+    transfer::sendfile(&sendable, size, &stream, Duration::from_secs(60)).unwrap();
+    hashing.join().unwrap();
+    receiving.join().unwrap();
+    started.elapsed()
+}
+
+// The data path before the transfer module: one read of the file and one
+// write to the socket per 8 bytes.
+fn legacy_path(path: &Path, size: u64, listener: &TcpListener) -> Duration {
+    let receiving = receiver(listener.local_addr().unwrap(), size);
+    let (mut stream, _) = listener.accept().unwrap();
+    let started = Instant::now();
+    let mut file = File::open(path).unwrap();
+    let mut checksum = Checksum::new();
+    let mut buf = [0u8; 8];
+    for _ in 0..size / 8 {
+        file.read_exact(&mut buf).unwrap();
+        checksum.update(&buf);
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    }
+    receiving.join().unwrap();
+    started.elapsed()
+}
+
+fn report(name: &str, size: u64, elapsed: Duration) {
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{:<10} {:>6} MiB in {:>8.3} s  {:>10.1} MiB/s",
+             name, size / MIB, seconds, (size / MIB) as f64 / seconds);
+}
+
+fn main() {
+    let sizes = [4 * MIB, 64 * MIB, 512 * MIB];
+    let path = std::env::temp_dir().join(format!("srws-bench-{}", std::process::id()));
+    let data: Vec<u8> = (0..MIB).map(|i| (i * 7 % 251) as u8).collect();
+    let mut file = File::create(&path).unwrap();
+    for _ in 0..sizes[sizes.len() - 1] / MIB {
+        file.write_all(&data).unwrap();
+    }
+    drop(file);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    for &size in &sizes {
+        report("copy", size, copy_path(&path, size, &listener));
+        #[cfg(target_os = "linux")]
+        report("sendfile", size, sendfile_path(&path, size, &listener));
+    }
+    report("8-byte", 4 * MIB, legacy_path(&path, 4 * MIB, &listener));
+    fs::remove_file(&path).unwrap();
+}
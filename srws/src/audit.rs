@@ -0,0 +1,245 @@
+use crate::config::ServerConfig;
+use crate::error::{SrwscError, ErrorCode};
+use crate::metrics::{Metrics, Protocol};
+use crate::permission::Operation;
+
+use chrono::{SecondsFormat, Utc};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+// Operations on files, the ones that are audited.
+const AUDITED: [&str; 5] = ["get", "put", "rm", "mkdir", "rmdir"];
+
+// One line of the audit log: who did what to which file, and how it ended.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Record {
+    // RFC 3339 in UTC, when the operation ended.
+    pub timestamp: String,
+    // The user logged in, or the identity of the client certificate.
+    pub user: Option<String>,
+    pub peer: Option<String>,
+    pub protocol: String,
+    pub command: String,
+    pub filename: String,
+    // Bytes of file data transferred, or the size of the file removed.
+    pub size: Option<u64>,
+    pub digest: Option<String>,
+    // "ok" or "error".
+    pub result: String,
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(protocol: Protocol,
+               operation: Operation,
+               filename: &str,
+               user: Option<&str>,
+               peer: Option<SocketAddr>) -> Self {
+        Record {
+            user: user.map(String::from),
+            peer: peer.map(|peer| peer.to_string()),
+            protocol: protocol.name().to_string(),
+            command: operation.to_string(),
+            filename: filename.to_string(),
+            ..Record::default()
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = String::from("-");
+        write!(f, "{} {} {} {} {} {} {} {} {}",
+               self.timestamp,
+               self.user.as_ref().unwrap_or(&unknown),
+               self.peer.as_ref().unwrap_or(&unknown),
+               self.protocol,
+               self.command,
+               self.filename,
+               self.size.map_or(unknown.clone(), |size| size.to_string()),
+               self.digest.as_ref().unwrap_or(&unknown),
+               self.result)?;
+        match &self.error {
+            Some(error) => write!(f, " {}", error),
+            None => Ok(()),
+        }
+    }
+}
+
+// Appends a line for every audited operation to audit.file, if set. Once the
+// file would grow beyond audit.max_size it is renamed to FILE.1, FILE.1 to
+// FILE.2 and so on, keeping audit.keep of them.
+pub struct Audit {
+    log: Option<Mutex<Log>>,
+    metrics: Arc<Metrics>,
+}
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl Audit {
+    pub fn open(c: &ServerConfig, metrics: Arc<Metrics>) -> Result<Self, SrwscError> {
+        let path = match &c.audit_file {
+            Some(path) => PathBuf::from(path),
+            None => {
+                return Ok(Audit {
+                    log: None,
+                    metrics: metrics,
+                })
+            },
+        };
+        let file = append(&path)?;
+        let size = file.metadata()
+            .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                         format!("cannot open {}: {}", path.display(), e)))?
+            .len();
+        Ok(Audit {
+            log: Some(Mutex::new(Log {
+                path: path,
+                file: file,
+                size: size,
+                max_size: c.audit_max_size,
+                keep: c.audit_keep,
+            })),
+            metrics: metrics,
+        })
+    }
+
+    // Writes `record` as it ended, with `error` unless it went through. A
+    // record that cannot be written is logged and counted, but does not fail
+    // the operation.
+    pub fn write(&self, mut record: Record, error: Option<String>) {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return,
+        };
+        if !AUDITED.contains(&record.command.as_str()) {
+            return;
+        }
+        record.timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        record.result = match error {
+            Some(_) => String::from("error"),
+            None => String::from("ok"),
+        };
+        record.error = error;
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Cannot write to the audit log: {}", e);
+                self.metrics.audit_failed();
+                return;
+            },
+        };
+        line.push(b'\n');
+        if let Err(e) = log.lock().unwrap().append(&line) {
+            error!(record = ?record, "Cannot write to the audit log: {}", e);
+            self.metrics.audit_failed();
+        }
+    }
+}
+
+impl Log {
+    // Every line is a single write, so lines never mix.
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // audit.keep is at least 1, so records are never thrown away before
+    // their file has been rotated out.
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            match fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                renamed => renamed?,
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<File, SrwscError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                     format!("cannot open {}: {}", path.display(), e)))
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// "srws audit": the records of `user` and of the files matching `filename`,
+// oldest first, from the rotated files and then the current one. Lines that do
+// not parse, like one cut off by a crash, are counted and skipped.
+pub fn query(path: &str, user: Option<&str>, filename: Option<&Pattern>)
+    -> Result<(Vec<Record>, usize), SrwscError> {
+    let path = PathBuf::from(path);
+    let mut files = Vec::new();
+    let mut n = 1;
+    while rotated(&path, n).is_file() {
+        files.push(rotated(&path, n));
+        n += 1;
+    }
+    files.reverse();
+    files.push(path);
+
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for file in &files {
+        let lines = match File::open(file) {
+            Ok(opened) => BufReader::new(opened).lines(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(SrwscError::new(ErrorCode::ErrorIo,
+                                                 format!("cannot read {}: {}",
+                                                         file.display(), e))),
+        };
+        for line in lines {
+            let line = line.map_err(|e| {
+                SrwscError::new(ErrorCode::ErrorIo,
+                                format!("cannot read {}: {}", file.display(), e))
+            })?;
+            let record = match serde_json::from_str::<Record>(&line) {
+                Ok(record) => record,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                },
+            };
+            if user.map_or(false, |user| record.user.as_deref() != Some(user)) {
+                continue;
+            }
+            if filename.map_or(false, |pattern| !pattern.matches(&record.filename)) {
+                continue;
+            }
+            records.push(record);
+        }
+    }
+    Ok((records, skipped))
+}
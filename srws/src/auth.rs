@@ -0,0 +1,256 @@
+use crate::error::{SrwscError, ErrorCode};
+use crate::permission::Role;
+
+use ring::{digest, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::num::NonZeroU32;
+
+// Each non-empty line of the credential file is "user = secret", where the
+// secret is either a password hash,
+//   alice = pbkdf2-sha256$ITERATIONS$SALT$HASH
+// or the hash of an API token,
+//   ci-bot = token$HASH
+// with SALT and HASH in hex. A user may have a password and any number of
+// tokens. Lines of the same form give the user a role,
+//   ci-bot = role$upload-only
+// users without one being read-only, and a storage other than its own,
+//   ci-bot = space$releases
+// which it shares with every user given the same one; the last line of each
+// counts. The lines are written by "srws passwd", "srws token", "srws role"
+// and "srws space".
+pub const PBKDF2_ITERATIONS: u32 = 100_000;
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+const TOKEN_SCHEME: &str = "token";
+const ROLE_SCHEME: &str = "role";
+const SPACE_SCHEME: &str = "space";
+const SALT_LEN: usize = 16;
+const TOKEN_LEN: usize = 32;
+static ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(ALGORITHM, self.iterations, &self.salt,
+                       password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+pub struct Credentials {
+    passwords: HashMap<String, PasswordHash>,
+    // Token hash to user. Tokens are long random strings, so a plain digest
+    // is enough to keep them out of the file.
+    tokens: HashMap<String, String>,
+    roles: HashMap<String, Role>,
+    spaces: HashMap<String, String>,
+    // Checked for unknown users, so that they take as long as known ones.
+    dummy: PasswordHash,
+}
+
+impl Credentials {
+    pub fn load(path: &str) -> Result<Self, SrwscError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            SrwscError::new(ErrorCode::ErrorAuth,
+                            format!("cannot read credential file {}: {}", path, e))
+        })?;
+
+        let mut credentials = Credentials {
+            passwords: HashMap::new(),
+            tokens: HashMap::new(),
+            roles: HashMap::new(),
+            spaces: HashMap::new(),
+            dummy: PasswordHash {
+                iterations: NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+                salt: vec![0u8; SALT_LEN],
+                hash: vec![0u8; digest::SHA256_OUTPUT_LEN],
+            },
+        };
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                SrwscError::new(ErrorCode::ErrorAuth,
+                                format!("{}:{}: {}", path, n + 1, reason))
+            };
+            let index = line.find('=')
+                .ok_or_else(|| invalid("expected \"user = secret\""))?;
+            let user = line[..index].trim();
+            validate_user(user).map_err(|e| invalid(e.message()))?;
+            let secret: Vec<&str> = line[index + 1..].trim().split('$').collect();
+            match secret.as_slice() {
+                [PASSWORD_SCHEME, iterations, salt, hash] => {
+                    let password = PasswordHash {
+                        iterations: iterations.parse().map_err(|_| invalid("invalid iterations"))?,
+                        salt: from_hex(salt).ok_or_else(|| invalid("invalid salt"))?,
+                        hash: from_hex(hash).ok_or_else(|| invalid("invalid hash"))?,
+                    };
+                    if credentials.passwords.insert(user.to_string(), password).is_some() {
+                        return Err(invalid("more than one password for the user"));
+                    }
+                },
+                [TOKEN_SCHEME, hash] => {
+                    from_hex(hash).ok_or_else(|| invalid("invalid hash"))?;
+                    credentials.tokens.insert(hash.to_lowercase(), user.to_string());
+                },
+                [ROLE_SCHEME, role] => {
+                    let role = role.parse().map_err(|e: SrwscError| invalid(e.message()))?;
+                    credentials.roles.insert(user.to_string(), role);
+                },
+                [SPACE_SCHEME, space] => {
+                    validate_user(space).map_err(|_| invalid("invalid space name"))?;
+                    credentials.spaces.insert(user.to_string(), space.to_string());
+                },
+                _ => return Err(invalid("unknown secret, expected a password, a token hash, a role or a space")),
+            }
+        }
+        Ok(credentials)
+    }
+
+    // Returns the user a password or a token belongs to.
+    pub fn check_password(&self, user: &str, password: &str) -> Option<String> {
+        match self.passwords.get(user) {
+            Some(hash) if hash.verify(password) => Some(user.to_string()),
+            Some(_) => None,
+            None => {
+                self.dummy.verify(password);
+                None
+            },
+        }
+    }
+
+    pub fn check_token(&self, token: &str) -> Option<String> {
+        self.tokens.get(&token_hash(token)).cloned()
+    }
+
+    pub fn role(&self, user: &str) -> Role {
+        self.roles.get(user).cloned().unwrap_or_default()
+    }
+
+    // The name of the storage the user works in; its own by default.
+    pub fn space(&self, user: &str) -> String {
+        self.spaces.get(user).cloned().unwrap_or_else(|| user.to_string())
+    }
+
+    // The arguments of a TCP login: "NAME PASSWORD" or "-t TOKEN".
+    pub fn login(&self, args: &str) -> Option<String> {
+        if let Some(token) = args.strip_prefix("-t ") {
+            return self.check_token(token.trim());
+        }
+        let mut words = args.splitn(2, ' ');
+        match (words.next(), words.next()) {
+            (Some(user), Some(password)) => self.check_password(user, password),
+            _ => None,
+        }
+    }
+
+    // The value of the gRPC "authorization" metadata: "Bearer TOKEN", or
+    // "Basic" and the base64 encoding of "NAME:PASSWORD" as in HTTP.
+    pub fn authorize(&self, authorization: &str) -> Option<String> {
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self.check_token(token.trim());
+        }
+        if let Some(basic) = authorization.strip_prefix("Basic ") {
+            let decoded = base64::decode(basic.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let index = decoded.find(':')?;
+            return self.check_password(&decoded[..index], &decoded[index + 1..]);
+        }
+        None
+    }
+}
+
+// User names end up in "user = secret" lines, in "NAME:PASSWORD", in gRPC
+// metadata and as the name of the user's directory, so they are limited to
+// printable ASCII without those separators and may not start with a dot.
+pub fn validate_user(user: &str) -> Result<(), SrwscError> {
+    if user.is_empty()
+        || user.starts_with('.')
+        || !user.chars().all(|c| c.is_ascii_graphic() && !"=:#/\\".contains(c)) {
+        return Err(SrwscError::new(ErrorCode::ErrorAuth,
+                                   format!("invalid user name {:?}", user)));
+    }
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> Result<String, SrwscError> {
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    let salt = random_bytes(SALT_LEN)?;
+    let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(ALGORITHM, iterations, &salt, password.as_bytes(), &mut hash);
+    Ok(format!("{}${}${}${}", PASSWORD_SCHEME, iterations, to_hex(&salt), to_hex(&hash)))
+}
+
+pub fn role_secret(role: Role) -> String {
+    format!("{}${}", ROLE_SCHEME, role)
+}
+
+// Spaces are directories under the storage root like the ones of users, so
+// they are named like users.
+pub fn space_secret(space: &str) -> Result<String, SrwscError> {
+    validate_user(space).map_err(|_| {
+        SrwscError::new(ErrorCode::InvalidArgument, format!("invalid space name {:?}", space))
+    })?;
+    Ok(format!("{}${}", SPACE_SCHEME, space))
+}
+
+// Returns a new API token and the secret to store for it.
+pub fn new_token() -> Result<(String, String), SrwscError> {
+    let token = to_hex(&random_bytes(TOKEN_LEN)?);
+    let secret = format!("{}${}", TOKEN_SCHEME, token_hash(&token));
+    Ok((token, secret))
+}
+
+// Appends "user = secret" to the credential file, which is created readable
+// by its owner only.
+pub fn add_credential(path: &str, user: &str, secret: &str) -> Result<(), SrwscError> {
+    validate_user(user)?;
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+        .and_then(|mut file| writeln!(file, "{} = {}", user, secret))
+        .map_err(|e| {
+            SrwscError::new(ErrorCode::ErrorAuth,
+                            format!("cannot write credential file {}: {}", path, e))
+        })
+}
+
+fn token_hash(token: &str) -> String {
+    to_hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, SrwscError> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| {
+        SrwscError::new(ErrorCode::ErrorAuth, String::from("no random numbers available"))
+    })?;
+    Ok(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
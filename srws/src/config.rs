@@ -1,4 +1,7 @@
+use crate::space::Quota;
+
 use std::net::SocketAddr;
+use std::time::Duration;
 
 pub const SERVER_NAME: &str = "srws";
 pub const VERSION: &str = "0.1.0";
@@ -6,15 +9,62 @@ pub const VERSION: &str = "0.1.0";
 pub const DEFAULT_TYPE: ServerType = ServerType::HTTP;
 pub const DEFAULT_ADDR: &str = "0.0.0.0:1417";
 pub const DEFAULT_STORAGE: &str = "/tmp/srws";
-pub const BUFFER_SIZE: usize = 8;
+pub const DEFAULT_MAX_SESSIONS: usize = 64;
+pub const DEFAULT_GRACE_PERIOD: u64 = 30;
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+// The audit log is rotated before it grows beyond this many bytes.
+pub const DEFAULT_AUDIT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+pub const DEFAULT_AUDIT_KEEP: usize = 5;
+// Longest control message taken from a client; a longer one ends the session.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// gRPC file streams are cut into messages of this size. The upper bound keeps
+// every message well below the default 4 MiB message limit of tonic.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+// Listings are returned in pages; a page streamed over gRPC is split into
+// messages of LIST_BATCH_SIZE entries.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+pub const MAX_PAGE_SIZE: usize = 10000;
+pub const LIST_BATCH_SIZE: usize = 100;
+// A TCP session is closed after this many failed logins, each of which is
+// answered only after the delay.
+pub const MAX_LOGIN_ATTEMPTS: u32 = 3;
+pub const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
+// A download the kernel sends with sendfile is cut off once the client has not
+// read anything for this long.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+// Interrupted uploads are kept for resuming; the ones older than this are
+// removed when the server starts and then every ORPHAN_SWEEP_INTERVAL.
+pub const ORPHAN_UPLOAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+pub const ORPHAN_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub const ACK_MESSAGE: &str              = "ACK";
+pub const NAK_MESSAGE: &str              = "NAK";
 pub const PREPARE_TRANSFER_MESSAGE: &str = "prepare transfer file";
 pub const CANNOT_FIND_FILE_MESSAGE: &str = "cannot find file";
 pub const REMOVED_OK_MESSAGE: &str       = "removed ok";
-pub const REMOVED_NOK_MESSAGE: &str      = "removed nok";
+pub const MKDIR_OK_MESSAGE: &str         = "mkdir ok";
+pub const CHECKSUM_OK_MESSAGE: &str      = "checksum ok";
+pub const FILE_INFO_MESSAGE: &str        = "file info";
+pub const FILE_LIST_MESSAGE: &str        = "file list";
+pub const LOGIN_OK_MESSAGE: &str         = "login ok";
+pub const LOGIN_FAILED_MESSAGE: &str     = "login failed";
+pub const LOGIN_REQUIRED_MESSAGE: &str   = "login required";
+pub const QUOTA_INFO_MESSAGE: &str       = "quota info";
+// Followed by a pb::Error frame.
+pub const ERROR_MESSAGE: &str            = "error";
 
 pub const GRPC_METADATA_FILENAME: &str  = "filename";
+pub const GRPC_METADATA_OFFSET: &str    = "offset";
+pub const GRPC_METADATA_COMPRESSION: &str = "compression";
+pub const GRPC_METADATA_ACCEPT_COMPRESSION: &str = "accept-compression";
+// Bytes a Put is going to send after the offset, before any compression.
+pub const GRPC_METADATA_LENGTH: &str    = "length";
+pub const GRPC_METADATA_AUTHORIZATION: &str = "authorization";
+// Set by the server itself once a call is authenticated.
+pub const GRPC_METADATA_USER: &str = "srws-user";
+pub const GRPC_METADATA_ROLE: &str = "srws-role";
+pub const GRPC_METADATA_SPACE: &str = "srws-space";
 
 
 #[derive(Debug)]
@@ -29,6 +79,26 @@ pub struct ServerConfig {
     pub server_type: ServerType,
     pub address: SocketAddr,
     pub storage: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+    pub identity_map: Option<String>,
+    pub credentials: Option<String>,
+    pub quota: Quota,
+    pub chunk_size: usize,
+    pub max_sessions: usize,
+    pub grace_period: Duration,
+    pub log_level: String,
+    pub log_file: Option<String>,
+    // Where to serve Prometheus metrics, if anywhere.
+    pub metrics_address: Option<SocketAddr>,
+    pub audit_file: Option<String>,
+    // 0 never rotates the audit log.
+    pub audit_max_size: u64,
+    // Rotated audit logs kept.
+    pub audit_keep: usize,
+    // The configuration file the settings were read from, if any.
+    pub config_file: Option<String>,
 }
 
 impl ServerConfig {
@@ -37,6 +107,22 @@ impl ServerConfig {
             server_type: DEFAULT_TYPE,
             address: DEFAULT_ADDR.parse().expect("Unable to parse socket address"),
             storage: DEFAULT_STORAGE.to_string(),
+            cert: None,
+            key: None,
+            client_ca: None,
+            identity_map: None,
+            credentials: None,
+            quota: Quota::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            grace_period: Duration::from_secs(DEFAULT_GRACE_PERIOD),
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_file: None,
+            metrics_address: None,
+            audit_file: None,
+            audit_max_size: DEFAULT_AUDIT_MAX_SIZE,
+            audit_keep: DEFAULT_AUDIT_KEEP,
+            config_file: None,
         }
     }
 }
@@ -45,6 +131,16 @@ pub struct ServerFile {
     pub fullpath: String,
     pub name: String,
     pub size: u64,
+    pub is_dir: bool,
+    // Seconds since the Unix epoch.
+    pub mtime: i64,
+    // Permission bits as in st_mode, without the file type.
+    pub mode: u32,
+    pub digest: Option<String>,
+    // Bytes kept from an interrupted upload of the file.
+    pub partial_size: u64,
+    // Digest of the first bytes of the file, when stat was asked for it.
+    pub prefix_digest: Option<String>,
 }
 
 impl ServerFile {
@@ -53,6 +149,12 @@ impl ServerFile {
             fullpath: String::from(""),
             name: String::from(""),
             size: 0,
+            is_dir: false,
+            mtime: 0,
+            mode: 0,
+            digest: None,
+            partial_size: 0,
+            prefix_digest: None,
         }
     }
 }
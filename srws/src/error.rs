@@ -1,4 +1,11 @@
+use crate::pb;
+use crate::pb::ErrorKind;
+
+use prost::Message;
+use std::error::Error;
 use std::fmt;
+use std::io;
+use tonic::{Code, Status};
 
 pub struct SrwscError {
     code: ErrorCode,
@@ -11,9 +18,22 @@ impl fmt::Display for SrwscError {
             ErrorCode::ErrorAck => "Failed to get ACK message",
             ErrorCode::ErrorRequest => "Failure to request",
             ErrorCode::NotExistFile => "Not exist file",
+            ErrorCode::ErrorTls => "Failed to set up TLS",
+            ErrorCode::InvalidFileName => "Invalid file name",
+            ErrorCode::OutOfRange => "Requested range is out of the file",
+            ErrorCode::ChecksumMismatch => "Checksum does not match",
+            ErrorCode::InvalidArgument => "Invalid argument",
+            ErrorCode::ErrorAuth => "Failed to authenticate",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::PermissionDenied => "Permission denied",
+            ErrorCode::ErrorIo => "I/O error",
+            ErrorCode::ErrorProtocol => "Protocol error",
         };
 
-        write!(f, "{}", err_msg)
+        match self.message.is_empty() {
+            true => write!(f, "{}", err_msg),
+            false => write!(f, "{}: {}", err_msg, self.message),
+        }
     }
 }
 
@@ -27,6 +47,8 @@ impl fmt::Debug for SrwscError {
     }
 }
 
+impl Error for SrwscError {}
+
 impl SrwscError {
     pub fn new(code: ErrorCode, message: String) -> Self {
         SrwscError {
@@ -34,12 +56,162 @@ impl SrwscError {
             message: message,
         }
     }
+
+    pub fn code(&self) -> &ErrorCode {
+        &self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<io::Error> for SrwscError {
+    fn from(e: io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => ErrorCode::NotExistFile,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidArgument,
+            _ => ErrorCode::ErrorIo,
+        };
+        SrwscError::new(code, e.to_string())
+    }
+}
+
+// The status a gRPC call fails with. The code tells the errors apart, so the
+// message is passed on without the description of the code. Codes that share
+// a status are told apart by the pb::Error in its details, the frame the TCP
+// protocol sends for the error.
+impl From<SrwscError> for Status {
+    fn from(e: SrwscError) -> Self {
+        let code = match e.code {
+            ErrorCode::NotExistFile => Code::NotFound,
+            ErrorCode::PermissionDenied => Code::PermissionDenied,
+            ErrorCode::ErrorTls |
+            ErrorCode::ErrorAuth => Code::Unauthenticated,
+            ErrorCode::QuotaExceeded => Code::ResourceExhausted,
+            ErrorCode::InvalidFileName |
+            ErrorCode::InvalidArgument => Code::InvalidArgument,
+            ErrorCode::OutOfRange => Code::OutOfRange,
+            ErrorCode::ChecksumMismatch => Code::DataLoss,
+            ErrorCode::ErrorAck |
+            ErrorCode::ErrorProtocol => Code::Aborted,
+            ErrorCode::ErrorRequest => Code::Unknown,
+            ErrorCode::ErrorIo => Code::Internal,
+        };
+        let error = pb::Error {
+            kind: e.code.kind() as i32,
+            message: e.message.clone(),
+        };
+        let mut details = Vec::new();
+        match error.encode(&mut details) {
+            Ok(_) => Status::with_details(code, e.message, details.into()),
+            Err(_) => Status::new(code, e.message),
+        }
+    }
 }
 
-#[allow(dead_code)]
+// Every error falls into one of these; srwc has the same codes, and they
+// travel between the two as pb::ErrorKind in TCP error frames and as the
+// status code over gRPC.
 #[derive(Debug)]
 pub enum ErrorCode {
+    // Protocol
     ErrorAck,
+    ErrorProtocol,
     ErrorRequest,
+    // Not found
     NotExistFile,
+    // Validation
+    InvalidFileName,
+    InvalidArgument,
+    OutOfRange,
+    ChecksumMismatch,
+    // Permission
+    ErrorTls,
+    ErrorAuth,
+    PermissionDenied,
+    // Quota
+    QuotaExceeded,
+    // I/O
+    ErrorIo,
+}
+
+impl ErrorCode {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ErrorCode::ErrorAck |
+            ErrorCode::ErrorProtocol => ErrorKind::Protocol,
+            ErrorCode::ErrorRequest => ErrorKind::Unknown,
+            ErrorCode::NotExistFile => ErrorKind::NotFound,
+            ErrorCode::InvalidFileName => ErrorKind::InvalidFileName,
+            ErrorCode::InvalidArgument => ErrorKind::InvalidArgument,
+            ErrorCode::OutOfRange => ErrorKind::OutOfRange,
+            ErrorCode::ChecksumMismatch => ErrorKind::ChecksumMismatch,
+            ErrorCode::ErrorTls |
+            ErrorCode::ErrorAuth => ErrorKind::Unauthenticated,
+            ErrorCode::PermissionDenied => ErrorKind::PermissionDenied,
+            ErrorCode::QuotaExceeded => ErrorKind::QuotaExceeded,
+            ErrorCode::ErrorIo => ErrorKind::Io,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes() -> Vec<ErrorCode> {
+        vec![ErrorCode::ErrorAck, ErrorCode::ErrorProtocol, ErrorCode::ErrorRequest,
+             ErrorCode::NotExistFile, ErrorCode::InvalidFileName, ErrorCode::InvalidArgument,
+             ErrorCode::OutOfRange, ErrorCode::ChecksumMismatch, ErrorCode::ErrorTls,
+             ErrorCode::ErrorAuth, ErrorCode::PermissionDenied, ErrorCode::QuotaExceeded,
+             ErrorCode::ErrorIo]
+    }
+
+    #[test]
+    fn statuses_carry_the_kind_of_the_tcp_error_frame() {
+        for code in codes() {
+            let kind = code.kind();
+            let status = Status::from(SrwscError::new(code, String::from("why")));
+            // tonic keeps the details as they go on the wire, in base64.
+            let details = base64::decode_config(status.details(), base64::STANDARD_NO_PAD);
+            let error = pb::Error::decode(&details.unwrap()[..]).unwrap();
+            assert_eq!(error.kind, kind as i32);
+            assert_eq!(error.message, "why");
+            assert_eq!(status.message(), "why");
+        }
+    }
+
+    #[test]
+    fn statuses_have_the_closest_grpc_code() {
+        let status_code = |code| Status::from(SrwscError::new(code, String::new())).code();
+        assert_eq!(status_code(ErrorCode::NotExistFile), Code::NotFound);
+        assert_eq!(status_code(ErrorCode::PermissionDenied), Code::PermissionDenied);
+        assert_eq!(status_code(ErrorCode::ErrorAuth), Code::Unauthenticated);
+        assert_eq!(status_code(ErrorCode::QuotaExceeded), Code::ResourceExhausted);
+        assert_eq!(status_code(ErrorCode::InvalidFileName), Code::InvalidArgument);
+        assert_eq!(status_code(ErrorCode::InvalidArgument), Code::InvalidArgument);
+        assert_eq!(status_code(ErrorCode::OutOfRange), Code::OutOfRange);
+        assert_eq!(status_code(ErrorCode::ChecksumMismatch), Code::DataLoss);
+        assert_eq!(status_code(ErrorCode::ErrorProtocol), Code::Aborted);
+        assert_eq!(status_code(ErrorCode::ErrorRequest), Code::Unknown);
+        assert_eq!(status_code(ErrorCode::ErrorIo), Code::Internal);
+    }
+
+    #[test]
+    fn tls_errors_are_reported_as_authentication_errors() {
+        assert_eq!(ErrorCode::ErrorTls.kind(), ErrorCode::ErrorAuth.kind());
+        assert_eq!(Status::from(SrwscError::new(ErrorCode::ErrorTls, String::new())).code(),
+                   Status::from(SrwscError::new(ErrorCode::ErrorAuth, String::new())).code());
+    }
+
+    #[test]
+    fn io_errors_keep_their_meaning() {
+        let code = |kind| SrwscError::from(io::Error::new(kind, "e")).code().kind();
+        assert_eq!(code(io::ErrorKind::NotFound), ErrorKind::NotFound);
+        assert_eq!(code(io::ErrorKind::PermissionDenied), ErrorKind::PermissionDenied);
+        assert_eq!(code(io::ErrorKind::InvalidInput), ErrorKind::InvalidArgument);
+        assert_eq!(code(io::ErrorKind::UnexpectedEof), ErrorKind::Io);
+    }
 }
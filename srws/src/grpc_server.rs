@@ -1,161 +1,878 @@
-mod pb {
-    tonic::include_proto!("srwsc.pb");
-}
-
+use crate::audit::{Audit, Record};
+use crate::auth::Credentials;
+use crate::checksum::Checksum;
+use crate::compression;
+use crate::compression::{Compression, Decoder, Encoder, Output};
 use crate::config;
+use crate::error::{SrwscError, ErrorCode};
+use crate::listing;
+use crate::logging;
+use crate::metrics::{ActiveSession, Metrics, Protocol};
 use crate::misc;
+use crate::misc::blocking;
+use crate::pb;
+use crate::permission::{Operation, Role};
+use crate::shutdown;
+use crate::space::{Space, Spaces};
+use crate::storage;
+use crate::storage::Storage;
+use crate::tls;
 
 use tonic::{transport::Server, Request, Response, Status, Streaming};
+use tonic::metadata::{MetadataMap, MetadataValue};
 use pb::srwsc_server::{Srwsc, SrwscServer};
-use pb::{SrwscRequest, SrwscResponse, FileStream};
+use pb::{SrwscRequest, SrwscResponse, FileStream, ListRequest, FileInfo, FileListResponse};
+use pb::{Empty, QuotaInfo};
 
-use console::style;
-use std::fs;
-use std::fs::File;
+use std::collections::HashMap;
+use std::cmp;
+use std::io;
 use std::io::{BufWriter, Write};
 use std::io::prelude::*;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use futures::StreamExt;
-use tokio::sync::mpsc;
+use futures::executor::block_on;
+use futures::pin_mut;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio::time;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-static mut STORAGE: &str = config::DEFAULT_STORAGE;
-
-fn send_file(filename: &str)
-    -> Vec<FileStream> {
-    let f: config::ServerFile;
-    unsafe {
-        f = misc::check_file(filename, STORAGE);
+// Everything that can go wrong before the first chunk is checked here, so that
+// it is reported as the status of the call instead of inside the stream.
+fn open_file(filename: &str,
+             offset: u64,
+             length: Option<u64>,
+             storage: &dyn Storage)
+    -> Result<(Box<dyn Read + Send>, Checksum, u64), Status> {
+    // A resumed download is verified as a whole, so it is read from the start
+    // to hash the part the client already has.
+    let start = match length {
+        Some(_) => offset,
+        None => 0,
+    };
+    let opened = storage.stat(filename)
+        .and_then(|f| storage.open(filename, start).map(|file| (f, file)));
+    let (f, mut file) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            warn!("Cannot open: {}", e);
+            return Err(SrwscError::from(e).into());
+        },
+    };
+    if !misc::in_range(offset, length, f.size) {
+        warn!(offset, length = ?length, size = f.size, "Range is beyond the file");
+        return Err(SrwscError::new(ErrorCode::OutOfRange,
+                                   format!("{} is {} bytes", filename, f.size)).into());
+    }
+    let mut checksum = Checksum::new();
+    if let Err(e) = checksum.update_from(&mut file, offset - start) {
+        warn!("Cannot read: {}", e);
+        return Err(SrwscError::from(e).into());
     }
+    Ok((file, checksum, length.unwrap_or(f.size - offset)))
+}
 
-    let mut msg: Vec<FileStream> = Vec::new();
-    let mut buf = [0u8; config::BUFFER_SIZE];
-    let mut remaining_data = f.size;
-    let mut file = File::open(f.fullpath).unwrap();
-    while remaining_data != 0 {
-        match file.read(&mut buf) {
-            Ok(n) => {
+// Runs on a blocking thread. A chunk is only read once the channel has room
+// for the previous one, so a slow client holds back the reads rather than
+// filling memory. The download is audited once it has ended.
+fn send_file(mut file: Box<dyn Read + Send>,
+             mut checksum: Checksum,
+             length: u64,
+             chunk_size: usize,
+             compression: Compression,
+             metrics: Arc<Metrics>,
+             audit: Arc<Audit>,
+             mut record: Record,
+             tx: mpsc::Sender<Result<FileStream, Status>>) {
+    let started = Instant::now();
+    let mut errors = tx.clone();
+    let sent = Encoder::new(compression, MessageWriter::new(tx, chunk_size))
+        .and_then(|mut encoder| {
+            let mut buf = vec![0u8; chunk_size];
+            let mut remaining_data = length;
+            while remaining_data != 0 {
+                let len = cmp::min(remaining_data, chunk_size as u64) as usize;
+                let n = file.read(&mut buf[..len])?;
                 if n == 0 {
-                    println!("Read file is 0");
-                    break;
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              format!("file ended {} bytes early",
+                                                      remaining_data)));
                 }
-                msg.push(FileStream{data: buf[..n].to_vec()});
-                remaining_data = remaining_data - n as u64;
-            },
-            _ => break,
+                checksum.update(&buf[..n]);
+                encoder.write_all(&buf[..n])?;
+                remaining_data -= n as u64;
+            }
+            let mut writer = encoder.finish()?;
+            writer.flush()?;
+            Ok(writer)
+        });
+    match sent {
+        Ok(mut writer) => {
+            let duration = started.elapsed();
+            info!(bytes = length, wire_bytes = writer.sent, compression = compression.name(),
+                  duration = ?duration, "Sent");
+            metrics.sent(Protocol::Grpc, writer.sent);
+            metrics.transfer(Protocol::Grpc, Operation::Get, duration);
+            let digest = checksum.finish();
+            record.size = Some(length);
+            record.digest = Some(digest.clone());
+            let sent = block_on(writer.tx.send(Ok(FileStream{data: Vec::new(), digest: digest})));
+            audit.write(record, sent.err().map(|_| String::from("client went away")));
+        },
+        Err(e) => {
+            warn!("Transfer cut off: {}", e);
+            let status = match e.kind() {
+                io::ErrorKind::UnexpectedEof => Status::data_loss(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            };
+            audit.write(record, Some(status_error(&status)));
+            let _ = block_on(errors.send(Err(status)));
+        },
+    }
+}
+
+// Cuts the data, compressed or not, into messages of chunk_size bytes. A send
+// blocks while the channel is full; it fails once the client has gone away.
+struct MessageWriter {
+    tx: mpsc::Sender<Result<FileStream, Status>>,
+    buf: Vec<u8>,
+    chunk_size: usize,
+    sent: u64,
+}
+
+impl MessageWriter {
+    fn new(tx: mpsc::Sender<Result<FileStream, Status>>, chunk_size: usize) -> Self {
+        MessageWriter {
+            tx: tx,
+            buf: Vec::with_capacity(chunk_size),
+            chunk_size: chunk_size,
+            sent: 0,
         }
     }
-    msg
+
+    fn send(&mut self, len: usize) -> io::Result<()> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        block_on(self.tx.send(Ok(FileStream{data: data, digest: String::new()})))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+        self.sent += len as u64;
+        Ok(())
+    }
 }
 
+impl Write for MessageWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= self.chunk_size {
+            self.send(self.chunk_size)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send(self.buf.len())?;
+        }
+        Ok(())
+    }
+}
+
+// `length`, when the client announced it, is the most that is accepted.
+// Returns the bytes written and their digest.
 async fn receive_file(filename: &str,
+                      offset: u64,
+                      length: Option<u64>,
+                      compression: Compression,
+                      role: Role,
+                      space: &Arc<Space>,
+                      metrics: &Metrics,
                       mut stream: Streaming<FileStream>)
-    -> String {
-    let mut fullname = String::new();
-    unsafe  {
-        fullname.push_str(STORAGE);
-    }
-    fullname.push_str("/");
-    fullname.push_str(&filename);
-    let mut file_buffer = BufWriter::new(
-        File::create(fullname).unwrap());
-
-    while let Some(msg) = stream.next().await {
-        let msg = msg
-            .unwrap();
-        let _ = file_buffer
-            .write(&msg.data)
-            .unwrap();
-    }
-
-    String::from("Ok")
-}
-
-fn remove_file(filename: &str)
-    -> String {
-    let f: config::ServerFile;
-    unsafe {
-        f = misc::check_file(filename, STORAGE);
-    }
-    if f.size > 0 {
-        println!("[remove_file] File found");
-        match fs::remove_file(&f.fullpath) {
-            Ok(_) => {
-                println!("[remove_file] {} is removed", f.name);
-                config::REMOVED_OK_MESSAGE.to_string()
-            },
-            Err(e) => {
-                println!("[remove_file] An error occured: {:?}", e);
-                config::REMOVED_NOK_MESSAGE.to_string()
-            },
+    -> Result<(u64, String), Status> {
+    let created = {
+        let space = space.clone();
+        let name = filename.to_string();
+        blocking(move || space.storage().create(&name, offset)).await
+    };
+    let upload = match created {
+        Ok(upload) => upload,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+            warn!("Cannot resume: {}", e);
+            return Err(SrwscError::new(ErrorCode::OutOfRange, e.to_string()).into());
+        },
+        Err(e) => {
+            warn!("Cannot create: {}", e);
+            return Err(SrwscError::from(e).into());
+        },
+    };
+    let (upload, prefix) = blocking(move || {
+        let mut checksum = Checksum::new();
+        let prefix = match offset {
+            0 => Ok(()),
+            _ => upload.read_back()
+                .and_then(|mut prefix| checksum.update_from(&mut prefix, offset)),
+        };
+        (upload, prefix.map(|_| checksum))
+    }).await;
+    let mut checksum = prefix.map_err(SrwscError::from)?;
+
+    // The messages are written on a blocking thread as they come in.
+    // Returning early keeps what was received, so that the upload can be
+    // resumed; only a complete and verified stream is committed.
+    let started = Instant::now();
+    let (mut data_tx, mut data_rx) = mpsc::channel::<Vec<u8>>(1);
+    let writing = blocking(move || {
+        let mut file_buffer = BufWriter::new(upload);
+        let output = Output::new(&mut file_buffer, &mut checksum, length.unwrap_or(u64::MAX));
+        let written = Decoder::new(compression, output)
+            .map_err(|e| Status::internal(e.to_string()))
+            .and_then(|mut decoder| {
+                while let Some(data) = block_on(data_rx.recv()) {
+                    decoder.write_all(&data)
+                        .map_err(|e| Status::data_loss(e.to_string()))?;
+                }
+                decoder.finish()
+                    .map(|output| output.written())
+                    .map_err(|e| Status::data_loss(e.to_string()))
+            });
+        (file_buffer.into_inner().map_err(io::Error::from), checksum, written)
+    });
+    let reading = async move {
+        let mut digest = String::new();
+        let mut received = 0;
+        while let Some(msg) = stream.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(status) => {
+                    warn!("Stream broken, keeping the upload to resume: {}", status.message());
+                    return Err(status);
+                },
+            };
+            received += msg.data.len() as u64;
+            if !msg.digest.is_empty() {
+                digest = msg.digest;
+            }
+            // The writer only stops early on an error, which it reports.
+            if data_tx.send(msg.data).await.is_err() {
+                break;
+            }
+        }
+        Ok((received, digest))
+    };
+    let ((upload, checksum, written), read) = tokio::join!(writing, reading);
+    let (received, digest) = read?;
+    let written = written?;
+    let duration = started.elapsed();
+    info!(bytes = written, wire_bytes = received, compression = compression.name(),
+          duration = ?duration, "Received");
+    metrics.received(Protocol::Grpc, received);
+    metrics.transfer(Protocol::Grpc, Operation::Put, duration);
+    if let Some(length) = length.filter(|&length| length != written) {
+        warn!(length, "Data ended early, keeping the upload to resume");
+        return Err(Status::data_loss(format!("data ended after {} of {} bytes",
+                                             written, length)));
+    }
+
+    if digest != checksum.finish() {
+        warn!(digest = %digest, "Checksum mismatch, discarding the upload");
+        if let Ok(upload) = upload {
+            blocking(move || upload.discard()).await;
+        }
+        return Err(SrwscError::new(ErrorCode::ChecksumMismatch,
+                                   format!("{} does not match the data received", digest))
+                   .into());
+    }
+    let replace = role.may_replace();
+    match blocking(move || upload.and_then(|upload| upload.commit(replace))).await {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && !replace => {
+            let e = role.check_replace(filename).unwrap_err();
+            warn!("Denied, the file was created meanwhile: {}", e.message());
+            return Err(e.into());
+        },
+        Err(e) => return Err(SrwscError::from(e).into()),
+    }
+    let stored = {
+        let space = space.clone();
+        let name = filename.to_string();
+        let digest = digest.clone();
+        blocking(move || space.storage().set_digest(&name, &digest)).await
+    };
+    if let Err(e) = stored {
+        warn!("Cannot store the digest: {}", e);
+    }
+
+    Ok((written, digest))
+}
+
+fn remove_file(filename: &str,
+               storage: &dyn Storage)
+    -> Result<String, Status> {
+    match storage.remove(filename) {
+        Ok(_) => {
+            info!("Removed");
+            Ok(config::REMOVED_OK_MESSAGE.to_string())
+        },
+        Err(e) => {
+            warn!("Cannot remove: {}", e);
+            Err(SrwscError::from(e).into())
+        },
+    }
+}
+
+fn make_dir(dirname: &str,
+            storage: &dyn Storage)
+    -> Result<String, Status> {
+    match storage.mkdir(dirname) {
+        Ok(_) => {
+            info!("Created");
+            Ok(config::MKDIR_OK_MESSAGE.to_string())
+        },
+        Err(e) => {
+            warn!("Cannot create: {}", e);
+            Err(SrwscError::from(e).into())
+        },
+    }
+}
+
+fn remove_dir(dirname: &str,
+              storage: &dyn Storage)
+    -> Result<String, Status> {
+    match storage.rmdir(dirname) {
+        Ok(_) => {
+            info!("Removed");
+            Ok(config::REMOVED_OK_MESSAGE.to_string())
+        },
+        Err(e) => {
+            warn!("Cannot remove: {}", e);
+            Err(SrwscError::from(e).into())
+        },
+    }
+}
+
+fn stat_file(filename: &str,
+             prefix: u64,
+             role: Role,
+             storage: &dyn Storage)
+    -> Result<FileInfo, Status> {
+    match storage::stat_for_resume(storage, filename, prefix) {
+        Ok(file) => Ok(misc::stat_info(&file, role)),
+        Err(e) => {
+            warn!("Cannot stat: {}", e);
+            Err(SrwscError::from(e).into())
+        },
+    }
+}
+
+// How a failed call is put in the audit log.
+fn status_error(status: &Status) -> String {
+    format!("{:?}: {}", status.code(), status.message())
+}
+
+fn list_status(e: SrwscError) -> Status {
+    warn!("Cannot list: {}", e);
+    e.into()
+}
+
+fn check_filename(filename: &str)
+    -> Result<(), Status> {
+    storage::validate_name(filename).map_err(|e| {
+        warn!("Rejected file name: {}", e);
+        Status::from(e)
+    })
+}
+
+// A call as it is logged, counted and audited.
+struct Call {
+    span: Span,
+    operation: Operation,
+    record: Mutex<Record>,
+    // Counts the call as an active session for as long as it is held; a
+    // download takes it along to the task that sends the data.
+    active: Mutex<Option<ActiveSession>>,
+}
+
+impl Call {
+    // The file the call transferred or removed.
+    fn file(&self, size: u64, digest: Option<String>) {
+        let mut record = self.record.lock().unwrap();
+        record.size = Some(size);
+        record.digest = digest;
+    }
+}
+
+pub struct ServerImpl {
+    spaces: Arc<Spaces>,
+    metrics: Arc<Metrics>,
+    audit: Arc<Audit>,
+    identity_map: HashMap<String, String>,
+    chunk_size: usize,
+}
+
+impl ServerImpl {
+    pub fn new(spaces: Arc<Spaces>, metrics: Arc<Metrics>, audit: Arc<Audit>) -> Self {
+        ServerImpl {
+            spaces: spaces,
+            metrics: metrics,
+            audit: audit,
+            identity_map: HashMap::new(),
+            chunk_size: config::DEFAULT_CHUNK_SIZE,
         }
-    } else {
-        println!("[remove_file] File is not found");
-        config::CANNOT_FIND_FILE_MESSAGE.to_string()
+    }
+
+    fn peer_identity<T>(&self, request: &Request<T>)
+        -> Option<String> {
+        let certs = request.peer_certs()?;
+        let cert = certs.iter().next()?;
+        tls::peer_identity(cert.get_ref(), &self.identity_map)
+    }
+
+    // The user the call was authenticated as, or else the identity of the
+    // client certificate.
+    fn user<T>(&self, request: &Request<T>)
+        -> Option<String> {
+        request.metadata()
+            .get(config::GRPC_METADATA_USER)
+            .and_then(|value| value.to_str().ok())
+            .map(|user| user.to_string())
+            .or_else(|| self.peer_identity(request))
+    }
+
+    // Every call is a session of its own, with the command in it.
+    fn call<T>(&self, request: &Request<T>, operation: Operation, filename: &str)
+        -> Call {
+        let user = self.user(request);
+        let session = info_span!("session",
+                                 id = logging::next_session_id(),
+                                 peer = ?request.remote_addr(),
+                                 user = ?user);
+        Call {
+            span: info_span!(parent: &session, "command", command = %operation, filename),
+            operation: operation,
+            record: Mutex::new(Record::new(Protocol::Grpc, operation, filename, user.as_deref(),
+                                           request.remote_addr())),
+            active: Mutex::new(Some(self.metrics.session(Protocol::Grpc))),
+        }
+    }
+
+    // Runs a call in its span, logs how it ended, counts it in the metrics
+    // and audits it. A download that has started is audited by send_file.
+    async fn traced<T, F>(&self, call: &Call, future: F)
+        -> Result<T, Status>
+        where F: Future<Output = Result<T, Status>> {
+        let started = Instant::now();
+        let result = future.instrument(call.span.clone()).await;
+        match &result {
+            Ok(_) => info!(parent: &call.span, duration = ?started.elapsed(), "Done"),
+            Err(status) => warn!(parent: &call.span, code = ?status.code(),
+                                 duration = ?started.elapsed(),
+                                 "Failed: {}", status.message()),
+        }
+        self.metrics.operation(Protocol::Grpc, call.operation, result.is_ok());
+        if result.is_err() || call.operation != Operation::Get {
+            let record = call.record.lock().unwrap().clone();
+            let error = result.as_ref().err().map(status_error);
+            let audit = self.audit.clone();
+            blocking(move || audit.write(record, error)).await;
+        }
+        result
+    }
+
+    // The storage of the user the call was authenticated as, the one of
+    // everybody without a credential file, once the user's role allows the
+    // operation.
+    async fn space(&self, metadata: &MetadataMap, operation: Operation)
+        -> Result<Arc<Space>, Status> {
+        if let Err(e) = role(metadata).check(operation) {
+            warn!("Denied: {}", e.message());
+            return Err(e.into());
+        }
+        let space = metadata
+            .get(config::GRPC_METADATA_SPACE)
+            .and_then(|value| value.to_str().ok())
+            .map(|space| space.to_string());
+        let spaces = self.spaces.clone();
+        let name = space.clone();
+        blocking(move || spaces.get(name.as_deref())).await.map_err(|e| {
+            error!(space = ?space, "Cannot open the storage: {}", e);
+            Status::from(SrwscError::from(e))
+        })
     }
 }
 
-#[derive(Default)]
-pub struct ServerImpl {}
+// The role authenticate gave the call.
+fn role(metadata: &MetadataMap) -> Role {
+    metadata
+        .get(config::GRPC_METADATA_ROLE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|role| role.parse::<Role>().ok())
+        .unwrap_or_default()
+}
+
+// Runs before every call. Whatever the client sent as the user, the role and
+// the space is dropped and, with a credential file, replaced by the user of
+// its "authorization" metadata and the user's role and space. Without one,
+// every call is an admin's.
+fn authenticate(credentials: Option<&Credentials>,
+                mut request: Request<()>)
+    -> Result<Request<()>, Status> {
+    request.metadata_mut().remove(config::GRPC_METADATA_USER);
+    request.metadata_mut().remove(config::GRPC_METADATA_ROLE);
+    request.metadata_mut().remove(config::GRPC_METADATA_SPACE);
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            let role = MetadataValue::from_str(&Role::Admin.to_string())
+                .map_err(|_| Status::internal("invalid role"))?;
+            request.metadata_mut().insert(config::GRPC_METADATA_ROLE, role);
+            return Ok(request);
+        },
+    };
+    let user = request.metadata()
+        .get(config::GRPC_METADATA_AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|authorization| credentials.authorize(authorization));
+    match user {
+        Some(user) => {
+            let value = MetadataValue::from_str(&user)
+                .map_err(|_| Status::internal("invalid user name"))?;
+            request.metadata_mut().insert(config::GRPC_METADATA_USER, value);
+            let role = MetadataValue::from_str(&credentials.role(&user).to_string())
+                .map_err(|_| Status::internal("invalid role"))?;
+            request.metadata_mut().insert(config::GRPC_METADATA_ROLE, role);
+            let space = MetadataValue::from_str(&credentials.space(&user))
+                .map_err(|_| Status::internal("invalid space name"))?;
+            request.metadata_mut().insert(config::GRPC_METADATA_SPACE, space);
+            Ok(request)
+        },
+        None => {
+            warn!(peer = ?request.remote_addr(), "Rejected a call without valid credentials");
+            Err(Status::unauthenticated(config::LOGIN_REQUIRED_MESSAGE))
+        },
+    }
+}
 
 #[tonic::async_trait]
 impl Srwsc for ServerImpl {
     type GetStream = mpsc::Receiver<Result<FileStream, Status>>;
+    type ListStream = mpsc::Receiver<Result<FileListResponse, Status>>;
 
     async fn get(&self, request: Request<SrwscRequest>)
         -> Result<Response<Self::GetStream>, Status> {
         let filename = &request.get_ref().filename;
-        let file_streams = send_file(filename);
-        let (mut tx, rx) = mpsc::channel(8);
-        tokio::spawn(async move {
-            for file_stream in &file_streams[..] {
-                tx.send(Ok(file_stream.clone())).await.unwrap();
+        let call = self.call(&request, Operation::Get, filename);
+        self.traced(&call, async {
+            let offset = request.get_ref().offset;
+            let length = match request.get_ref().length {
+                0 => None,
+                length => Some(length),
+            };
+            debug!(offset, length = ?length, "Requested");
+            check_filename(filename)?;
+            let space = self.space(request.metadata(), Operation::Get).await?;
+            let name = filename.clone();
+            let (file, checksum, length) = blocking(move || {
+                open_file(&name, offset, length, space.storage())
+            }).await?;
+            let compression = request.metadata()
+                .get(config::GRPC_METADATA_ACCEPT_COMPRESSION)
+                .and_then(|value| value.to_str().ok())
+                .map(compression::negotiate);
+            let chunk_size = self.chunk_size;
+            let metrics = self.metrics.clone();
+            let audit = self.audit.clone();
+            let record = call.record.lock().unwrap().clone();
+            let active = call.active.lock().unwrap().take();
+            let (tx, rx) = mpsc::channel(8);
+            // The data is sent after the call has returned, still in its span
+            // and as an active session.
+            let span = Span::current();
+            task::spawn_blocking(move || {
+                let _enter = span.enter();
+                let _active = active;
+                send_file(file, checksum, length, chunk_size,
+                          compression.unwrap_or(Compression::None), metrics, audit, record, tx);
+            });
+            let mut response = Response::new(rx);
+            if let Some(compression) = compression {
+                response.metadata_mut().insert(config::GRPC_METADATA_COMPRESSION,
+                                               MetadataValue::from_static(compression.name()));
             }
-
-            println!("Done sending");
-        });
-        Ok(Response::new(rx))
+            Ok(response)
+        }).await
     }
 
     async fn put(&self, stream: Request<Streaming<FileStream>>)
         -> Result<Response<SrwscResponse>, Status> {
         let filename = stream.metadata()
-                                               .get(config::GRPC_METADATA_FILENAME)
-                                               .cloned()
-                                               .unwrap();
-        Ok(Response::new(SrwscResponse{
-            message: receive_file(filename.to_str().unwrap(),
-                                  stream.into_inner()).await,
-        }))
+            .get(config::GRPC_METADATA_FILENAME)
+            .and_then(|value| value.to_str().ok())
+            .map(|filename| filename.to_string());
+        let call = self.call(&stream, Operation::Put, filename.as_deref().unwrap_or_default());
+        self.traced(&call, async {
+            let filename = match filename {
+                Some(filename) => filename,
+                None => return Err(Status::invalid_argument(
+                    format!("missing \"{}\" metadata", config::GRPC_METADATA_FILENAME))),
+            };
+            let offset = match stream.metadata().get(config::GRPC_METADATA_OFFSET) {
+                Some(value) => match value.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+                    Some(offset) => offset,
+                    None => return Err(Status::invalid_argument(
+                        format!("invalid \"{}\" metadata", config::GRPC_METADATA_OFFSET))),
+                },
+                None => 0,
+            };
+            // The client picks the compression of an upload; there is no
+            // answer to negotiate with before the stream starts.
+            let compression = match stream.metadata().get(config::GRPC_METADATA_COMPRESSION) {
+                Some(value) => match value.to_str().ok().and_then(Compression::from_name) {
+                    Some(compression) => compression,
+                    None => return Err(Status::invalid_argument(
+                        format!("unsupported \"{}\" metadata",
+                                config::GRPC_METADATA_COMPRESSION))),
+                },
+                None => Compression::None,
+            };
+            let length = match stream.metadata().get(config::GRPC_METADATA_LENGTH) {
+                Some(value) => match value.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+                    Some(length) => Some(length),
+                    None => return Err(Status::invalid_argument(
+                        format!("invalid \"{}\" metadata", config::GRPC_METADATA_LENGTH))),
+                },
+                None => None,
+            };
+            debug!(offset, length = ?length, "Requested");
+            check_filename(&filename)?;
+            let space = self.space(stream.metadata(), Operation::Put).await?;
+            let role = role(stream.metadata());
+            let exists = {
+                let space = space.clone();
+                let name = filename.clone();
+                blocking(move || space.storage().stat(&name).is_ok()).await
+            };
+            if exists {
+                role.check_replace(&filename).map_err(|e| {
+                    warn!("Denied: {}", e.message());
+                    Status::from(e)
+                })?;
+            }
+            // The quota is checked against the announced length before any
+            // data is read, and the upload may not grow beyond it.
+            if length.is_none() && space.has_quota() {
+                return Err(Status::invalid_argument(
+                    format!("missing \"{}\" metadata", config::GRPC_METADATA_LENGTH)));
+            }
+            let reserved = match misc::upload_end(offset, length.unwrap_or(0)) {
+                Ok(end) => {
+                    let space = space.clone();
+                    let name = filename.clone();
+                    blocking(move || space.reserve(&name, end)).await
+                },
+                Err(e) => Err(e),
+            };
+            let _reservation = reserved
+                .map_err(|e| {
+                    warn!("Rejected: {}", e);
+                    Status::from(e)
+                })?;
+            let (written, digest) = receive_file(&filename,
+                                                 offset,
+                                                 length,
+                                                 compression,
+                                                 role,
+                                                 &space,
+                                                 &self.metrics,
+                                                 stream.into_inner()).await?;
+            call.file(written, Some(digest));
+            Ok(Response::new(SrwscResponse{
+                message: String::from("Ok"),
+            }))
+        }).await
     }
 
     async fn remove(&self, request: Request<SrwscRequest>)
         -> Result<Response<SrwscResponse>, Status> {
         let filename = &request.get_ref().filename;
-        Ok(Response::new(SrwscResponse{
-            message: remove_file(filename),
-        }))
+        let call = self.call(&request, Operation::Remove, filename);
+        self.traced(&call, async {
+            check_filename(filename)?;
+            let space = self.space(request.metadata(), Operation::Remove).await?;
+            let name = filename.clone();
+            let (stat, removed) = blocking(move || {
+                (space.storage().stat(&name), remove_file(&name, space.storage()))
+            }).await;
+            if let Ok(f) = stat {
+                call.file(f.size, f.digest);
+            }
+            Ok(Response::new(SrwscResponse{
+                message: removed?,
+            }))
+        }).await
     }
 
-    async fn file_list(&self, _: Request<pb::Empty>)
-        -> Result<Response<SrwscResponse>,Status> {
-        unsafe {
-            Ok(Response::new(SrwscResponse {
-                message: misc::get_file_list(STORAGE),
+    async fn file_list(&self, request: Request<ListRequest>)
+        -> Result<Response<FileListResponse>,Status> {
+        self.traced(&self.call(&request, Operation::List, &request.get_ref().path), async {
+            let space = self.space(request.metadata(), Operation::List).await?;
+            let list_request = request.get_ref().clone();
+            let page = blocking(move || listing::list(space.storage(), &list_request)).await
+                .map_err(list_status)?;
+            Ok(Response::new(page))
+        }).await
+    }
+
+    async fn list(&self, request: Request<ListRequest>)
+        -> Result<Response<Self::ListStream>, Status> {
+        self.traced(&self.call(&request, Operation::List, &request.get_ref().path), async {
+            let space = self.space(request.metadata(), Operation::List).await?;
+            let list_request = request.get_ref().clone();
+            let page = blocking(move || listing::list(space.storage(), &list_request)).await
+                .map_err(list_status)?;
+
+            let (mut tx, rx) = mpsc::channel(4);
+            task::spawn(async move {
+                let next_page_token = page.next_page_token;
+                let mut files = page.files.into_iter().peekable();
+                loop {
+                    let batch: Vec<FileInfo> = files.by_ref()
+                        .take(config::LIST_BATCH_SIZE)
+                        .collect();
+                    let last = files.peek().is_none();
+                    let message = FileListResponse {
+                        files: batch,
+                        next_page_token: match last {
+                            true => next_page_token.clone(),
+                            false => String::new(),
+                        },
+                    };
+                    if tx.send(Ok(message)).await.is_err() || last {
+                        break;
+                    }
+                }
+            });
+            Ok(Response::new(rx))
+        }).await
+    }
+
+    async fn make_dir(&self, request: Request<SrwscRequest>)
+        -> Result<Response<SrwscResponse>, Status> {
+        let dirname = &request.get_ref().filename;
+        self.traced(&self.call(&request, Operation::MakeDir, dirname), async {
+            check_filename(dirname)?;
+            let space = self.space(request.metadata(), Operation::MakeDir).await?;
+            let name = dirname.clone();
+            Ok(Response::new(SrwscResponse{
+                message: blocking(move || make_dir(&name, space.storage())).await?,
             }))
-        }
+        }).await
+    }
+
+    async fn remove_dir(&self, request: Request<SrwscRequest>)
+        -> Result<Response<SrwscResponse>, Status> {
+        let dirname = &request.get_ref().filename;
+        self.traced(&self.call(&request, Operation::RemoveDir, dirname), async {
+            check_filename(dirname)?;
+            let space = self.space(request.metadata(), Operation::RemoveDir).await?;
+            let name = dirname.clone();
+            Ok(Response::new(SrwscResponse{
+                message: blocking(move || remove_dir(&name, space.storage())).await?,
+            }))
+        }).await
+    }
+
+    async fn stat(&self, request: Request<SrwscRequest>)
+        -> Result<Response<FileInfo>, Status> {
+        let filename = &request.get_ref().filename;
+        self.traced(&self.call(&request, Operation::Stat, filename), async {
+            check_filename(filename)?;
+            let space = self.space(request.metadata(), Operation::Stat).await?;
+            let name = filename.clone();
+            let prefix = request.get_ref().length;
+            let role = role(request.metadata());
+            Ok(Response::new(blocking(move || {
+                stat_file(&name, prefix, role, space.storage())
+            }).await?))
+        }).await
+    }
+
+    async fn quota(&self, request: Request<Empty>)
+        -> Result<Response<QuotaInfo>, Status> {
+        self.traced(&self.call(&request, Operation::Quota, ""), async {
+            let space = self.space(request.metadata(), Operation::Quota).await?;
+            match blocking(move || space.info()).await {
+                Ok(info) => Ok(Response::new(info)),
+                Err(e) => {
+                    error!("Cannot count usage: {}", e);
+                    Err(Status::from(SrwscError::from(e)))
+                },
+            }
+        }).await
     }
 }
 
 #[tokio::main]
-pub async fn run(c: config::ServerConfig)
+pub async fn run(c: config::ServerConfig,
+                 spaces: Arc<Spaces>,
+                 metrics: Arc<Metrics>,
+                 audit: Arc<Audit>)
     -> Result<(), Box<dyn std::error::Error>> {
-    let s = ServerImpl::default();
-    unsafe {
-        STORAGE = Box::leak(c.storage.into_boxed_str());
-    }
-    println!("Listening on address: {}", style(&c.address).green());
-    Server::builder()
-        .add_service(SrwscServer::new(s))
-        .serve(c.address)
-        .await?;
+    let mut s = ServerImpl::new(spaces, metrics, audit);
+    s.chunk_size = c.chunk_size;
+    if let Some(path) = &c.identity_map {
+        s.identity_map = tls::load_identity_map(path)
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    let credentials = match &c.credentials {
+        Some(path) => Some(Credentials::load(path)
+                           .map_err(|e| format!("{:?}", e))?),
+        None => {
+            info!("No credential file, calls do not need to authenticate");
+            None
+        },
+    };
+    if credentials.is_some() && c.cert.is_none() {
+        warn!("Passwords and tokens are sent in clear text without TLS");
+    }
+
+    let mut builder = Server::builder();
+    match (&c.cert, &c.key) {
+        (Some(cert), Some(key)) => {
+            let tls_config = tls::grpc_server_config(cert, key, &c.client_ca)
+                .map_err(|e| format!("{:?}", e))?;
+            builder = builder.tls_config(tls_config);
+            info!(client_certificate = c.client_ca.is_some(), "TLS is enabled");
+        },
+        (None, None) => {},
+        _ => return Err("gRPC TLS requires both -c [CERT] and -k [KEY]".into()),
+    }
+
+    info!(address = %c.address, "Listening");
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = builder
+        .add_service(SrwscServer::with_interceptor(s, move |request| {
+            authenticate(credentials.as_ref(), request)
+        }))
+        .serve_with_shutdown(c.address, async {
+            let _ = stop_rx.await;
+        });
+    pin_mut!(server);
+
+    tokio::select! {
+        served = &mut server => return Ok(served?),
+        _ = shutdown::requested() => {},
+    }
+
+    // No new connections are accepted from here on while the RPCs in flight
+    // get the grace period to finish. Returning tears down the runtime with
+    // whatever is left; an unfinished put keeps its partial upload to be
+    // resumed.
+    info!(grace_period = ?c.grace_period, "Shutting down, waiting for RPCs in flight");
+    let _ = stop_tx.send(());
+    match time::timeout(c.grace_period, server).await {
+        Ok(served) => served?,
+        Err(_) => warn!("Grace period is over, aborting RPCs in flight"),
+    }
     Ok(())
 }
@@ -1,330 +1,1393 @@
 extern crate encoding;
 
-use crate::config::{ServerConfig, BUFFER_SIZE};
+use crate::config::{ServerConfig, ServerType, MAX_MESSAGE_SIZE, MAX_LOGIN_ATTEMPTS, LOGIN_FAILURE_DELAY};
+use crate::config::SEND_TIMEOUT;
 use crate::config::{ACK_MESSAGE,
+                    NAK_MESSAGE,
                     PREPARE_TRANSFER_MESSAGE,
                     CANNOT_FIND_FILE_MESSAGE,
                     REMOVED_OK_MESSAGE,
-                    REMOVED_NOK_MESSAGE};
+                    MKDIR_OK_MESSAGE,
+                    CHECKSUM_OK_MESSAGE,
+                    FILE_INFO_MESSAGE,
+                    FILE_LIST_MESSAGE,
+                    LOGIN_OK_MESSAGE,
+                    LOGIN_FAILED_MESSAGE,
+                    LOGIN_REQUIRED_MESSAGE,
+                    QUOTA_INFO_MESSAGE,
+                    ERROR_MESSAGE};
+use crate::audit::{Audit, Record};
+use crate::auth::Credentials;
+use crate::checksum::Checksum;
+use crate::compression;
+use crate::compression::Compression;
 use crate::error::{SrwscError, ErrorCode};
+use crate::listing;
+use crate::logging;
+use crate::metrics::{Metrics, Protocol};
 use crate::misc;
+use crate::misc::blocking;
+use crate::pb::Error;
+use crate::permission::{Operation, Role};
+use crate::shutdown;
+use crate::space::{Space, Spaces};
+use crate::storage;
+use crate::tls;
+use crate::transfer;
+use crate::transfer::CHUNK_SIZE;
 
 use encoding::{Encoding, EncoderTrap};
 use encoding::all::ASCII;
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-use std::io::BufWriter;
+use prost::Message;
+use std::io;
 use std::io::prelude::*;
-use std::str;
 use std::fs::File;
-use std::fs;
-use console::style;
+use std::mem;
+use std::net;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::mem::ManuallyDrop;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::executor::block_on;
+use futures::pin_mut;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::time;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-pub fn run(c: ServerConfig) {
-    let listener = TcpListener::bind(&c.address.clone())
-        .unwrap();
-    println!("Listening on address: {}", style(&c.address).green());
-    let mut children = vec![];
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let storage = c.storage.clone();
-        let builder = thread::Builder::new();
-        children.push(builder.spawn(move || {
-            println!("New client {} connected",
-                     style(stream.peer_addr().unwrap().to_string()).green());
-            handle_event(storage, stream);
-        }).unwrap());
+#[tokio::main]
+pub async fn run(c: ServerConfig,
+                 spaces: Arc<Spaces>,
+                 metrics: Arc<Metrics>,
+                 audit: Arc<Audit>) {
+    let tls_acceptor = match c.server_type {
+        ServerType::HTTPS => {
+            let (cert, key) = match (&c.cert, &c.key) {
+                (Some(cert), Some(key)) => (cert, key),
+                _ => {
+                    error!("HTTPS server requires both -c [CERT] and -k [KEY]");
+                    return;
+                },
+            };
+            match tls::server_config(cert, key) {
+                Ok(config) => Some(TlsAcceptor::from(config)),
+                Err(e) => {
+                    error!("Unable to load TLS configuration: {}", e);
+                    return;
+                },
+            }
+        },
+        _ => None,
+    };
+    let credentials = match &c.credentials {
+        Some(path) => match Credentials::load(path) {
+            Ok(credentials) => Some(Arc::new(credentials)),
+            Err(e) => {
+                error!("Unable to load credentials: {}", e);
+                return;
+            },
+        },
+        None => {
+            info!("No credential file, sessions do not need to log in");
+            None
+        },
+    };
+    if credentials.is_some() && tls_acceptor.is_none() {
+        warn!("Passwords and tokens are sent in clear text without TLS");
     }
 
-    for child in children {
-        let _ = child.join().unwrap();
+    let mut listener = match TcpListener::bind(c.address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to listen on {}: {}", c.address, e);
+            return;
+        },
+    };
+    info!(address = %c.address, max_sessions = c.max_sessions, "Listening");
+
+    let sessions = Arc::new(Semaphore::new(c.max_sessions));
+    let (stopping, stop) = watch::channel(Stop::Running);
+    let requested = shutdown::requested();
+    pin_mut!(requested);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Unable to accept a connection: {}", e);
+                    continue;
+                },
+            },
+            _ = &mut requested => break,
+        };
+        // A connection over the limit waits here, and the ones after it in
+        // the listen backlog, until a session ends.
+        tokio::select! {
+            permit = sessions.acquire() => permit.forget(),
+            _ = &mut requested => break,
+        }
+        let id = logging::next_session_id();
+        let span = info_span!("session", id, peer = %peer, user = field::Empty);
+        info!(parent: &span, "Connected");
+        let active = metrics.session(Protocol::Tcp);
+
+        // Every session is a task of its own; a supervising task reports how
+        // it ended and frees its slot, even when it panicked.
+        let session = tokio::spawn(run_session(spaces.clone(), metrics.clone(), audit.clone(),
+                                               tls_acceptor.clone(), credentials.clone(), peer,
+                                               stream, stop.clone())
+                                   .instrument(span.clone()));
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            match session.await {
+                Ok(_) => info!(parent: &span, "Disconnected"),
+                Err(ref e) if e.is_panic() => {
+                    error!(parent: &span, "Session panicked, connection dropped");
+                },
+                Err(e) => error!(parent: &span, "Session failed: {}", e),
+            }
+            drop(active);
+            sessions.add_permits(1);
+        });
     }
+
+    drain(c.max_sessions, c.grace_period, &sessions, &stopping).await;
+}
+
+// How far the server has got with shutting down. Sessions close when they are
+// idle once it drains, and in the middle of a command once it aborts.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Stop {
+    Running,
+    Draining,
+    Aborting,
 }
 
-fn check_ack(mut ack_buf: &mut [u8])
+// Resolves once the server has got at least as far as `stop`.
+async fn stopped(stop_rx: &mut watch::Receiver<Stop>, stop: Stop) {
+    if *stop_rx.borrow() >= stop {
+        return;
+    }
+    while let Some(current) = stop_rx.recv().await {
+        if current >= stop {
+            return;
+        }
+    }
+}
+
+// Idle sessions are closed right away, sessions in the middle of a command get
+// until the end of the grace period. Whatever still runs after that has its
+// connection cut, which fails the transfer; a partial upload is kept to be
+// resumed.
+async fn drain(max_sessions: usize,
+               grace_period: Duration,
+               sessions: &Semaphore,
+               stopping: &watch::Sender<Stop>) {
+    info!(sessions = max_sessions - sessions.available_permits(), grace_period = ?grace_period,
+          "Shutting down");
+    let _ = stopping.broadcast(Stop::Draining);
+
+    let deadline = time::Instant::now() + grace_period;
+    let mut closed = 0;
+    while closed < max_sessions {
+        match time::timeout_at(deadline, sessions.acquire()).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => break,
+        }
+        closed += 1;
+    }
+    if closed < max_sessions {
+        warn!(sessions = max_sessions - closed, "Grace period is over, aborting");
+        let _ = stopping.broadcast(Stop::Aborting);
+        while closed < max_sessions {
+            sessions.acquire().await.forget();
+            closed += 1;
+        }
+    }
+    info!("All sessions are closed");
+}
+
+async fn run_session(spaces: Arc<Spaces>,
+                     metrics: Arc<Metrics>,
+                     audit: Arc<Audit>,
+                     tls_acceptor: Option<TlsAcceptor>,
+                     credentials: Option<Arc<Credentials>>,
+                     peer: SocketAddr,
+                     stream: TcpStream,
+                     mut stop: watch::Receiver<Stop>) {
+    match tls_acceptor {
+        Some(acceptor) => {
+            let accepted = tokio::select! {
+                accepted = acceptor.accept(stream) => accepted,
+                _ = stopped(&mut stop, Stop::Draining) => return,
+            };
+            let mut tls_stream = match accepted {
+                Ok(tls_stream) => BufStream::new(tls_stream),
+                Err(e) => {
+                    warn!("TLS handshake failed: {}", e);
+                    return;
+                },
+            };
+            handle_event(&spaces, &metrics, &audit, credentials.as_deref(), peer,
+                         &mut tls_stream, stop).await;
+            // Sends the close_notify before closing the connection.
+            let _ = tls_stream.shutdown().await;
+        },
+        None => {
+            let mut stream = BufStream::new(stream);
+            handle_event(&spaces, &metrics, &audit, credentials.as_deref(), peer,
+                         &mut stream, stop).await;
+            let _ = stream.shutdown().await;
+        },
+    }
+}
+
+// The connection of a session, buffered: control messages are read up to
+// their terminator, and what is written goes out on flush.
+trait Stream: AsyncBufRead + AsyncWrite + Unpin + Send {
+    // The socket of a plain TCP connection, shared rather than taken over, for
+    // downloads to be sent to with sendfile.
+    fn socket(&self) -> Option<net::TcpStream> {
+        None
+    }
+}
+
+impl Stream for BufStream<TcpStream> {
+    #[cfg(target_os = "linux")]
+    fn socket(&self) -> Option<net::TcpStream> {
+        let socket = unsafe { net::TcpStream::from_raw_fd(self.get_ref().as_raw_fd()) };
+        // Only the clone is closed when it is dropped.
+        ManuallyDrop::new(socket).try_clone().ok()
+    }
+}
+
+impl Stream for BufStream<TlsStream<TcpStream>> {}
+
+// The audit log is a file like any other.
+async fn write_audit(audit: &Arc<Audit>, record: Record, error: Option<String>) {
+    let audit = audit.clone();
+    blocking(move || audit.write(record, error)).await
+}
+
+fn check_ack(reply: &str)
     -> Result<(), SrwscError> {
-    let ack_slice: &str = str::from_utf8(&mut ack_buf).unwrap();
-    let mut ack_str = ack_slice.to_string();
-    let index: usize = ack_str.rfind('\r').unwrap();
-    ack_str.truncate(index);
-    if ack_str != ACK_MESSAGE {
-        return Err(SrwscError::new(ErrorCode::ErrorAck,
-                                   String::from("ACK failed")));
+    match reply {
+        ACK_MESSAGE => Ok(()),
+        _ => Err(SrwscError::new(ErrorCode::ErrorAck,
+                                 String::from("ACK failed"))),
     }
-    Ok(())
 }
 
 fn encoded_message_size(cmd: &str)
     -> Result<Vec<u8>, SrwscError> {
-    let mut msg_size = cmd.len();
-    msg_size = msg_size + 1;
-    let msg_size_str = msg_size.to_string();
-    let mut msg_size_bytes = ASCII
-        .encode(&msg_size_str, EncoderTrap::Strict)
-        .map_err(|x| x.into_owned())
-        .unwrap();
-    msg_size_bytes.push('\r' as u8);
-
-    Ok(msg_size_bytes)
+    encoded_message(&(cmd.len() + 1).to_string())
 }
 
+// Messages are ASCII; anything else is a bug in the caller rather than
+// something the peer sent.
 fn encoded_message(cmd: &str)
     -> Result <Vec<u8>, SrwscError> {
-    let msg_str = cmd.to_string();
     let mut msg_bytes = ASCII
-        .encode(&msg_str, EncoderTrap::Strict)
-        .map_err(|x| x.into_owned())
-        .unwrap();
-    msg_bytes.push('\r' as u8);
+        .encode(cmd, EncoderTrap::Strict)
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorProtocol,
+                                     format!("cannot encode {:?}: {}", cmd, e)))?;
+    msg_bytes.push(b'\r');
 
     Ok(msg_bytes)
 }
 
-fn send_ack_message(stream: &mut TcpStream) {
-    let ack = encoded_message("ACK").unwrap();
-    stream.write_all(&ack).unwrap();
+// Everything written is flushed before waiting for the peer to answer it.
+async fn write_flush<S: Stream>(bytes: &[u8],
+                                stream: &mut S)
+    -> io::Result<()> {
+    stream.write_all(bytes).await?;
+    stream.flush().await
 }
 
-fn send_normal_message(msg: &str,
-                       stream: &mut TcpStream)
-    -> Result<(), SrwscError> {
-    let mut buf = [0u8; BUFFER_SIZE];
-    let send_msg_size = encoded_message_size(msg).unwrap();
-    let send_msg = encoded_message(msg).unwrap();
+// Reads one "...\r" field of the protocol, without the terminator.
+async fn read_field<S: Stream>(stream: &mut S)
+    -> Result<String, SrwscError> {
+    let mut field = Vec::new();
+    (&mut *stream).take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_until(b'\r', &mut field)
+        .await?;
+    if field.pop() != Some(b'\r') {
+        let reason = match field.len() >= MAX_MESSAGE_SIZE {
+            true => "message is too long",
+            false => "connection closed",
+        };
+        return Err(SrwscError::new(ErrorCode::ErrorProtocol, String::from(reason)));
+    }
+    String::from_utf8(field)
+        .map_err(|_| SrwscError::new(ErrorCode::ErrorProtocol,
+                                     String::from("message is not UTF-8")))
+}
+
+// A failed write shows up as the next read failing, which ends the session.
+async fn send_ack_message<S: Stream>(stream: &mut S) {
+    let _ = write_flush(b"ACK\r", stream).await;
+}
 
-    stream.write_all(&send_msg_size).unwrap();
+// Reads the peer's answer to what was just written.
+async fn receive_ack<S: Stream>(stream: &mut S)
+    -> Result<(), SrwscError> {
+    check_ack(&read_field(stream).await?)
+}
 
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_normal_message] check_ack for size: {}", e);
+// Replaces the ACK of a request the server refuses. Once the peer has
+// acknowledged the NAK, the reason follows as a normal message.
+async fn send_nak_message<S: Stream>(reason: &str,
+                                     stream: &mut S)
+    -> Result<(), SrwscError> {
+    write_flush(&encoded_message(NAK_MESSAGE)?, stream).await?;
+    if let Err(e) = receive_ack(stream).await {
+        debug!("No ACK for a NAK: {}", e);
         return Err(e);
     }
+    send_normal_message(reason, stream).await
+}
 
-    stream.write_all(&send_msg).unwrap();
+async fn send_normal_message<S: Stream>(msg: &str,
+                                        stream: &mut S)
+    -> Result<(), SrwscError> {
+    let send_msg_size = encoded_message_size(msg)?;
+    let send_msg = encoded_message(msg)?;
 
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_normal_message] check_ack for message: {}", e);
+    write_flush(&send_msg_size, stream).await?;
+    if let Err(e) = receive_ack(stream).await {
+        debug!("No ACK for the size of {:?}: {}", msg, e);
+        return Err(e);
+    }
+
+    write_flush(&send_msg, stream).await?;
+    if let Err(e) = receive_ack(stream).await {
+        debug!("No ACK for {:?}: {}", msg, e);
         return Err(e);
     }
 
     Ok(())
 }
 
-fn decoded_message_len(mut msg: &mut [u8])
+// A message comes as its size, counting the terminator, and then itself; the
+// server acknowledges both.
+async fn receive_message<S: Stream>(stream: &mut S)
+    -> Result<String, SrwscError> {
+    let size = read_field(stream).await?;
+    let size = size.parse::<usize>().ok()
+        .filter(|&size| size > 0 && size <= MAX_MESSAGE_SIZE)
+        .ok_or_else(|| SrwscError::new(ErrorCode::ErrorProtocol,
+                                       format!("invalid message size {:?}", size)))?;
+    send_ack_message(stream).await;
+
+    let mut msg = vec![0u8; size];
+    stream.read_exact(&mut msg).await?;
+    if msg.pop() != Some(b'\r') {
+        return Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                   String::from("message is not terminated")));
+    }
+    send_ack_message(stream).await;
+    String::from_utf8(msg)
+        .map_err(|_| SrwscError::new(ErrorCode::ErrorProtocol,
+                                     String::from("message is not UTF-8")))
+}
+
+// An empty message means that the peer has gone away or does not speak the
+// protocol.
+async fn get_message<S: Stream>(stream: &mut S)
     -> String {
-    let mut msg_len = str::from_utf8(&mut msg)
-        .unwrap()
-        .to_string();
-
-    let mut index = 0;
-    for c in msg_len.chars() {
-        if c.is_numeric() == true {
-            index = index + 1;
+    match receive_message(stream).await {
+        Ok(msg) => msg,
+        Err(e) => {
+            debug!("No message: {}", e);
+            String::new()
+        },
+    }
+}
+
+// Sizes of files are sent as "DIGITS\r", without a message around them.
+async fn read_size<S: Stream>(stream: &mut S)
+    -> Result<u64, SrwscError> {
+    let digits = read_field(stream).await?;
+    if digits.is_empty() || digits.len() > 20 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                   format!("size {:?} is not a number", digits)));
+    }
+    digits.parse::<u64>()
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorProtocol, e.to_string()))
+}
+
+// Typed replies follow a message naming their type, as a frame holding the
+// protobuf encoding: its size like a file size, the bytes once the peer has
+// acknowledged the size, and a final ACK from the peer.
+async fn send_frame<M: Message, S: Stream>(frame: &M,
+                                           stream: &mut S)
+    -> Result<(), SrwscError> {
+    let mut bytes = Vec::with_capacity(frame.encoded_len());
+    frame.encode(&mut bytes)
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorProtocol, e.to_string()))?;
+    write_flush(&encoded_message(&bytes.len().to_string())?, stream).await?;
+    if let Err(e) = receive_ack(stream).await {
+        debug!("No ACK for the size of a frame: {}", e);
+        return Err(e);
+    }
+
+    write_flush(&bytes, stream).await?;
+    if let Err(e) = receive_ack(stream).await {
+        debug!("No ACK for a frame: {}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// File data is moved between the connection, on the session's task, and the
+// file, on the blocking pool, through channels. The transfer code of
+// srwsc-common runs unchanged on the blocking side, where ChunkWriter stands
+// in for the connection of a download: it hands the data over in chunks of
+// about CHUNK_SIZE bytes.
+struct ChunkWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(tx: mpsc::Sender<Vec<u8>>) -> Self {
+        ChunkWriter {
+            tx: tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
         }
     }
-    msg_len.truncate(index);
-    msg_len
+
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        block_on(self.tx.send(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+    }
 }
 
-fn decoded_message(msg_len: &str,
-                   stream: &mut TcpStream)
-    -> String {
-    let mut remaining_data = msg_len.parse::<i32>().unwrap();
-    let mut msg: String = String::new();
-    let mut buf = [0u8; BUFFER_SIZE];
-
-    while remaining_data > 0 {
-        let r = stream.read(&mut buf);
-        match r {
-            Ok(n) => {
-                msg.push_str(str::from_utf8(&mut buf).unwrap());
-                remaining_data = remaining_data - n as i32;
-            },
-            _ => { break; },
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
         }
+        Ok(data.len())
     }
 
-    let index = msg.rfind('\r').unwrap();
-    msg.truncate(index);
-    msg
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
 }
 
-fn get_message(stream: &mut TcpStream)
-    -> String {
-    let mut buf = [0u8; BUFFER_SIZE];
-    stream.read(&mut buf).unwrap();
-    let msg_len = decoded_message_len(&mut buf);
-    if msg_len.is_empty() == true {
-        return String::from("");
-    }
-    send_ack_message(stream);
-    let msg = decoded_message(&msg_len, stream);
-    send_ack_message(stream);
-    msg
-}
-
-fn receive_file_impl(file_name: &str,
-                     storage: &str,
-                     stream: &mut TcpStream) {
-    println!("[receive_file_impl] file_name = {}", file_name);
-    let mut buf = [0u8; BUFFER_SIZE];
-
-    stream.read(&mut buf).unwrap();
-    let file_size = decoded_message_len(&mut buf);
-    println!("[receive_file_impl] file_size = {}", file_size);
-
-    send_ack_message(stream);
-
-    let mut fullname = String::from(storage);
-    fullname.push_str("/");
-    fullname.push_str(&file_name);
-
-    let mut file_buffer = BufWriter::new(File::create(fullname).unwrap());
-    let mut remaining_data = file_size.parse::<u64>().unwrap();
-    let mut written: i32;
-    while remaining_data > 0 {
-        let slab = stream.read(&mut buf);
-        match slab {
-            Ok(n) => {
-                if remaining_data < BUFFER_SIZE as u64 {
-                    let sbuf = &buf[0 .. remaining_data as usize];
-                    file_buffer.write(sbuf).unwrap();
-                    written = remaining_data as i32;
-                } else {
-                    file_buffer.write(&mut buf).unwrap();
-                    written = n as i32;
-                }
-                file_buffer.flush().unwrap();
-                remaining_data = remaining_data - written as u64;
-            }
-            _ => { break; }
+// The connection of an upload on the blocking side. Every read asks the
+// session's task for at most that many bytes, so that nothing after the data
+// is taken from the connection.
+struct SocketReader {
+    requests: mpsc::Sender<usize>,
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Read for SocketReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "connection closed");
+        block_on(self.requests.send(buf.len())).map_err(|_| closed())?;
+        match block_on(self.chunks.recv()) {
+            Some(Ok(chunk)) => {
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            },
+            Some(Err(e)) => Err(e),
+            None => Err(closed()),
         }
     }
 }
 
-fn send_file_impl(fullpath: &str,
-                  file_size: u64,
-                  stream: &mut TcpStream) {
-    let mut buf = [0u8; BUFFER_SIZE];
-    let send_file_size = encoded_message(&file_size.to_string()).unwrap();
-    stream.write_all(&send_file_size).unwrap();
+// The session's side of a download: writes out the chunks until the file
+// side is done.
+async fn write_chunks<S: Stream>(mut chunks: mpsc::Receiver<Vec<u8>>,
+                                 stream: &mut S)
+    -> io::Result<()> {
+    while let Some(chunk) = chunks.recv().await {
+        stream.write_all(&chunk).await?;
+    }
+    stream.flush().await
+}
 
-    stream.read(&mut buf).unwrap();
-    if let Err(e) = check_ack(&mut buf) {
-        println!("[send_file_impl] check_ack: {}", e);
-        return;
+// The session's side of an upload: answers the reads of the file side until
+// it is done.
+async fn serve_reads<S: Stream>(mut requests: mpsc::Receiver<usize>,
+                                mut chunks: mpsc::Sender<io::Result<Vec<u8>>>,
+                                stream: &mut S) {
+    while let Some(len) = requests.recv().await {
+        let mut buf = vec![0u8; len];
+        let read = stream.read(&mut buf).await.map(|n| {
+            buf.truncate(n);
+            buf
+        });
+        if chunks.send(read).await.is_err() {
+            break;
+        }
     }
+}
 
-    let mut remaining_data = file_size;
-    let mut file = File::open(fullpath).unwrap();
-    while remaining_data != 0 {
-        let r = file.read(&mut buf);
-        match r {
-            Ok(n) => {
-                if n == 0 {
-                    println!("Read file is 0");
-                    break;
-                }
-                stream.write_all(&buf).unwrap();
-                println!("Sent {} bytes", n);
-                remaining_data = remaining_data - n as u64;
-            }
-            _ => {}
+async fn receive_file_impl<S: Stream>(file_name: &str,
+                                      offset: u64,
+                                      compression: Option<Compression>,
+                                      role: Role,
+                                      space: &Arc<Space>,
+                                      metrics: &Metrics,
+                                      record: &mut Record,
+                                      stream: &mut S)
+    -> Result<(), SrwscError> {
+    let file_size = match read_size(stream).await {
+        Ok(file_size) => file_size,
+        Err(e) => {
+            warn!("Cannot read the file size: {}", e);
+            return Err(e);
+        },
+    };
+    debug!(offset, size = file_size, "Upload announced");
+    record.size = Some(file_size);
+
+    if let Err(e) = storage::validate_name(file_name) {
+        warn!("Rejected file name: {}", e);
+        send_nak_error(&e, stream).await;
+        return Err(e);
+    }
+    let exists = {
+        let space = space.clone();
+        let name = file_name.to_string();
+        blocking(move || space.storage().stat(&name).is_ok()).await
+    };
+    if exists {
+        if let Err(e) = role.check_replace(file_name) {
+            warn!("Denied: {}", e.message());
+            send_nak_error(&e, stream).await;
+            return Err(e);
         }
     }
+    // Held until the upload is committed or thrown away.
+    let reserved = match misc::upload_end(offset, file_size) {
+        Ok(end) => {
+            let space = space.clone();
+            let name = file_name.to_string();
+            blocking(move || space.reserve(&name, end)).await
+        },
+        Err(e) => Err(e),
+    };
+    let _reservation = match reserved {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            warn!("Rejected: {}", e);
+            send_nak_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    let created = {
+        let space = space.clone();
+        let name = file_name.to_string();
+        blocking(move || space.storage().create(&name, offset)).await
+    };
+    let upload = match created {
+        Ok(upload) => upload,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+            warn!("Cannot resume: {}", e);
+            let e = SrwscError::new(ErrorCode::OutOfRange, e.to_string());
+            send_nak_error(&e, stream).await;
+            return Err(e);
+        },
+        Err(e) => {
+            warn!("Cannot create: {}", e);
+            let e = SrwscError::from(e);
+            send_nak_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    // A resumed upload is verified as a whole, so the part the server already
+    // has is hashed first.
+    let (mut upload, prefix) = blocking(move || {
+        let mut checksum = Checksum::new();
+        let prefix = match offset {
+            0 => Ok(()),
+            _ => upload.read_back()
+                .and_then(|mut prefix| checksum.update_from(&mut prefix, offset)),
+        };
+        (upload, prefix.map(|_| checksum))
+    }).await;
+    let mut checksum = match prefix {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            warn!("Cannot read back: {}", e);
+            let e = SrwscError::from(e);
+            send_nak_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    send_ack_message(stream).await;
+    if let Some(compression) = compression {
+        send_normal_message(compression.name(), stream).await?;
+    }
+
+    let started = Instant::now();
+    let (requests_tx, requests_rx) = mpsc::channel(1);
+    let (chunks_tx, chunks_rx) = mpsc::channel(1);
+    let writing = blocking(move || {
+        let mut socket = SocketReader {
+            requests: requests_tx,
+            chunks: chunks_rx,
+        };
+        let received = match compression {
+            Some(compression) if compression != Compression::None => {
+                compression::receive_compressed(&mut socket, &mut upload, file_size,
+                                                &mut checksum, compression)
+            },
+            _ => transfer::receive_data(&mut socket, &mut upload, file_size, &mut checksum)
+                .map(|_| file_size),
+        };
+        (upload, checksum, received)
+    });
+    let ((upload, checksum, received), _) = tokio::join!(writing,
+                                                         serve_reads(requests_rx, chunks_tx,
+                                                                     stream));
+    match received {
+        Ok(received) => {
+            let duration = started.elapsed();
+            info!(bytes = file_size, wire_bytes = received,
+                  compression = compression.unwrap_or(Compression::None).name(),
+                  duration = ?duration, "Received");
+            metrics.received(Protocol::Tcp, received);
+            metrics.transfer(Protocol::Tcp, Operation::Put, duration);
+        },
+        Err(e) => {
+            warn!("Transfer cut off, keeping the upload to resume: {}", e);
+            return Err(e.into());
+        },
+    }
+
+    // Only a verified upload is committed.
+    let digest = get_message(stream).await;
+    if digest != checksum.finish() {
+        warn!(digest = %digest, "Checksum mismatch, discarding the upload");
+        blocking(move || upload.discard()).await;
+        let e = SrwscError::new(ErrorCode::ChecksumMismatch,
+                                format!("{} does not match the data received", digest));
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    let replace = role.may_replace();
+    match blocking(move || upload.commit(replace)).await {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && !replace => {
+            let e = role.check_replace(file_name).unwrap_err();
+            warn!("Denied, the file was created meanwhile: {}", e.message());
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+        Err(e) => {
+            error!("Cannot commit: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+    }
+    let stored = {
+        let space = space.clone();
+        let name = file_name.to_string();
+        let digest = digest.clone();
+        blocking(move || space.storage().set_digest(&name, &digest)).await
+    };
+    match stored {
+        Ok(_) => debug!(digest = %digest, "Verified"),
+        Err(e) => warn!("Cannot store the digest: {}", e),
+    }
+    record.digest = Some(digest);
+    // The upload is in place whether or not the client hears about it.
+    let _ = send_normal_message(CHECKSUM_OK_MESSAGE, stream).await;
+    Ok(())
 }
 
-fn send_file(file_name: &str,
-             storage: &str,
-             stream: &mut TcpStream) {
-    let file_info = misc::check_file(file_name, storage);
-    if file_info.size > 0 {
-        println!("[send_file] File found");
-        match send_normal_message(PREPARE_TRANSFER_MESSAGE, stream) {
-            Err(e) => {
-                println!("[send_file] Error with {:?}", e);
-                return;
+async fn send_file_impl<S: Stream>(file: Box<dyn Read + Send>,
+                                   checksum: Checksum,
+                                   sendable: Option<(File, net::TcpStream)>,
+                                   file_size: u64,
+                                   compression: Option<Compression>,
+                                   metrics: &Metrics,
+                                   record: &mut Record,
+                                   stream: &mut S)
+    -> Result<(), SrwscError> {
+    record.size = Some(file_size);
+    let size = encoded_message(&file_size.to_string())?;
+    let sent_size = match write_flush(&size, stream).await {
+        Ok(_) => receive_ack(stream).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = sent_size {
+        debug!("No ACK for the file size: {}", e);
+        return Err(e);
+    }
+    if let Some(compression) = compression {
+        send_normal_message(compression.name(), stream).await?;
+    }
+
+    let started = Instant::now();
+    let (checksum, sent) = match sendable {
+        #[cfg(target_os = "linux")]
+        Some((sendable, socket)) => {
+            send_with_sendfile(file, sendable, socket, checksum, file_size).await
+        },
+        _ => send_with_copy(file, checksum, file_size, compression, stream).await,
+    };
+    match sent {
+        Ok(sent) => {
+            let duration = started.elapsed();
+            info!(bytes = file_size, wire_bytes = sent,
+                  compression = compression.unwrap_or(Compression::None).name(),
+                  duration = ?duration, "Sent");
+            metrics.sent(Protocol::Tcp, sent);
+            metrics.transfer(Protocol::Tcp, Operation::Get, duration);
+        },
+        Err(e) => {
+            warn!("Transfer cut off: {}", e);
+            return Err(e.into());
+        },
+    }
+
+    let digest = checksum.finish();
+    if let Err(e) = send_normal_message(&digest, stream).await {
+        warn!("Cannot send the checksum: {}", e);
+        return Err(e);
+    }
+    record.digest = Some(digest);
+    match get_message(stream).await.as_ref() {
+        CHECKSUM_OK_MESSAGE => {
+            debug!("Verified by the client");
+            Ok(())
+        },
+        _ => {
+            warn!("The client rejected the checksum");
+            Err(SrwscError::new(ErrorCode::ChecksumMismatch,
+                                String::from("rejected by the client")))
+        },
+    }
+}
+
+// The data of a download through user space, where it is hashed, and
+// compressed if asked for, on the way. Returns the bytes sent on the wire.
+async fn send_with_copy<S: Stream>(mut file: Box<dyn Read + Send>,
+                                   mut checksum: Checksum,
+                                   file_size: u64,
+                                   compression: Option<Compression>,
+                                   stream: &mut S)
+    -> (Checksum, io::Result<u64>) {
+    let (chunks_tx, chunks_rx) = mpsc::channel(1);
+    let reading = blocking(move || {
+        let mut socket = ChunkWriter::new(chunks_tx);
+        let sent = match compression {
+            Some(compression) if compression != Compression::None => {
+                compression::send_compressed(&mut file, file_size, &mut checksum, compression,
+                                             &mut socket)
             },
-            _ => {},
-        }
-        send_file_impl(&file_info.fullpath, file_info.size, stream);
-    } else {
-        println!("[send_file] File is not found");
-        let _ = send_normal_message(CANNOT_FIND_FILE_MESSAGE, stream);
+            _ => transfer::send_data(&mut file, file_size, &mut checksum, &mut socket)
+                .map(|_| file_size),
+        };
+        (checksum, sent)
+    });
+    let ((checksum, sent), written) = tokio::join!(reading, write_chunks(chunks_rx, stream));
+    (checksum, sent.and_then(|sent| written.map(|_| sent)))
+}
+
+// The data of a download copied by the kernel from `sendable` to `socket`,
+// while `file` is read again for the checksum. Everything before the data
+// has been flushed, so nothing is left in the buffer of the stream.
+#[cfg(target_os = "linux")]
+async fn send_with_sendfile(mut file: Box<dyn Read + Send>,
+                            sendable: File,
+                            socket: net::TcpStream,
+                            mut checksum: Checksum,
+                            file_size: u64)
+    -> (Checksum, io::Result<u64>) {
+    debug!("Sending with sendfile");
+    let hashing = blocking(move || {
+        let hashed = checksum.update_from(&mut file, file_size);
+        (checksum, hashed)
+    });
+    let sending = blocking(move || {
+        transfer::sendfile(&sendable, file_size, &socket, SEND_TIMEOUT)
+    });
+    let ((checksum, hashed), sent) = tokio::join!(hashing, sending);
+    (checksum, sent.and(hashed).map(|_| file_size))
+}
+
+async fn send_file<S: Stream>(file_name: &str,
+                              offset: u64,
+                              length: Option<u64>,
+                              compression: Option<Compression>,
+                              space: &Arc<Space>,
+                              metrics: &Metrics,
+                              record: &mut Record,
+                              stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Err(e) = storage::validate_name(file_name) {
+        warn!("Rejected file name: {}", e);
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    // A resumed download is verified as a whole, so it is read from the
+    // start to hash the part the client already has.
+    let start = match length {
+        Some(_) => offset,
+        None => 0,
+    };
+    let opened = {
+        let space = space.clone();
+        let name = file_name.to_string();
+        blocking(move || {
+            let storage = space.storage();
+            storage.stat(&name)
+                .and_then(|f| storage.open(&name, start).map(|file| (f, file)))
+        }).await
+    };
+    match opened {
+        Ok((file_info, _)) if !misc::in_range(offset, length, file_info.size) => {
+            warn!(offset, length = ?length, size = file_info.size, "Range is beyond the file");
+            let e = SrwscError::new(ErrorCode::OutOfRange,
+                                    format!("{} is {} bytes", file_name, file_info.size));
+            send_error(&e, stream).await;
+            Err(e)
+        },
+        Ok((file_info, file)) => {
+            debug!(offset, length = ?length, "Sending");
+            // Plain data over plain TCP is left to the kernel to copy.
+            let socket = match compression.unwrap_or(Compression::None) {
+                Compression::None => stream.socket(),
+                _ => None,
+            };
+            let sendfile = socket.is_some();
+            let (file, prefix) = {
+                let space = space.clone();
+                let name = file_name.to_string();
+                blocking(move || {
+                    let mut file = file;
+                    let mut checksum = Checksum::new();
+                    let prefix = checksum.update_from(&mut file, offset - start)
+                        .and_then(|_| match sendfile {
+                            true => space.storage().open_file(&name, offset),
+                            false => Ok(None),
+                        });
+                    (file, prefix.map(|sendable| (checksum, sendable)))
+                }).await
+            };
+            let (checksum, sendable) = match prefix {
+                Ok(prefix) => prefix,
+                Err(e) => {
+                    warn!("Cannot read: {}", e);
+                    let e = SrwscError::from(e);
+                    send_error(&e, stream).await;
+                    return Err(e);
+                },
+            };
+            if let Err(e) = send_normal_message(PREPARE_TRANSFER_MESSAGE, stream).await {
+                debug!("Cannot start the transfer: {}", e);
+                return Err(e);
+            }
+            let length = length.unwrap_or(file_info.size - offset);
+            send_file_impl(file, checksum, sendable.zip(socket), length, compression, metrics,
+                           record, stream).await
+        },
+        Err(e) => {
+            warn!("Cannot open: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            Err(e)
+        },
     }
 }
 
-fn receive_file(file_name: &str,
-                storage: &str,
-                mut stream: &mut TcpStream) {
-    match get_message(stream).as_ref(){
+async fn receive_file<S: Stream>(file_name: &str,
+                                 offset: u64,
+                                 compression: Option<Compression>,
+                                 role: Role,
+                                 space: &Arc<Space>,
+                                 metrics: &Metrics,
+                                 record: &mut Record,
+                                 stream: &mut S)
+    -> Result<(), SrwscError> {
+    match get_message(stream).await.as_ref() {
         CANNOT_FIND_FILE_MESSAGE => {
-            println!("[receive_file] Cannot transfer file");
+            debug!("The client cannot find its file");
+            Err(SrwscError::new(ErrorCode::NotExistFile,
+                                String::from("the client cannot find its file")))
         }
         PREPARE_TRANSFER_MESSAGE => {
-            println!("[receive_file] Prepare transfer file");
-            receive_file_impl(file_name, storage, &mut stream);
+            receive_file_impl(file_name, offset, compression, role, space, metrics, record,
+                              stream).await
         }
         _ => {
-            println!("[receive_file] Unknown message");
+            warn!("Unexpected reply to an upload");
+            Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                                String::from("unexpected reply to an upload")))
         }
     }
 }
 
-fn remove_file(filename: &str,
-               storage: &str,
-               stream: &mut TcpStream) {
-    println!("Not impl");
-    let mut msg = String::new();
-    let f = misc::check_file(filename, storage);
-    if f.size > 0 {
-        println!("[remove_file] File found");
-        match fs::remove_file(&f.fullpath) {
-            Ok(_) => {
-                println!("[remove_file] {} is removed", f.name);
-                msg.push_str(REMOVED_OK_MESSAGE);
-            },
-            Err(e) => {
-                println!("[remove_file] An error occured: {:?}", e);
-                msg.push_str(REMOVED_NOK_MESSAGE);
-            },
-        }
-    } else {
-        println!("[remove_file] File is not found");
-        msg.push_str(CANNOT_FIND_FILE_MESSAGE);
+async fn remove_file<S: Stream>(filename: &str,
+                                space: &Arc<Space>,
+                                record: &mut Record,
+                                stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Err(e) = storage::validate_name(filename) {
+        warn!("Rejected file name: {}", e);
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    let (removed, removed_result) = {
+        let space = space.clone();
+        let name = filename.to_string();
+        blocking(move || {
+            let storage = space.storage();
+            (storage.stat(&name).ok(), storage.remove(&name))
+        }).await
+    };
+    if let Some(f) = removed {
+        record.size = Some(f.size);
+        record.digest = f.digest;
+    }
+    match removed_result {
+        Ok(_) => {
+            info!("Removed");
+            let _ = send_normal_message(REMOVED_OK_MESSAGE, stream).await;
+            Ok(())
+        },
+        Err(e) => {
+            warn!("Cannot remove: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            Err(e)
+        },
+    }
+}
+
+async fn make_dir<S: Stream>(dirname: &str,
+                             space: &Arc<Space>,
+                             stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Err(e) = storage::validate_name(dirname) {
+        warn!("Rejected directory name: {}", e);
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    let created = {
+        let space = space.clone();
+        let name = dirname.to_string();
+        blocking(move || space.storage().mkdir(&name)).await
+    };
+    match created {
+        Ok(_) => {
+            info!("Created");
+            let _ = send_normal_message(MKDIR_OK_MESSAGE, stream).await;
+            Ok(())
+        },
+        Err(e) => {
+            warn!("Cannot create: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            Err(e)
+        },
+    }
+}
+
+async fn remove_dir<S: Stream>(dirname: &str,
+                               space: &Arc<Space>,
+                               stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Err(e) = storage::validate_name(dirname) {
+        warn!("Rejected directory name: {}", e);
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    let removed = {
+        let space = space.clone();
+        let name = dirname.to_string();
+        blocking(move || space.storage().rmdir(&name)).await
+    };
+    match removed {
+        Ok(_) => {
+            info!("Removed");
+            let _ = send_normal_message(REMOVED_OK_MESSAGE, stream).await;
+            Ok(())
+        },
+        Err(e) => {
+            warn!("Cannot remove: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            Err(e)
+        },
     }
-    let _ = send_normal_message(&msg, stream);
 }
 
-fn ls_server(storage: &str,
-             stream: &mut TcpStream) {
-    let mut msg = misc::get_file_list(storage);
-    msg.push('\r');
+async fn stat_file<S: Stream>(filename: &str,
+                              prefix: u64,
+                              role: Role,
+                              space: &Arc<Space>,
+                              stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Err(e) = storage::validate_name(filename) {
+        warn!("Rejected file name: {}", e);
+        send_error(&e, stream).await;
+        return Err(e);
+    }
+    let stat = {
+        let space = space.clone();
+        let name = filename.to_string();
+        blocking(move || storage::stat_for_resume(space.storage(), &name, prefix)).await
+    };
+    let file = match stat {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Cannot stat: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    send_normal_message(FILE_INFO_MESSAGE, stream).await?;
+    send_frame(&misc::stat_info(&file, role), stream).await
+}
+
+// "quota": usage and limits of the session's storage.
+async fn quota_info<S: Stream>(space: &Arc<Space>,
+                               stream: &mut S)
+    -> Result<(), SrwscError> {
+    let counted = {
+        let space = space.clone();
+        blocking(move || space.info()).await
+    };
+    let info = match counted {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Cannot count usage: {}", e);
+            let e = SrwscError::from(e);
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    send_normal_message(QUOTA_INFO_MESSAGE, stream).await?;
+    send_frame(&info, stream).await
+}
+
+// "ls [OPTIONS] [DIR]": sends one page of the listing of DIR (the storage
+// root by default). See misc::list_args for the options.
+async fn ls_server<S: Stream>(args: &str,
+                              space: &Arc<Space>,
+                              stream: &mut S)
+    -> Result<(), SrwscError> {
+    let request = match misc::list_args(args) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Rejected arguments: {}", e);
+            let e = SrwscError::new(ErrorCode::InvalidArgument, e);
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+
+    let path = request.path.clone();
+    let listed = {
+        let space = space.clone();
+        blocking(move || listing::list(space.storage(), &request)).await
+    };
+    let page = match listed {
+        Ok(page) => page,
+        Err(e) => {
+            warn!(path = %path, "Cannot list: {}", e);
+            send_error(&e, stream).await;
+            return Err(e);
+        },
+    };
+    send_normal_message(FILE_LIST_MESSAGE, stream).await?;
+    send_frame(&page, stream).await
+}
 
-    let _ = send_normal_message(&msg, stream);
+// "login NAME PASSWORD" or "login -t TOKEN". Without a credential file every
+// login is accepted, so that a client with credentials works with any server.
+// A bare "login" asks whether one is needed. Returns the user the session is
+// logged in as.
+async fn login<S: Stream>(args: &str,
+                          credentials: Option<&Credentials>,
+                          stream: &mut S)
+    -> Option<String> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            let _ = send_normal_message(LOGIN_OK_MESSAGE, stream).await;
+            return None;
+        },
+    };
+    if args.is_empty() {
+        let _ = send_normal_message(LOGIN_REQUIRED_MESSAGE, stream).await;
+        return None;
+    }
+    match credentials.login(args) {
+        Some(user) => {
+            Span::current().record("user", user.as_str());
+            info!("Logged in");
+            let _ = send_normal_message(LOGIN_OK_MESSAGE, stream).await;
+            Some(user)
+        },
+        None => {
+            warn!("Wrong credentials");
+            time::delay_for(LOGIN_FAILURE_DELAY).await;
+            let _ = send_normal_message(LOGIN_FAILED_MESSAGE, stream).await;
+            None
+        },
+    }
 }
 
-fn handle_event(storage: String, mut stream: TcpStream) {
+// Serves commands until the peer disconnects or stops speaking the protocol,
+// or the server shuts down. With a credential file, nothing but a login is
+// accepted until one succeeds, and the session then works in the storage of
+// the user. Every command is counted in `metrics` by how it ended, and the
+// ones on files are audited.
+async fn handle_event<S: Stream>(spaces: &Arc<Spaces>,
+                                 metrics: &Metrics,
+                                 audit: &Arc<Audit>,
+                                 credentials: Option<&Credentials>,
+                                 peer: SocketAddr,
+                                 stream: &mut S,
+                                 mut stop: watch::Receiver<Stop>) {
+    let mut space = match credentials {
+        Some(_) => None,
+        None => match open_space(spaces, None).await {
+            Some(space) => Some(space),
+            None => return,
+        },
+    };
+    let mut user = None;
+    let mut role = match credentials {
+        Some(_) => Role::default(),
+        None => Role::Admin,
+    };
+    let mut failed_logins = 0;
     loop {
-        let msg = get_message(&mut stream);
-
-        if msg.starts_with("get ") {
-            send_file(&msg[4..], &storage, &mut stream);
-        } else if msg.starts_with("put ") {
-            receive_file(&msg[4..], &storage, &mut stream);
-        } else if msg.starts_with("rm ") {
-            remove_file(&msg[3..], &storage, &mut stream);
-        } else {
-            match msg.as_ref() {
-                "ls" => {
-                    ls_server(&storage, &mut stream);
+        let msg = tokio::select! {
+            msg = get_message(stream) => msg,
+            _ = stopped(&mut stop, Stop::Draining) => break,
+        };
+        if msg.is_empty() {
+            break;
+        }
+
+        if msg == "login" || msg.starts_with("login ") {
+            let args = msg[5..].trim_start();
+            match login(args, credentials, stream).await {
+                Some(name) => {
+                    let (space_name, user_role) = match credentials {
+                        Some(credentials) => (credentials.space(&name), credentials.role(&name)),
+                        None => (name.clone(), Role::Admin),
+                    };
+                    space = open_space(spaces, Some(&space_name)).await;
+                    role = user_role;
+                    user = Some(name);
                 },
-                _ => {
-                    println!("Unknown command: {}", msg);
+                None if credentials.is_some() => {
+                    space = None;
+                    user = None;
+                    if !args.is_empty() {
+                        failed_logins += 1;
+                        if failed_logins >= MAX_LOGIN_ATTEMPTS {
+                            warn!(failed_logins, "Closing the session");
+                            break;
+                        }
+                    }
                 },
+                None => {},
+            }
+            continue;
+        }
+        let space = match &space {
+            Some(space) => space.clone(),
+            None => {
+                warn!("Rejected a command before login");
+                let _ = send_normal_message(LOGIN_REQUIRED_MESSAGE, stream).await;
+                break;
+            },
+        };
+        let command = msg.split(' ').next().unwrap_or_default();
+        let filename = command_filename(&msg);
+        let span = info_span!("command", command, filename);
+        let started = Instant::now();
+        let operation = match command_operation(&msg) {
+            Some(operation) => operation,
+            None => {
+                warn!(parent: &span, "Unknown command: {}", msg);
+                continue;
+            },
+        };
+        let mut record = Record::new(Protocol::Tcp, operation, filename, user.as_deref(),
+                                     Some(peer));
+        if let Err(e) = role.check(operation) {
+            deny(operation, &e, stream).instrument(span.clone()).await;
+            metrics.operation(Protocol::Tcp, operation, false);
+            write_audit(audit, record, Some(e.to_string())).await;
+            continue;
+        }
+
+        let running = run_command(&msg, filename, role, &space, metrics, &mut record, stream)
+            .instrument(span.clone());
+        let done = tokio::select! {
+            done = running => Some(done),
+            _ = stopped(&mut stop, Stop::Aborting) => None,
+        };
+        let aborted = done.is_none();
+        let done = done.unwrap_or_else(|| {
+            Err(SrwscError::new(ErrorCode::ErrorIo, String::from("aborted by the shutdown")))
+        });
+        match &done {
+            Ok(_) => info!(parent: &span, duration = ?started.elapsed(), "Done"),
+            Err(e) => warn!(parent: &span, code = ?e.code(), duration = ?started.elapsed(),
+                            "Failed: {}", e.message()),
+        }
+        metrics.operation(Protocol::Tcp, operation, done.is_ok());
+        write_audit(audit, record, done.err().map(|e| e.to_string())).await;
+        if aborted {
+            // A download sendfile is still copying on a blocking thread only
+            // stops once the socket is shut down under it.
+            if let Some(socket) = stream.socket() {
+                let _ = socket.shutdown(net::Shutdown::Both);
             }
+            break;
         }
     }
 }
 
+async fn run_command<S: Stream>(msg: &str,
+                                filename: &str,
+                                role: Role,
+                                space: &Arc<Space>,
+                                metrics: &Metrics,
+                                record: &mut Record,
+                                stream: &mut S)
+    -> Result<(), SrwscError> {
+    if let Some(args) = msg.strip_prefix("get ") {
+        let (compression, args) = misc::compression_args(args);
+        let (file_name, offset, length) = misc::transfer_args(args);
+        send_file(file_name, offset, length, compression, space, metrics, record, stream).await
+    } else if let Some(args) = msg.strip_prefix("put ") {
+        let (compression, args) = misc::compression_args(args);
+        let (file_name, offset, _) = misc::transfer_args(args);
+        receive_file(file_name, offset, compression, role, space, metrics, record, stream).await
+    } else if msg.starts_with("rm ") {
+        remove_file(filename, space, record, stream).await
+    } else if msg.starts_with("mkdir ") {
+        make_dir(filename, space, stream).await
+    } else if msg.starts_with("rmdir ") {
+        remove_dir(filename, space, stream).await
+    } else if let Some(args) = msg.strip_prefix("stat ") {
+        // "stat [PREFIX --] NAME", with the length of the prefix to digest.
+        let (file_name, prefix, _) = misc::transfer_args(args);
+        stat_file(file_name, prefix, role, space, stream).await
+    } else if msg == "ls" || msg.starts_with("ls ") {
+        ls_server(&msg[2..], space, stream).await
+    } else if msg == "quota" {
+        quota_info(space, stream).await
+    } else {
+        Err(SrwscError::new(ErrorCode::ErrorProtocol,
+                            format!("unknown command {:?}", msg)))
+    }
+}
+
+// The file or directory a command is about, empty for the others.
+fn command_filename(msg: &str) -> &str {
+    let mut words = msg.splitn(2, ' ');
+    let command = words.next().unwrap_or_default();
+    let args = words.next().unwrap_or_default();
+    match command {
+        "get" | "put" => misc::transfer_args(misc::compression_args(args).1).0,
+        "stat" => misc::transfer_args(args).0,
+        "rm" | "mkdir" | "rmdir" => args,
+        _ => "",
+    }
+}
+
+fn command_operation(msg: &str) -> Option<Operation> {
+    let operation = match msg.split(' ').next() {
+        Some("get") => Operation::Get,
+        Some("put") => Operation::Put,
+        Some("rm") => Operation::Remove,
+        Some("mkdir") => Operation::MakeDir,
+        Some("rmdir") => Operation::RemoveDir,
+        Some("stat") => Operation::Stat,
+        Some("ls") => Operation::List,
+        Some("quota") => Operation::Quota,
+        _ => return None,
+    };
+    Some(operation)
+}
+
+// Answers a command the role of the session does not allow. An upload is
+// turned down where its size is answered, like an upload over the quota.
+async fn deny<S: Stream>(operation: Operation,
+                         error: &SrwscError,
+                         stream: &mut S) {
+    warn!("Denied: {}", error.message());
+    if operation == Operation::Put {
+        if get_message(stream).await != PREPARE_TRANSFER_MESSAGE
+            || read_size(stream).await.is_err() {
+            return;
+        }
+        send_nak_error(error, stream).await;
+        return;
+    }
+    send_error(error, stream).await;
+}
+
+// "error" and the error as a frame, in place of the usual reply.
+async fn send_error<S: Stream>(error: &SrwscError,
+                               stream: &mut S) {
+    if send_normal_message(ERROR_MESSAGE, stream).await.is_ok() {
+        let _ = send_error_frame(error, stream).await;
+    }
+}
+
+// A refused upload: the NAK in place of the ACK for its size, with "error" as
+// the reason and the error as a frame.
+async fn send_nak_error<S: Stream>(error: &SrwscError,
+                                   stream: &mut S) {
+    if send_nak_message(ERROR_MESSAGE, stream).await.is_ok() {
+        let _ = send_error_frame(error, stream).await;
+    }
+}
+
+async fn send_error_frame<S: Stream>(error: &SrwscError,
+                                     stream: &mut S)
+    -> Result<(), SrwscError> {
+    send_frame(&Error {
+        kind: error.code().kind() as i32,
+        message: error.message().to_string(),
+    }, stream).await
+}
+
+async fn open_space(spaces: &Arc<Spaces>, user: Option<&str>)
+    -> Option<Arc<Space>> {
+    let opened = {
+        let spaces = spaces.clone();
+        let user = user.map(String::from);
+        blocking(move || spaces.get(user.as_deref())).await
+    };
+    match opened {
+        Ok(space) => Some(space),
+        Err(e) => {
+            error!(user = ?user, "Cannot open the storage: {}", e);
+            None
+        },
+    }
+}
@@ -0,0 +1,193 @@
+use crate::config::{ServerFile, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::error::{SrwscError, ErrorCode};
+use crate::misc;
+use crate::pb::{FileListResponse, ListRequest, SortKey};
+use crate::storage;
+use crate::storage::Storage;
+
+use glob::Pattern;
+use std::cmp;
+use std::cmp::Ordering;
+use std::io;
+
+// Lists one page of `request.path`. Everything that matches is sorted before
+// the page is cut, so that pages do not depend on the order of read_dir.
+pub fn list(storage: &dyn Storage, request: &ListRequest)
+    -> Result<FileListResponse, SrwscError> {
+    if !request.path.is_empty() {
+        storage::validate_name(&request.path)?;
+    }
+    let sort = SortKey::from_i32(request.sort)
+        .ok_or_else(|| invalid(format!("unknown sort key {}", request.sort)))?;
+    let pattern = match request.pattern.is_empty() {
+        true => None,
+        false => Some(Pattern::new(&request.pattern)
+                      .map_err(|e| invalid(format!("{:?}: {}", request.pattern, e)))?),
+    };
+    let last = match request.page_token.is_empty() {
+        true => None,
+        false => Some(parse_page_token(&request.page_token, sort, request.descending)
+                      .ok_or_else(|| invalid(String::from("invalid page token")))?),
+    };
+    let page_size = match request.page_size as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        n => cmp::min(n, MAX_PAGE_SIZE),
+    };
+
+    let mut files = storage.list(&request.path, request.recursive)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => SrwscError::new(ErrorCode::NotExistFile, e.to_string()),
+            _ => SrwscError::new(ErrorCode::ErrorRequest, e.to_string()),
+        })?;
+    if let Some(pattern) = &pattern {
+        files.retain(|f| pattern.matches(base_name(&f.name)));
+    }
+    let order = |a: &ServerFile, b: &ServerFile| match request.descending {
+        true => compare(sort, a, b).reverse(),
+        false => compare(sort, a, b),
+    };
+    files.sort_by(|a, b| order(a, b));
+
+    let start = match &last {
+        Some(last) => files.iter()
+            .position(|f| order(f, last) == Ordering::Greater)
+            .unwrap_or(files.len()),
+        None => 0,
+    };
+    let end = cmp::min(start + page_size, files.len());
+    let next_page_token = match end < files.len() {
+        true => page_token(sort, request.descending, &files[end - 1]),
+        false => String::new(),
+    };
+    Ok(FileListResponse {
+        files: files[start..end].iter().map(misc::file_info).collect(),
+        next_page_token: next_page_token,
+    })
+}
+
+fn invalid(message: String) -> SrwscError {
+    SrwscError::new(ErrorCode::InvalidArgument, message)
+}
+
+fn base_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+// Entries with the same key are ordered by name, which makes the order total:
+// names are unique within a listing.
+fn compare(sort: SortKey, a: &ServerFile, b: &ServerFile) -> Ordering {
+    let by_key = match sort {
+        SortKey::Name => Ordering::Equal,
+        SortKey::Size => a.size.cmp(&b.size),
+        SortKey::Mtime => a.mtime.cmp(&b.mtime),
+    };
+    by_key.then_with(|| a.name.cmp(&b.name))
+}
+
+// A page token names the last entry of the previous page, as
+// "SORT.DESCENDING.KEY.HEXNAME". The next page starts right after that entry,
+// even when entries were added or removed in the meantime.
+fn page_token(sort: SortKey, descending: bool, last: &ServerFile) -> String {
+    let key = match sort {
+        SortKey::Name => String::from("0"),
+        SortKey::Size => last.size.to_string(),
+        SortKey::Mtime => last.mtime.to_string(),
+    };
+    let name: String = last.name.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.{}.{}.{}", sort as i32, descending as u8, key, name)
+}
+
+// A token is only valid for the order it was issued for.
+fn parse_page_token(token: &str, sort: SortKey, descending: bool)
+    -> Option<ServerFile> {
+    let parts: Vec<&str> = token.splitn(4, '.').collect();
+    if parts.len() != 4
+        || parts[0] != (sort as i32).to_string()
+        || parts[1] != (descending as u8).to_string() {
+        return None;
+    }
+
+    let mut last = ServerFile::new();
+    match sort {
+        SortKey::Name => {},
+        SortKey::Size => last.size = parts[2].parse().ok()?,
+        SortKey::Mtime => last.mtime = parts[2].parse().ok()?,
+    }
+    let hex = parts[3].as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    last.name = String::from_utf8(bytes).ok()?;
+    Some(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    fn file(name: &str, size: u64, mtime: i64) -> ServerFile {
+        let mut f = ServerFile::new();
+        f.name = name.to_string();
+        f.size = size;
+        f.mtime = mtime;
+        f
+    }
+
+    #[test]
+    fn page_token_round_trips() {
+        let last = file("dir/b c.txt", 42, 1_600_000_000);
+        for &sort in &[SortKey::Name, SortKey::Size, SortKey::Mtime] {
+            for &descending in &[false, true] {
+                let token = page_token(sort, descending, &last);
+                let parsed = parse_page_token(&token, sort, descending).unwrap();
+                assert_eq!(parsed.name, last.name);
+                assert_eq!(compare(sort, &parsed, &last), Ordering::Equal);
+            }
+        }
+    }
+
+    #[test]
+    fn page_token_is_bound_to_its_order() {
+        let token = page_token(SortKey::Size, false, &file("a", 1, 0));
+        assert!(parse_page_token(&token, SortKey::Name, false).is_none());
+        assert!(parse_page_token(&token, SortKey::Size, true).is_none());
+    }
+
+    #[test]
+    fn malformed_page_tokens_are_rejected() {
+        for token in &["", "0.0.0", "0.0.0.6", "0.0.0.zz", "0.0.0.ff", "1.0.x.61", "0.2.0.61"] {
+            assert!(parse_page_token(token, SortKey::Name, false).is_none(), "{}", token);
+        }
+    }
+
+    #[test]
+    fn pages_cover_the_listing_once() {
+        let root = std::env::temp_dir().join(format!("srws-listing-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        for name in &["a", "b", "c", "d", "e"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let storage = LocalStorage::new(root.to_str().unwrap());
+
+        let mut request = ListRequest {
+            page_size: 2,
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        loop {
+            let page = list(&storage, &request).unwrap();
+            names.extend(page.files.into_iter().map(|f| f.name));
+            if page.next_page_token.is_empty() {
+                break;
+            }
+            request.page_token = page.next_page_token;
+        }
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(names, vec!["a", "b", "c", "d", "e"]);
+    }
+}
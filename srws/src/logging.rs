@@ -0,0 +1,64 @@
+use crate::config::ServerConfig;
+use crate::error::{SrwscError, ErrorCode};
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+
+// Every TCP connection and every gRPC call gets a session ID of its own, which
+// is in every event logged while serving it.
+static SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn next_session_id() -> u64 {
+    SESSION_ID.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+// Events up to log.level are written to stdout and, with log.file, appended to
+// that file as JSON lines, one object per event with the spans it happened in.
+pub fn init(c: &ServerConfig) -> Result<(), SrwscError> {
+    let level = c.log_level.parse::<LevelFilter>()
+        .map_err(|e| SrwscError::new(ErrorCode::InvalidArgument,
+                                     format!("log level {:?}: {}", c.log_level, e)))?;
+    let stdout = fmt::layer().with_target(false);
+    let installed = match &c.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                             format!("cannot open {}: {}", path, e)))?;
+            let file = Arc::new(file);
+            let json = fmt::layer()
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+                .with_ansi(false)
+                .with_writer(move || LogFile(file.clone()));
+            tracing::subscriber::set_global_default(
+                tracing_subscriber::registry().with(level).with(stdout).with(json))
+        },
+        None => tracing::subscriber::set_global_default(
+            tracing_subscriber::registry().with(level).with(stdout)),
+    };
+    installed.map_err(|e| SrwscError::new(ErrorCode::ErrorRequest, e.to_string()))
+}
+
+// Every event is a single write of one line, so sessions logging at the same
+// time do not mix their lines.
+struct LogFile(Arc<File>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
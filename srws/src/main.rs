@@ -1,100 +1,225 @@
+// The code spells out `field: field` in struct literals and `map_or(false, ..)`
+// for optional conditions, names the server types after the protocols, and
+// passes the session state to the transfer functions one by one.
+#![allow(clippy::redundant_field_names, clippy::unnecessary_map_or,
+         clippy::upper_case_acronyms, clippy::too_many_arguments)]
+
+mod audit;
+mod auth;
 mod config;
 mod error;
 mod http_server;
 mod grpc_server;
+mod listing;
+mod logging;
+mod metrics;
 mod misc;
+mod pb;
+mod permission;
+mod settings;
+mod shutdown;
+mod space;
+mod storage;
+mod tls;
+
+use srwsc_common::{checksum, compression, transfer};
+
+use audit::Audit;
+use metrics::Metrics;
+use space::Spaces;
 
 use std::process;
+use std::sync::Arc;
+use std::thread;
 use ace::App;
+use glob::Pattern;
+use tracing::{debug, error, info, warn};
 
 fn main() {
     let c = get_proc_info();
     match c {
         Some(info) => {
-            println!("{:?}", info);
+            if let Err(e) = logging::init(&info) {
+                println!("Unable to set up logging: {}", e);
+                process::exit(-1);
+            }
+            info!(version = config::VERSION,
+                  config_file = ?info.config_file,
+                  "Starting {:?} server", info.server_type);
+            debug!(config = ?info);
+            let spaces = Arc::new(Spaces::new(&info.storage, info.quota));
+            remove_orphans(&spaces);
+            let sweeping = spaces.clone();
+            thread::spawn(move || loop {
+                thread::sleep(config::ORPHAN_SWEEP_INTERVAL);
+                remove_orphans(&sweeping);
+            });
+            let metrics = Arc::new(Metrics::new(spaces.clone()));
+            if let Some(address) = info.metrics_address {
+                if let Err(e) = metrics::serve(metrics.clone(), address) {
+                    error!("Unable to serve metrics: {}", e);
+                    process::exit(-1);
+                }
+            }
+            let audit = match Audit::open(&info, metrics.clone()) {
+                Ok(audit) => Arc::new(audit),
+                Err(e) => {
+                    error!("Unable to open the audit log: {}", e);
+                    process::exit(-1);
+                },
+            };
             match &info.server_type {
-                config::ServerType::HTTP => { http_server::run(info); },
+                config::ServerType::HTTP |
+                config::ServerType::HTTPS => { http_server::run(info, spaces, metrics, audit); },
                 config::ServerType::GRPC => {
-                    match grpc_server::run(info) {
-                        Err(e) => println!("Grpc Server error with : {}", e),
-                        _ => {},
+                    if let Err(e) = grpc_server::run(info, spaces, metrics, audit) {
+                        error!("gRPC server failed: {}", e);
                     }
                 },
-                _ => println!("Not implement"),
             }
         },
         None => println!("argument is none"),
     }
 }
 
+// Interrupted uploads count against the quota until they are resumed, so the
+// abandoned ones are cleared out while the server runs as well.
+fn remove_orphans(spaces: &Spaces) {
+    match spaces.remove_orphans(config::ORPHAN_UPLOAD_AGE) {
+        Ok(0) => {},
+        Ok(n) => info!("Removed {} abandoned uploads", n),
+        Err(e) => warn!("Cannot clean up abandoned uploads: {}", e),
+    }
+}
+
 fn get_proc_info() -> Option<config::ServerConfig> {
     let app = App::new()
         .config(config::SERVER_NAME, config::VERSION)
         .cmd("start", "Start server with user config")
+        .cmd("passwd", "Set the password of a user (-u) in the credential file (-p)")
+        .cmd("token", "Create an API token for a user (-u) in the credential file (-p)")
+        .cmd("role", "Set the role (-o) of a user (-u) in the credential file (-p)")
+        .cmd("space", "Make a user (-u) work in a storage (-o) shared with others, in the credential file (-p)")
+        .cmd("audit", "Show the audit log, only for a user (-u) or file names (-n) if given")
         .cmd("help", "Print help information")
         .cmd("version", "Print version information")
+        .opt("-C", "Set the configuration file (default: srws.toml, ~/.config/srws/srws.toml, /etc/srws/srws.toml)")
         .opt("-t", "Set server type (Use one of http, https, grpc)")
         .opt("-a", "Set the binding address and port for server")
-        .opt("-r", "Set the root directory for srws");
+        .opt("-r", "Set the root directory for srws; users who log in get a directory of their own in it, or of their space")
+        .opt("-c", "Set the certificate chain file (PEM) for https or grpc")
+        .opt("-k", "Set the private key file (PEM) for https or grpc")
+        .opt("-m", "Set the client CA file (PEM) to require client certificates on grpc")
+        .opt("-i", "Set the file mapping client certificate subjects to identities")
+        .opt("-s", "Set the chunk size in bytes for grpc file streams")
+        .opt("-l", "Set the maximum number of concurrent sessions for http or https")
+        .opt("-g", "Set the grace period in seconds for transfers to finish on shutdown")
+        .opt("-p", "Set the credential file; clients have to log in when it is set")
+        .opt("-u", "Set the user name for passwd, token, role, space and audit")
+        .opt("-n", "Set the file name, or a glob pattern, for audit")
+        .opt("-o", "Set the role for role (Use one of read-only, upload-only, admin), or the storage for space")
+        .opt("-q", "Set the quota of every user in bytes")
+        .opt("-f", "Set the quota of every user in files");
 
     if let Some(cmd) = app.command() {
         match cmd.as_str() {
             "start" => {
+                let config_file = single_value(&app, "-C", "[CONFIG FILE]");
                 let mut c = config::ServerConfig::new();
-                let server_type = app
-                    .value("-t")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-t value: [SERVER TYPE(http, https, grpc)]");
+                let loaded = settings::load(&mut c, config_file)
+                    .and_then(|_| settings::load_options(&mut c, |opt| {
+                        app.value(opt).map(|values| values.into_iter().cloned().collect())
+                    }));
+                if let Err(e) = loaded {
+                    println!("Invalid configuration: {}", e.message());
+                    process::exit(-1);
+                }
+                Some(c)
+            }
+            "passwd" | "token" | "role" | "space" => {
+                let (path, user) = match (app.value("-p"), app.value("-u")) {
+                    (Some(path), Some(user)) if path.len() == 1 && user.len() == 1 => {
+                        (path[0].clone(), user[0].clone())
+                    },
+                    _ => {
+                        println!("{} needs -p [CREDENTIAL FILE] and -u [USER]", cmd);
+                        process::exit(-1);
+                    }
+                };
+                let added = match cmd.as_str() {
+                    "passwd" => read_new_password(&user)
+                        .and_then(|password| auth::hash_password(&password))
+                        .and_then(|secret| auth::add_credential(&path, &user, &secret))
+                        .map(|_| println!("Password of {} is added to {}", user, path)),
+                    "role" => match app.value("-o") {
+                        Some(role) if role.len() == 1 => role[0].parse::<permission::Role>()
+                            .map(auth::role_secret)
+                            .and_then(|secret| auth::add_credential(&path, &user, &secret))
+                            .map(|_| println!("Role of {} is set to {} in {}", user, role[0], path)),
+                        _ => {
+                            println!("role needs -o [ROLE(read-only, upload-only, admin)]");
                             process::exit(-1);
                         }
-                        values[0].clone()
-                    });
-                match server_type {
-                    Some(t) => {
-                        match t.as_str() {
-                            "http" => c.server_type = config::ServerType::HTTP,
-                            "https" => c.server_type = config::ServerType::HTTPS,
-                            "grpc" => c.server_type = config::ServerType::GRPC,
-                            _ => {
-                                println!("1 -t value: [SERVER TYPE(http, https, grpc)]");
-                                process::exit(-1);
-                            }
-                        }
                     },
-                    None => println!("Use default value for server type"),
-                }
-
-                let addr = app
-                    .value("-a")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-a value: [ADDRESS:PORT]");
+                    "space" => match app.value("-o") {
+                        Some(space) if space.len() == 1 => auth::space_secret(space[0])
+                            .and_then(|secret| auth::add_credential(&path, &user, &secret))
+                            .map(|_| println!("Space of {} is set to {} in {}", user, space[0], path)),
+                        _ => {
+                            println!("space needs -o [SPACE]");
                             process::exit(-1);
                         }
-                        values[0].clone()
-                    });
-                match addr {
-                    Some(a) => c.address = a.parse()
-                                            .expect("Unable to parse socket address"),
-                    None => println!("Use default value for binding address"),
+                    },
+                    _ => auth::new_token()
+                        .and_then(|(token, secret)| {
+                            auth::add_credential(&path, &user, &secret)?;
+                            println!("Token of {} is added to {}. It is shown only once:",
+                                     user, path);
+                            println!("{}", token);
+                            Ok(())
+                        }),
+                };
+                if let Err(e) = added {
+                    println!("{}", e);
+                    process::exit(-1);
                 }
-
-                let storage = app
-                    .value("-r")
-                    .map(|values| {
-                        if values.len() != 1 {
-                            println!("-r value: [DIR]");
-                            process::exit(-1);
+                None
+            }
+            "audit" => {
+                let mut c = config::ServerConfig::new();
+                if let Err(e) = settings::load(&mut c, single_value(&app, "-C", "[CONFIG FILE]")) {
+                    println!("Invalid configuration: {}", e.message());
+                    process::exit(-1);
+                }
+                let path = match &c.audit_file {
+                    Some(path) => path,
+                    None => {
+                        println!("No audit log, set audit.file or SRWS_AUDIT_FILE");
+                        process::exit(-1);
+                    },
+                };
+                let user = single_value(&app, "-u", "[USER]");
+                let pattern = single_value(&app, "-n", "[FILE NAME]").map(|name| {
+                    Pattern::new(&name).unwrap_or_else(|e| {
+                        println!("-n value {:?}: {}", name, e);
+                        process::exit(-1);
+                    })
+                });
+                match audit::query(path, user.as_deref(), pattern.as_ref()) {
+                    Ok((records, skipped)) => {
+                        for record in records {
+                            println!("{}", record);
+                        }
+                        if skipped > 0 {
+                            println!("Skipped {} lines that are not audit records", skipped);
                         }
-                        values[0].clone()
-                    });
-                match storage {
-                    Some(s) => c.storage = s,
-                    None => println!("Use default value for storage"),
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                        process::exit(-1);
+                    },
                 }
-
-                Some(c)
+                None
             }
             "help" => {
                 app.print_help();
@@ -113,3 +238,37 @@ fn get_proc_info() -> Option<config::ServerConfig> {
         None
     }
 }
+
+// The value of `opt`, which may be given once.
+fn single_value(app: &App, opt: &str, usage: &str) -> Option<String> {
+    app.value(opt).map(|values| {
+        if values.len() != 1 {
+            println!("{} value: {}", opt, usage);
+            process::exit(-1);
+        }
+        values[0].clone()
+    })
+}
+
+fn read_new_password(user: &str) -> Result<String, error::SrwscError> {
+    let read = |prompt: &str| {
+        rpassword::read_password_from_tty(Some(prompt)).map_err(|e| {
+            error::SrwscError::new(error::ErrorCode::ErrorAuth,
+                                   format!("cannot read the password: {}", e))
+        })
+    };
+    let password = read(&format!("New password for {}: ", user))?;
+    if password.is_empty() {
+        return Err(error::SrwscError::new(error::ErrorCode::ErrorAuth,
+                                          String::from("the password is empty")));
+    }
+    if !password.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return Err(error::SrwscError::new(error::ErrorCode::ErrorAuth,
+                                          String::from("the password has to be printable ASCII")));
+    }
+    if read("Retype the password: ")? != password {
+        return Err(error::SrwscError::new(error::ErrorCode::ErrorAuth,
+                                          String::from("the passwords do not match")));
+    }
+    Ok(password)
+}
@@ -0,0 +1,284 @@
+use crate::error::{SrwscError, ErrorCode};
+use crate::permission::Operation;
+use crate::space::Spaces;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Upper bounds of the buckets of the transfer durations, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+// A scraper that takes longer than this to send its request, or to read the
+// answer, is dropped.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    // The TCP protocol, with or without TLS.
+    Tcp,
+    Grpc,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Grpc => "grpc",
+        }
+    }
+}
+
+// Observations of both servers, kept from the start of srws on and read by
+// whatever scrapes metrics.address.
+pub struct Metrics {
+    spaces: Arc<Spaces>,
+    counters: Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    // Bytes as they went over the wire, after compression.
+    received_bytes: BTreeMap<Protocol, u64>,
+    sent_bytes: BTreeMap<Protocol, u64>,
+    active_sessions: BTreeMap<Protocol, u64>,
+    // By protocol, operation and result.
+    operations: BTreeMap<(Protocol, String, &'static str), u64>,
+    // By protocol and operation, get or put.
+    transfers: BTreeMap<(Protocol, String), Histogram>,
+    // Audit records that were lost.
+    audit_failures: u64,
+}
+
+// The bucket counts are cumulative, as they are exposed.
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, &bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// Counts a session as active until it is dropped.
+pub struct ActiveSession {
+    metrics: Arc<Metrics>,
+    protocol: Protocol,
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        let mut counters = self.metrics.counters.lock().unwrap();
+        if let Some(active) = counters.active_sessions.get_mut(&self.protocol) {
+            *active -= 1;
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(spaces: Arc<Spaces>) -> Self {
+        Metrics {
+            spaces: spaces,
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    pub fn session(self: &Arc<Self>, protocol: Protocol) -> ActiveSession {
+        *self.counters.lock().unwrap().active_sessions.entry(protocol).or_insert(0) += 1;
+        ActiveSession {
+            metrics: self.clone(),
+            protocol: protocol,
+        }
+    }
+
+    pub fn operation(&self, protocol: Protocol, operation: Operation, succeeded: bool) {
+        let result = match succeeded {
+            true => "ok",
+            false => "error",
+        };
+        let key = (protocol, operation.to_string(), result);
+        *self.counters.lock().unwrap().operations.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn received(&self, protocol: Protocol, bytes: u64) {
+        *self.counters.lock().unwrap().received_bytes.entry(protocol).or_insert(0) += bytes;
+    }
+
+    pub fn sent(&self, protocol: Protocol, bytes: u64) {
+        *self.counters.lock().unwrap().sent_bytes.entry(protocol).or_insert(0) += bytes;
+    }
+
+    // A transfer that went through; the ones cut off count as failed
+    // operations only.
+    pub fn transfer(&self, protocol: Protocol, operation: Operation, duration: Duration) {
+        self.counters.lock().unwrap()
+            .transfers.entry((protocol, operation.to_string()))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn audit_failed(&self) {
+        self.counters.lock().unwrap().audit_failures += 1;
+    }
+
+    // The Prometheus text format. Storage usage is counted on every scrape,
+    // for the storage of every user that has logged in since srws started.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        {
+            let counters = self.counters.lock().unwrap();
+            header(&mut out, "srws_received_bytes_total", "counter",
+                   "Bytes received in file transfers, as sent over the wire.");
+            for (protocol, bytes) in &counters.received_bytes {
+                sample(&mut out, "srws_received_bytes_total",
+                       &[("protocol", protocol.name())], *bytes as f64);
+            }
+            header(&mut out, "srws_sent_bytes_total", "counter",
+                   "Bytes sent in file transfers, as sent over the wire.");
+            for (protocol, bytes) in &counters.sent_bytes {
+                sample(&mut out, "srws_sent_bytes_total",
+                       &[("protocol", protocol.name())], *bytes as f64);
+            }
+            header(&mut out, "srws_active_sessions", "gauge",
+                   "TCP sessions connected and gRPC calls running.");
+            for (protocol, active) in &counters.active_sessions {
+                sample(&mut out, "srws_active_sessions",
+                       &[("protocol", protocol.name())], *active as f64);
+            }
+            header(&mut out, "srws_operations_total", "counter",
+                   "Commands and calls served, by operation and result.");
+            for ((protocol, operation, result), count) in &counters.operations {
+                sample(&mut out, "srws_operations_total",
+                       &[("protocol", protocol.name()),
+                         ("operation", operation.as_str()),
+                         ("result", *result)],
+                       *count as f64);
+            }
+            header(&mut out, "srws_transfer_duration_seconds", "histogram",
+                   "Time taken by the file transfers that went through.");
+            for ((protocol, operation), histogram) in &counters.transfers {
+                let labels = [("protocol", protocol.name()), ("operation", operation.as_str())];
+                for (&bound, &count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    let le = bound.to_string();
+                    sample(&mut out, "srws_transfer_duration_seconds_bucket",
+                           &[labels[0], labels[1], ("le", &le)], count as f64);
+                }
+                sample(&mut out, "srws_transfer_duration_seconds_bucket",
+                       &[labels[0], labels[1], ("le", "+Inf")], histogram.count as f64);
+                sample(&mut out, "srws_transfer_duration_seconds_sum", &labels, histogram.sum);
+                sample(&mut out, "srws_transfer_duration_seconds_count", &labels,
+                       histogram.count as f64);
+            }
+            header(&mut out, "srws_audit_write_failures_total", "counter",
+                   "Audit records that could not be written to audit.file.");
+            sample(&mut out, "srws_audit_write_failures_total", &[],
+                   counters.audit_failures as f64);
+        }
+
+        let mut usages = Vec::new();
+        for (user, space) in self.spaces.opened() {
+            match space.storage().usage() {
+                Ok(usage) => usages.push((user, usage)),
+                Err(e) => warn!(user = %user, "Cannot count usage: {}", e),
+            }
+        }
+        header(&mut out, "srws_storage_used_bytes", "gauge",
+               "Bytes stored, by user or shared space; empty without a credential file.");
+        for (user, usage) in &usages {
+            sample(&mut out, "srws_storage_used_bytes", &[("user", user.as_str())],
+                   usage.bytes as f64);
+        }
+        header(&mut out, "srws_storage_used_files", "gauge",
+               "Files stored, by user or shared space; empty without a credential file.");
+        for (user, usage) in &usages {
+            sample(&mut out, "srws_storage_used_files", &[("user", user.as_str())],
+                   usage.files as f64);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels.iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+        .collect();
+    match labels.is_empty() {
+        true => out.push_str(&format!("{} {}\n", name, value)),
+        false => out.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value)),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Answers GET /metrics on `address` from a thread of its own, which is simply
+// left behind on shutdown. Scrapes are few and quick, so they are answered one
+// after the other.
+pub fn serve(metrics: Arc<Metrics>, address: SocketAddr)
+    -> Result<(), SrwscError> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| SrwscError::new(ErrorCode::ErrorIo,
+                                     format!("cannot listen on {}: {}", address, e)))?;
+    info!(address = %address, "Serving metrics");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|mut stream| answer(&metrics, &mut stream)) {
+                debug!("Metrics scrape failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(metrics: &Metrics, stream: &mut TcpStream)
+    -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request is too large"));
+        }
+        match stream.read(&mut buf)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           "request ended early")),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Metrics are on /metrics\n")),
+        _ => ("405 Method Not Allowed", String::from("Only GET is supported\n")),
+    };
+    write!(stream,
+           "HTTP/1.1 {}\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
@@ -1,46 +1,224 @@
-use crate::config;
-
-use std::fs;
-
-pub fn get_file_list(storage: &str) -> String {
-    let mut msg = String::new();
-    for entry in fs::read_dir(storage).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if !path.is_dir() {
-            let fullpath = String::from(entry.path().to_string_lossy());
-            let filename = String::from(str::replace(&fullpath, storage, ""));
-            let file_name = &filename[1..];
-
-            let file = fs::File::open(fullpath).unwrap();
-            let file_size = file.metadata().unwrap().len();
-            let file_info = format!("{}  [{} bytes]", file_name, file_size);
-            msg.push_str(&file_info);
-            msg.push('\n');
+use crate::compression;
+use crate::compression::Compression;
+use crate::config::ServerFile;
+use crate::error::{SrwscError, ErrorCode};
+use crate::pb::{FileInfo, ListRequest, SortKey};
+use crate::permission::{Operation, Role};
+
+use std::panic;
+use tokio::task;
+use tracing::Span;
+
+pub fn file_info(f: &ServerFile) -> FileInfo {
+    FileInfo {
+        name: f.name.clone(),
+        size: f.size,
+        mtime: f.mtime,
+        mode: f.mode,
+        digest: f.digest.clone().unwrap_or_default(),
+        is_dir: f.is_dir,
+        partial_size: f.partial_size,
+        prefix_digest: f.prefix_digest.clone().unwrap_or_default(),
+    }
+}
+
+// What stat answers. A role that may not download the file learns whether
+// there is an interrupted upload of it to resume, but not its size or digest.
+pub fn stat_info(f: &ServerFile, role: Role) -> FileInfo {
+    let mut info = file_info(f);
+    if !role.allows(Operation::Get) {
+        info.size = 0;
+        info.digest = String::new();
+        info.prefix_digest = String::new();
+    }
+    info
+}
+
+// Parses "[-r] [-d] [-s name|size|mtime] [-p PATTERN] [-n COUNT] [-c TOKEN]
+// [DIR]" as sent with ls. Options come first, up to an optional "--"; the
+// rest of the line is the directory.
+pub fn list_args(args: &str) -> Result<ListRequest, String> {
+    let mut request = ListRequest::default();
+    let mut rest = args.trim_start();
+    while rest.starts_with('-') {
+        let (option, tail) = split_word(rest);
+        rest = tail;
+        match option {
+            "--" => break,
+            "-r" => request.recursive = true,
+            "-d" => request.descending = true,
+            "-s" | "-p" | "-n" | "-c" => {
+                let (value, tail) = split_word(rest);
+                if value.is_empty() {
+                    return Err(format!("{} needs a value", option));
+                }
+                rest = tail;
+                match option {
+                    "-s" => request.sort = sort_key(value)? as i32,
+                    "-p" => request.pattern = value.to_string(),
+                    "-n" => request.page_size = value.parse()
+                        .map_err(|_| format!("invalid page size {}", value))?,
+                    _ => request.page_token = value.to_string(),
+                }
+            },
+            _ => return Err(format!("unknown option {}", option)),
         }
     }
-    msg
+    request.path = rest.to_string();
+    Ok(request)
+}
+
+fn sort_key(name: &str) -> Result<SortKey, String> {
+    match name {
+        "name" => Ok(SortKey::Name),
+        "size" => Ok(SortKey::Size),
+        "mtime" => Ok(SortKey::Mtime),
+        _ => Err(format!("unknown sort key {}", name)),
+    }
 }
 
-pub fn check_file(file_name: &str, storage: &str) -> config::ServerFile {
-    let mut f = config::ServerFile::new();
+fn split_word(args: &str) -> (&str, &str) {
+    match args.find(' ') {
+        Some(index) => (&args[..index], args[index + 1..].trim_start()),
+        None => (args, ""),
+    }
+}
 
-    for entry in fs::read_dir(storage).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if !path.is_dir() {
-            let fullpath = String::from(entry.path().to_string_lossy());
-            let filename = String::from(str::replace(&fullpath, storage, ""));
+// Strips the "-z OFFER" a client puts in front of get and put arguments to
+// ask for compression. Without it the data goes uncompressed and no choice
+// is announced, as older clients expect.
+pub fn compression_args(args: &str) -> (Option<Compression>, &str) {
+    if !args.starts_with("-z ") {
+        return (None, args);
+    }
+    let (offer, rest) = split_word(args[3..].trim_start());
+    (Some(compression::negotiate(offer)), rest)
+}
 
-            if &filename[1..] == file_name {
-                let file = fs::File::open(&fullpath).unwrap();
-                f.size = file.metadata().unwrap().len();
-                f.name = file_name.to_string();
-                f.fullpath = fullpath;
-                break;
-            }
+// Splits "[START [LENGTH]] -- NAME" as sent with get and put. Without "--"
+// all of it is the name, so that names may hold spaces and numbers, and a
+// name that starts like a range is sent as "-- NAME".
+pub fn transfer_args(args: &str) -> (&str, u64, Option<u64>) {
+    let mut numbers = Vec::new();
+    let mut rest = args;
+    loop {
+        let (word, tail) = match rest.find(' ') {
+            Some(index) => (&rest[..index], &rest[index + 1..]),
+            None => (rest, ""),
+        };
+        if word == "--" {
+            return match numbers[..] {
+                [] => (tail, 0, None),
+                [start] => (tail, start, None),
+                [start, length] => (tail, start, Some(length)),
+                _ => (args, 0, None),
+            };
         }
+        match word.parse::<u64>() {
+            Ok(number) if numbers.len() < 2 => numbers.push(number),
+            _ => return (args, 0, None),
+        }
+        rest = tail;
     }
+}
+
+// Checks a requested range against the size of the file it reads from. No
+// length means everything from `offset` to the end of the file.
+pub fn in_range(offset: u64, length: Option<u64>, size: u64) -> bool {
+    match length {
+        Some(length) => offset.checked_add(length).map_or(false, |end| end <= size),
+        None => offset <= size,
+    }
+}
 
-    f
+// Where an upload of `length` bytes from `offset` ends, which is what its
+// reservation has to cover.
+pub fn upload_end(offset: u64, length: u64) -> Result<u64, SrwscError> {
+    offset.checked_add(length).ok_or_else(|| {
+        SrwscError::new(ErrorCode::InvalidArgument,
+                        format!("{} bytes from offset {} is too large", length, offset))
+    })
+}
+
+// Runs file system work on the blocking pool, in the span of the caller, so
+// that a session never holds up the others while it waits for the disk.
+pub async fn blocking<T, F>(f: F) -> T
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+    let span = Span::current();
+    match task::spawn_blocking(move || span.in_scope(f)).await {
+        Ok(value) => value,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => panic!("file task failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_hides_the_content_from_uploaders() {
+        let mut f = ServerFile::new();
+        f.size = 10;
+        f.digest = Some(String::from("digest"));
+        f.prefix_digest = Some(String::from("prefix"));
+        f.partial_size = 4;
+        let info = stat_info(&f, Role::UploadOnly);
+        assert_eq!((info.size, info.digest.as_str(), info.prefix_digest.as_str()), (0, "", ""));
+        assert_eq!(info.partial_size, 4);
+        let info = stat_info(&f, Role::ReadOnly);
+        assert_eq!((info.size, info.digest.as_str(), info.prefix_digest.as_str()),
+                   (10, "digest", "prefix"));
+    }
+
+    #[test]
+    fn ranges_inside_the_file() {
+        assert!(in_range(0, None, 0));
+        assert!(in_range(0, Some(10), 10));
+        assert!(in_range(4, Some(6), 10));
+        assert!(in_range(10, None, 10));
+        assert!(in_range(10, Some(0), 10));
+    }
+
+    #[test]
+    fn uploads_that_end_past_u64_are_rejected() {
+        assert_eq!(upload_end(10, 20).unwrap(), 30);
+        assert_eq!(upload_end(0, u64::MAX).unwrap(), u64::MAX);
+        assert!(matches!(upload_end(1, u64::MAX).unwrap_err().code(),
+                         ErrorCode::InvalidArgument));
+    }
+
+    #[test]
+    fn ranges_past_the_end() {
+        assert!(!in_range(11, None, 10));
+        assert!(!in_range(5, Some(6), 10));
+        assert!(!in_range(1, Some(u64::MAX), 10));
+        assert!(!in_range(u64::MAX, Some(1), u64::MAX));
+    }
+
+    #[test]
+    fn transfer_arguments() {
+        assert_eq!(transfer_args("a.txt"), ("a.txt", 0, None));
+        assert_eq!(transfer_args("-- a.txt"), ("a.txt", 0, None));
+        assert_eq!(transfer_args("5 -- a.txt"), ("a.txt", 5, None));
+        assert_eq!(transfer_args("5 10 -- a.txt"), ("a.txt", 5, Some(10)));
+        assert_eq!(transfer_args("release 2.txt"), ("release 2.txt", 0, None));
+    }
+
+    #[test]
+    fn names_ending_in_numbers_are_names() {
+        assert_eq!(transfer_args("report 2024"), ("report 2024", 0, None));
+        assert_eq!(transfer_args("a 1 2"), ("a 1 2", 0, None));
+        assert_eq!(transfer_args("-- report 2024"), ("report 2024", 0, None));
+        assert_eq!(transfer_args("1 2 -- a 1 2"), ("a 1 2", 1, Some(2)));
+    }
+
+    #[test]
+    fn names_that_look_like_ranges_follow_the_separator() {
+        assert_eq!(transfer_args("-- 5 -- a"), ("5 -- a", 0, None));
+        assert_eq!(transfer_args("-- --"), ("--", 0, None));
+        assert_eq!(transfer_args("1 2 3 -- a"), ("1 2 3 -- a", 0, None));
+        assert_eq!(transfer_args("5 a -- b"), ("5 a -- b", 0, None));
+    }
 }
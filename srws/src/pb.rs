@@ -0,0 +1,3 @@
+// Messages and services generated from proto/srwsc_pb.proto, shared by the
+// gRPC and the TCP protocol.
+tonic::include_proto!("srwsc.pb");
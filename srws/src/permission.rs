@@ -0,0 +1,173 @@
+use crate::error::{SrwscError, ErrorCode};
+
+use std::fmt;
+use std::str::FromStr;
+
+// What a user may do. Users without a role in the credential file are
+// read-only; everybody is an admin when nobody has to log in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Role {
+    // Downloads and looks around, for consumers of the files.
+    #[default]
+    ReadOnly,
+    // Uploads new files without seeing the other ones, for CI bots. Stat stays
+    // open so that interrupted uploads can be resumed, but leaves out the
+    // size and digest of the files.
+    UploadOnly,
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Get,
+    Put,
+    Remove,
+    List,
+    Stat,
+    MakeDir,
+    RemoveDir,
+    Quota,
+}
+
+impl Role {
+    pub fn allows(self, operation: Operation) -> bool {
+        matches!((self, operation),
+                 (Role::Admin, _) |
+                 (_, Operation::Stat) | (_, Operation::Quota) |
+                 (Role::ReadOnly, Operation::Get) | (Role::ReadOnly, Operation::List) |
+                 (Role::UploadOnly, Operation::Put) | (Role::UploadOnly, Operation::MakeDir))
+    }
+
+    pub fn check(self, operation: Operation) -> Result<(), SrwscError> {
+        if self.allows(operation) {
+            return Ok(());
+        }
+        Err(SrwscError::new(ErrorCode::PermissionDenied,
+                            format!("{} is not allowed for {} users", operation, self)))
+    }
+
+    // A put onto an existing file replaces it, which is as good as removing
+    // it.
+    pub fn may_replace(self) -> bool {
+        self.allows(Operation::Remove)
+    }
+
+    pub fn check_replace(self, name: &str) -> Result<(), SrwscError> {
+        if self.may_replace() {
+            return Ok(());
+        }
+        Err(SrwscError::new(ErrorCode::PermissionDenied,
+                            format!("{} exists, and {} users cannot replace files", name, self)))
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::ReadOnly => "read-only",
+            Role::UploadOnly => "upload-only",
+            Role::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Role {
+    type Err = SrwscError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "read-only" => Ok(Role::ReadOnly),
+            "upload-only" => Ok(Role::UploadOnly),
+            "admin" => Ok(Role::Admin),
+            _ => Err(SrwscError::new(ErrorCode::InvalidArgument,
+                                     format!("unknown role {:?}, use one of read-only, upload-only, admin",
+                                             name))),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Get => "get",
+            Operation::Put => "put",
+            Operation::Remove => "rm",
+            Operation::List => "ls",
+            Operation::Stat => "stat",
+            Operation::MakeDir => "mkdir",
+            Operation::RemoveDir => "rmdir",
+            Operation::Quota => "quota",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATIONS: [Operation; 8] = [
+        Operation::Get,
+        Operation::Put,
+        Operation::Remove,
+        Operation::List,
+        Operation::Stat,
+        Operation::MakeDir,
+        Operation::RemoveDir,
+        Operation::Quota,
+    ];
+
+    fn allowed(role: Role) -> Vec<Operation> {
+        OPERATIONS.iter().cloned().filter(|&operation| role.allows(operation)).collect()
+    }
+
+    #[test]
+    fn read_only_users_download_and_look_around() {
+        assert_eq!(allowed(Role::ReadOnly),
+                   vec![Operation::Get, Operation::List, Operation::Stat, Operation::Quota]);
+        assert!(!Role::ReadOnly.may_replace());
+    }
+
+    #[test]
+    fn upload_only_users_add_files() {
+        assert_eq!(allowed(Role::UploadOnly),
+                   vec![Operation::Put, Operation::Stat, Operation::MakeDir, Operation::Quota]);
+        assert!(!Role::UploadOnly.may_replace());
+    }
+
+    #[test]
+    fn admins_do_everything() {
+        assert_eq!(allowed(Role::Admin), OPERATIONS.to_vec());
+        assert!(Role::Admin.may_replace());
+    }
+
+    #[test]
+    fn users_without_a_role_are_read_only() {
+        assert_eq!(Role::default(), Role::ReadOnly);
+    }
+
+    #[test]
+    fn denials_are_permission_errors() {
+        for &role in &[Role::ReadOnly, Role::UploadOnly, Role::Admin] {
+            for &operation in &OPERATIONS {
+                match role.check(operation) {
+                    Ok(_) => assert!(role.allows(operation)),
+                    Err(e) => {
+                        assert!(!role.allows(operation));
+                        assert!(matches!(e.code(), ErrorCode::PermissionDenied));
+                    },
+                }
+            }
+            assert_eq!(role.check_replace("a").is_ok(), role.may_replace());
+        }
+    }
+
+    #[test]
+    fn roles_round_trip_through_their_names() {
+        for &role in &[Role::ReadOnly, Role::UploadOnly, Role::Admin] {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+}
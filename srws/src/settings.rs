@@ -0,0 +1,313 @@
+use crate::config::{ServerConfig, ServerType, MAX_CHUNK_SIZE, SERVER_NAME};
+use crate::error::{SrwscError, ErrorCode};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// srws takes its settings, each one overriding the ones before, from
+//   1. the defaults in config.rs,
+//   2. the configuration file,
+//   3. SRWS_* environment variables,
+//   4. the options of "srws start".
+// The configuration file is the one given with -C or in $SRWS_CONFIG, which
+// then has to exist, or else the first one found of
+//   ./srws.toml
+//   $XDG_CONFIG_HOME/srws/srws.toml, by default ~/.config/srws/srws.toml
+//   /etc/srws/srws.toml
+// It is TOML; every key is optional and shown here with the variable and the
+// option that override it:
+//   [server]
+//   type = "https"                    # SRWS_TYPE, -t: http, https or grpc
+//   address = "0.0.0.0:1417"          # SRWS_ADDRESS, -a
+//   storage = "/var/lib/srws"         # SRWS_STORAGE, -r
+//   [tls]
+//   cert = "/etc/srws/cert.pem"       # SRWS_CERT, -c
+//   key = "/etc/srws/key.pem"         # SRWS_KEY, -k
+//   client_ca = "/etc/srws/ca.pem"    # SRWS_CLIENT_CA, -m
+//   identity_map = "/etc/srws/ids"    # SRWS_IDENTITY_MAP, -i
+//   [auth]
+//   credentials = "/etc/srws/users"   # SRWS_CREDENTIALS, -p
+//   [limits]
+//   chunk_size = 65536                # SRWS_CHUNK_SIZE, -s
+//   max_sessions = 64                 # SRWS_MAX_SESSIONS, -l
+//   grace_period = 30                 # SRWS_GRACE_PERIOD, -g, in seconds
+//   quota_bytes = 1073741824          # SRWS_QUOTA_BYTES, -q
+//   quota_files = 10000               # SRWS_QUOTA_FILES, -f
+//   [log]
+//   level = "info"                    # SRWS_LOG_LEVEL: error, warn, info, debug or trace
+//   file = "/var/log/srws.json"       # SRWS_LOG_FILE, written as JSON lines
+//   [metrics]
+//   address = "127.0.0.1:9417"        # SRWS_METRICS_ADDRESS, served as /metrics over HTTP
+//   [audit]
+//   file = "/var/log/srws-audit.json" # SRWS_AUDIT_FILE, written as JSON lines
+//   max_size = 104857600              # SRWS_AUDIT_MAX_SIZE, rotated beyond it; 0 never rotates
+//   keep = 5                          # SRWS_AUDIT_KEEP, rotated files kept, at least 1
+const CONFIG_ENV: &str = "SRWS_CONFIG";
+const CONFIG_FILE: &str = "srws.toml";
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    opt: Option<&'static str>,
+    // What the value has to look like, for error messages.
+    usage: &'static str,
+    // False when the value is invalid.
+    apply: fn(&mut ServerConfig, &str) -> bool,
+}
+
+static SETTINGS: &[Setting] = &[
+    Setting {
+        key: "server.type", env: "SRWS_TYPE", opt: Some("-t"),
+        usage: "[SERVER TYPE(http, https, grpc)]",
+        apply: |c, value| {
+            c.server_type = match value {
+                "http" => ServerType::HTTP,
+                "https" => ServerType::HTTPS,
+                "grpc" => ServerType::GRPC,
+                _ => return false,
+            };
+            true
+        },
+    },
+    Setting {
+        key: "server.address", env: "SRWS_ADDRESS", opt: Some("-a"),
+        usage: "[ADDRESS:PORT]",
+        apply: |c, value| value.parse().map(|address| c.address = address).is_ok(),
+    },
+    Setting {
+        key: "server.storage", env: "SRWS_STORAGE", opt: Some("-r"),
+        usage: "[DIR]",
+        apply: |c, value| set_path(&mut c.storage, value),
+    },
+    Setting {
+        key: "tls.cert", env: "SRWS_CERT", opt: Some("-c"),
+        usage: "[CERT FILE]",
+        apply: |c, value| set_optional_path(&mut c.cert, value),
+    },
+    Setting {
+        key: "tls.key", env: "SRWS_KEY", opt: Some("-k"),
+        usage: "[KEY FILE]",
+        apply: |c, value| set_optional_path(&mut c.key, value),
+    },
+    Setting {
+        key: "tls.client_ca", env: "SRWS_CLIENT_CA", opt: Some("-m"),
+        usage: "[CLIENT CA FILE]",
+        apply: |c, value| set_optional_path(&mut c.client_ca, value),
+    },
+    Setting {
+        key: "tls.identity_map", env: "SRWS_IDENTITY_MAP", opt: Some("-i"),
+        usage: "[IDENTITY MAP FILE]",
+        apply: |c, value| set_optional_path(&mut c.identity_map, value),
+    },
+    Setting {
+        key: "auth.credentials", env: "SRWS_CREDENTIALS", opt: Some("-p"),
+        usage: "[CREDENTIAL FILE]",
+        apply: |c, value| set_optional_path(&mut c.credentials, value),
+    },
+    Setting {
+        key: "limits.chunk_size", env: "SRWS_CHUNK_SIZE", opt: Some("-s"),
+        usage: "[BYTES] between 1 and 1048576",
+        apply: |c, value| match value.parse::<usize>() {
+            Ok(size) if size > 0 && size <= MAX_CHUNK_SIZE => {
+                c.chunk_size = size;
+                true
+            },
+            _ => false,
+        },
+    },
+    Setting {
+        key: "limits.max_sessions", env: "SRWS_MAX_SESSIONS", opt: Some("-l"),
+        usage: "[SESSIONS] of at least 1",
+        apply: |c, value| match value.parse::<usize>() {
+            Ok(sessions) if sessions > 0 => {
+                c.max_sessions = sessions;
+                true
+            },
+            _ => false,
+        },
+    },
+    Setting {
+        key: "limits.grace_period", env: "SRWS_GRACE_PERIOD", opt: Some("-g"),
+        usage: "[SECONDS]",
+        apply: |c, value| value.parse()
+            .map(|seconds| c.grace_period = Duration::from_secs(seconds))
+            .is_ok(),
+    },
+    Setting {
+        key: "limits.quota_bytes", env: "SRWS_QUOTA_BYTES", opt: Some("-q"),
+        usage: "[BYTES]",
+        apply: |c, value| value.parse().map(|bytes| c.quota.bytes = Some(bytes)).is_ok(),
+    },
+    Setting {
+        key: "limits.quota_files", env: "SRWS_QUOTA_FILES", opt: Some("-f"),
+        usage: "[FILES]",
+        apply: |c, value| value.parse().map(|files| c.quota.files = Some(files)).is_ok(),
+    },
+    Setting {
+        key: "log.level", env: "SRWS_LOG_LEVEL", opt: None,
+        usage: "[LEVEL(error, warn, info, debug, trace)]",
+        apply: |c, value| {
+            if !LOG_LEVELS.contains(&value) {
+                return false;
+            }
+            c.log_level = value.to_string();
+            true
+        },
+    },
+    Setting {
+        key: "log.file", env: "SRWS_LOG_FILE", opt: None,
+        usage: "[LOG FILE]",
+        apply: |c, value| set_optional_path(&mut c.log_file, value),
+    },
+    Setting {
+        key: "metrics.address", env: "SRWS_METRICS_ADDRESS", opt: None,
+        usage: "[ADDRESS:PORT]",
+        apply: |c, value| value.parse()
+            .map(|address| c.metrics_address = Some(address))
+            .is_ok(),
+    },
+    Setting {
+        key: "audit.file", env: "SRWS_AUDIT_FILE", opt: None,
+        usage: "[AUDIT LOG FILE]",
+        apply: |c, value| set_optional_path(&mut c.audit_file, value),
+    },
+    Setting {
+        key: "audit.max_size", env: "SRWS_AUDIT_MAX_SIZE", opt: None,
+        usage: "[BYTES]",
+        apply: |c, value| value.parse().map(|bytes| c.audit_max_size = bytes).is_ok(),
+    },
+    Setting {
+        key: "audit.keep", env: "SRWS_AUDIT_KEEP", opt: None,
+        usage: "[FILES] of at least 1",
+        apply: |c, value| match value.parse::<usize>() {
+            Ok(files) if files > 0 => {
+                c.audit_keep = files;
+                true
+            },
+            _ => false,
+        },
+    },
+];
+
+fn set_path(path: &mut String, value: &str) -> bool {
+    if value.is_empty() {
+        return false;
+    }
+    *path = value.to_string();
+    true
+}
+
+fn set_optional_path(path: &mut Option<String>, value: &str) -> bool {
+    if value.is_empty() {
+        return false;
+    }
+    *path = Some(value.to_string());
+    true
+}
+
+fn invalid(message: String) -> SrwscError {
+    SrwscError::new(ErrorCode::InvalidArgument, message)
+}
+
+// Applies the configuration file, `path` or else the first one on the search
+// path, and then the environment.
+pub fn load(c: &mut ServerConfig, path: Option<String>) -> Result<(), SrwscError> {
+    let path = path.or_else(|| env::var(CONFIG_ENV).ok());
+    let file = match path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => search_path().into_iter().find(|path| path.is_file()),
+    };
+    if let Some(file) = file {
+        load_file(c, &file)?;
+        c.config_file = Some(file.to_string_lossy().into_owned());
+    }
+    load_env(c)
+}
+
+fn search_path() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join(SERVER_NAME).join(CONFIG_FILE));
+    }
+    paths.push(PathBuf::from("/etc").join(SERVER_NAME).join(CONFIG_FILE));
+    paths
+}
+
+fn load_file(c: &mut ServerConfig, path: &PathBuf) -> Result<(), SrwscError> {
+    let name = path.display();
+    let content = fs::read_to_string(path)
+        .map_err(|e| invalid(format!("cannot read {}: {}", name, e)))?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(invalid(format!("{}: expected a table", name))),
+        Err(e) => return Err(invalid(format!("{}: {}", name, e))),
+    };
+
+    for (section, values) in &table {
+        let values = match values {
+            toml::Value::Table(values) => values,
+            _ => return Err(invalid(format!("{}: {} is not a section, expected one of \
+                                             [server], [tls], [auth], [limits], [log], \
+                                             [metrics], [audit]",
+                                            name, section))),
+        };
+        for (key, value) in values {
+            let key = format!("{}.{}", section, key);
+            let setting = SETTINGS.iter()
+                .find(|setting| setting.key == key)
+                .ok_or_else(|| invalid(format!("{}: unknown setting {}", name, key)))?;
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                _ => return Err(invalid(format!("{}: {} has to be a string or a number",
+                                                name, key))),
+            };
+            if !(setting.apply)(c, &value) {
+                return Err(invalid(format!("{}: {} = {:?}, expected {}",
+                                           name, key, value, setting.usage)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_env(c: &mut ServerConfig) -> Result<(), SrwscError> {
+    for setting in SETTINGS {
+        let value = match env::var(setting.env) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => continue,
+            Err(e) => return Err(invalid(format!("{}: {}", setting.env, e))),
+        };
+        if !(setting.apply)(c, &value) {
+            return Err(invalid(format!("{}={:?}, expected {}",
+                                       setting.env, value, setting.usage)));
+        }
+    }
+    Ok(())
+}
+
+// Applies the options of "srws start"; `values` returns the values given for
+// an option.
+pub fn load_options<F>(c: &mut ServerConfig, values: F) -> Result<(), SrwscError>
+    where F: Fn(&str) -> Option<Vec<String>> {
+    for setting in SETTINGS {
+        let opt = match setting.opt {
+            Some(opt) => opt,
+            None => continue,
+        };
+        let values = match values(opt) {
+            Some(values) => values,
+            None => continue,
+        };
+        if values.len() != 1 || !(setting.apply)(c, &values[0]) {
+            return Err(invalid(format!("{} value: {}", opt, setting.usage)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use futures::future;
+use tracing::{error, info};
+
+// Resolves once the server is asked to stop with SIGINT or SIGTERM.
+#[cfg(unix)]
+pub async fn requested() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let signals = signal(SignalKind::interrupt())
+        .and_then(|interrupt| signal(SignalKind::terminate())
+                  .map(|terminate| (interrupt, terminate)));
+    let (mut interrupt, mut terminate) = match signals {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Unable to listen for shutdown signals: {}", e);
+            return future::pending().await;
+        },
+    };
+    tokio::select! {
+        _ = interrupt.recv() => info!("SIGINT received"),
+        _ = terminate.recv() => info!("SIGTERM received"),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn requested() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for shutdown signals: {}", e);
+        future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::{SrwscError, ErrorCode};
+use crate::pb::QuotaInfo;
+use crate::storage;
+use crate::storage::{LocalStorage, Storage, Usage};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Limits for the files of one user; None is no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+}
+
+// The storage of one user, or of everybody when nobody has to log in, along
+// with the space promised to the uploads running in it.
+pub struct Space {
+    storage: Arc<dyn Storage>,
+    quota: Quota,
+    reserved: Mutex<Usage>,
+}
+
+impl Space {
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub fn has_quota(&self) -> bool {
+        self.quota.bytes.is_some() || self.quota.files.is_some()
+    }
+
+    pub fn info(&self) -> io::Result<QuotaInfo> {
+        let used = self.storage.usage()?;
+        let left = self.quota.bytes.map(|bytes| bytes.saturating_sub(used.bytes));
+        let available_bytes = match (left, self.storage.free_space()?) {
+            (Some(left), Some(free)) => cmp::min(left, free),
+            (Some(bytes), None) | (None, Some(bytes)) => bytes,
+            (None, None) => u64::MAX,
+        };
+        Ok(QuotaInfo {
+            used_bytes: used.bytes,
+            used_files: used.files,
+            quota_bytes: self.quota.bytes.unwrap_or(0),
+            quota_files: self.quota.files.unwrap_or(0),
+            available_bytes: available_bytes,
+        })
+    }
+
+    // Checks that `name` can grow to `size` bytes within the quota, counting
+    // the uploads still running, and holds the space until the reservation is
+    // dropped. Usage is taken from the files themselves every time, so that
+    // it stays right when they are changed behind the server's back.
+    pub fn reserve(self: &Arc<Self>, name: &str, size: u64)
+        -> Result<Reservation, SrwscError> {
+        let mut reserved = self.reserved.lock().unwrap();
+        if self.has_quota() {
+            let used = self.storage.usage().map_err(|e| {
+                SrwscError::new(ErrorCode::ErrorRequest,
+                                format!("cannot count usage: {}", e))
+            })?;
+            // The upload takes the place of the file and of its own
+            // interrupted upload, if there are any.
+            let replaced = self.storage.stat(name).ok();
+            let resumed = self.storage.partial(name).ok().flatten().unwrap_or(0);
+            let bytes = used.bytes
+                .saturating_sub(replaced.as_ref().map_or(0, |f| f.size))
+                .saturating_sub(resumed)
+                + reserved.bytes + size;
+            let files = used.files.saturating_sub(replaced.as_ref().map_or(0, |_| 1))
+                + reserved.files + 1;
+            if let Some(limit) = self.quota.bytes.filter(|&limit| bytes > limit) {
+                return Err(SrwscError::new(ErrorCode::QuotaExceeded,
+                                           format!("{} would take {} of {} bytes",
+                                                   name, bytes, limit)));
+            }
+            if let Some(limit) = self.quota.files.filter(|&limit| files > limit) {
+                return Err(SrwscError::new(ErrorCode::QuotaExceeded,
+                                           format!("{} would make {} of {} files",
+                                                   name, files, limit)));
+            }
+        }
+        reserved.bytes += size;
+        reserved.files += 1;
+        Ok(Reservation {
+            space: self.clone(),
+            usage: Usage { bytes: size, files: 1 },
+        })
+    }
+}
+
+pub struct Reservation {
+    space: Arc<Space>,
+    usage: Usage,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut reserved = self.space.reserved.lock().unwrap();
+        reserved.bytes -= self.usage.bytes;
+        reserved.files -= self.usage.files;
+    }
+}
+
+// Hands out the storages: once users log in, the directory under the storage
+// root named after the user, or after the space the credential file puts the
+// user in; the root itself otherwise. Users in the same space share its
+// files and its quota, and every storage gets the same quota.
+pub struct Spaces {
+    root: PathBuf,
+    quota: Quota,
+    spaces: Mutex<HashMap<String, Arc<Space>>>,
+}
+
+impl Spaces {
+    pub fn new(root: &str, quota: Quota) -> Self {
+        Spaces {
+            root: PathBuf::from(root),
+            quota: quota,
+            spaces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, name: Option<&str>) -> io::Result<Arc<Space>> {
+        let root = match name {
+            Some(name) => {
+                if name.contains('/') || storage::validate_name(name).is_err() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("no storage named {:?}", name)));
+                }
+                self.root.join(name)
+            },
+            None => self.root.clone(),
+        };
+        let key = String::from(name.unwrap_or(""));
+        let mut spaces = self.spaces.lock().unwrap();
+        if let Some(space) = spaces.get(&key) {
+            return Ok(space.clone());
+        }
+
+        fs::create_dir_all(&root)?;
+        let storage = LocalStorage::new(&root.to_string_lossy());
+        let space = Arc::new(Space {
+            storage: Arc::new(storage),
+            quota: self.quota,
+            reserved: Mutex::new(Usage::default()),
+        });
+        spaces.insert(key, space.clone());
+        Ok(space)
+    }
+
+    // Removes the interrupted uploads older than `age` from the storage root
+    // and from the storage of every user. A storage handed out already keeps
+    // the uploads running in it; none is handed out while this runs.
+    pub fn remove_orphans(&self, age: Duration) -> io::Result<usize> {
+        let spaces = self.spaces.lock().unwrap();
+        let mut roots = vec![(String::new(), self.root.clone())];
+        if self.root.is_dir() {
+            for entry in fs::read_dir(&self.root)? {
+                let entry = entry?;
+                if entry.path().join(storage::META_DIR).is_dir() {
+                    roots.push((entry.file_name().to_string_lossy().to_string(), entry.path()));
+                }
+            }
+        }
+        let mut removed = 0;
+        for (key, root) in roots {
+            removed += match spaces.get(&key) {
+                Some(space) => space.storage().remove_orphans(age)?,
+                None => LocalStorage::new(&root.to_string_lossy()).remove_orphans(age)?,
+            };
+        }
+        Ok(removed)
+    }
+
+    // Every storage handed out so far, by name; the one of everybody is "".
+    pub fn opened(&self) -> Vec<(String, Arc<Space>)> {
+        self.spaces.lock().unwrap()
+            .iter()
+            .map(|(user, space)| (user.clone(), space.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,711 @@
+use crate::checksum;
+use crate::checksum::Checksum;
+use crate::config::ServerFile;
+use crate::error::{SrwscError, ErrorCode};
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Kept in the storage root for the server's own bookkeeping; never listed and
+// never reachable by clients.
+pub const META_DIR: &str = ".srws";
+const DIGEST_DIR: &str = "digests";
+const UPLOAD_DIR: &str = "uploads";
+
+// Files modified before the epoch get a negative time; filesystems without
+// modification times report the epoch itself.
+fn mtime(metadata: &fs::Metadata) -> i64 {
+    match metadata.modified() {
+        Ok(time) => match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        },
+        Err(_) => 0,
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+// Without Unix permissions only the read-only flag is known.
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+// Every name received from a client goes through here before it reaches a
+// storage backend, so that it can never escape the storage root.
+pub fn validate_name(name: &str)
+    -> Result<(), SrwscError> {
+    if name.is_empty() {
+        return Err(SrwscError::new(ErrorCode::InvalidFileName,
+                                   String::from("empty file name")));
+    }
+    if name.chars().any(|c| c == '\0' || c.is_control()) {
+        return Err(SrwscError::new(ErrorCode::InvalidFileName,
+                                   format!("{:?} contains control characters", name)));
+    }
+    if Path::new(name).starts_with(META_DIR) {
+        return Err(SrwscError::new(ErrorCode::InvalidFileName,
+                                   format!("{:?} is reserved", name)));
+    }
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(_) => {},
+            _ => return Err(SrwscError::new(ErrorCode::InvalidFileName,
+                                            format!("{:?} must be a relative path \
+                                                     without \".\" or \"..\"", name))),
+        }
+    }
+    Ok(())
+}
+
+// Space taken by the files of a storage, not counting directories and the
+// server's own bookkeeping.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+// An upload in progress. Nothing shows up under the final name until commit;
+// dropping an upload without committing it keeps the written data, so that
+// an interrupted upload can be resumed.
+pub trait Upload: Write + Send {
+    // Puts the upload in place. Without `replace` it fails with AlreadyExists
+    // when the name has been taken in the meantime, and the upload is thrown
+    // away.
+    fn commit(self: Box<Self>, replace: bool) -> io::Result<()>;
+    // Throws the written data away, for an upload that turned out wrong.
+    fn discard(self: Box<Self>);
+    // Reads what was written so far from the start, which a resumed upload
+    // has to hash before it goes on.
+    fn read_back(&self) -> io::Result<Box<dyn Read + Send>>;
+}
+
+pub trait Storage: Send + Sync {
+    fn open(&self, name: &str, offset: u64) -> io::Result<Box<dyn Read + Send>>;
+    // The file itself at `offset`, for backends that keep it on a local
+    // filesystem, so that it can be sent with sendfile.
+    fn open_file(&self, name: &str, offset: u64) -> io::Result<Option<File>>;
+    fn create(&self, name: &str, offset: u64) -> io::Result<Box<dyn Upload>>;
+    fn stat(&self, name: &str) -> io::Result<ServerFile>;
+    // Bytes kept from an interrupted upload of `name`, if there is one.
+    fn partial(&self, name: &str) -> io::Result<Option<u64>>;
+    fn list(&self, dir: &str, recursive: bool) -> io::Result<Vec<ServerFile>>;
+    fn remove(&self, name: &str) -> io::Result<()>;
+    fn mkdir(&self, name: &str) -> io::Result<()>;
+    fn rmdir(&self, name: &str) -> io::Result<()>;
+    fn set_digest(&self, name: &str, digest: &str) -> io::Result<()>;
+    // Space taken by the files, and by the interrupted uploads kept for
+    // resuming, which count as bytes but not as files.
+    fn usage(&self) -> io::Result<Usage>;
+    // Removes the interrupted uploads that nobody resumed within `age`.
+    fn remove_orphans(&self, age: Duration) -> io::Result<usize>;
+    // Bytes that can still be written, if the backend knows.
+    fn free_space(&self) -> io::Result<Option<u64>>;
+}
+
+// Files put into the storage directory by other means have no digest yet; it
+// is computed on first request and kept from then on.
+pub fn stat_with_digest(storage: &dyn Storage, name: &str)
+    -> io::Result<ServerFile> {
+    let mut f = storage.stat(name)?;
+    if f.digest.is_none() {
+        let digest = checksum::file_digest(&mut storage.open(name, 0)?)?;
+        storage.set_digest(name, &digest)?;
+        f.digest = Some(digest);
+    }
+    Ok(f)
+}
+
+// What stat answers: the file along with what is left of an interrupted
+// upload of it. A name that only has an interrupted upload is reported with
+// no data of its own, so that the upload can still be resumed. A `prefix`
+// no longer than the file gets the digest of that many first bytes, which a
+// client compares with its partial download before resuming it.
+pub fn stat_for_resume(storage: &dyn Storage, name: &str, prefix: u64)
+    -> io::Result<ServerFile> {
+    let partial = storage.partial(name)?;
+    let mut f = match stat_with_digest(storage, name) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && partial.is_some() => {
+            let mut f = ServerFile::new();
+            f.name = name.to_string();
+            f
+        },
+        Err(e) => return Err(e),
+    };
+    f.partial_size = partial.unwrap_or(0);
+    if prefix > 0 && prefix <= f.size {
+        f.prefix_digest = match prefix == f.size {
+            true => f.digest.clone(),
+            false => {
+                let mut checksum = Checksum::new();
+                checksum.update_from(&mut storage.open(name, 0)?, prefix)?;
+                Some(checksum.finish())
+            },
+        };
+    }
+    Ok(f)
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+    // Temporary files of the uploads running now; a name is uploaded by one
+    // session at a time.
+    running: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        LocalStorage {
+            root: PathBuf::from(root),
+            running: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // Every name has one temporary file, so that an interrupted upload is
+    // found again by the one resuming it. Names are hashed to keep them flat
+    // and short.
+    fn upload_path(&self, name: &str) -> PathBuf {
+        let mut key = Checksum::new();
+        key.update(name.as_bytes());
+        self.root.join(META_DIR).join(UPLOAD_DIR).join(key.finish())
+    }
+
+    // A non-zero offset resumes either the interrupted upload of the name,
+    // when that stopped right at the offset, or the file already there, whose
+    // first `offset` bytes are carried over.
+    fn open_upload(&self, name: &str, fullpath: &Path, temp: &Path, offset: u64)
+        -> io::Result<File> {
+        if offset > 0 && fs::metadata(temp).map_or(false, |m| m.len() == offset) {
+            let mut file = OpenOptions::new().write(true).open(temp)?;
+            file.seek(SeekFrom::End(0))?;
+            return Ok(file);
+        }
+
+        let mut prefix = None;
+        if offset > 0 {
+            let file = File::open(fullpath)?;
+            if offset > file.metadata()?.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("offset {} is beyond the end of {}",
+                                                  offset, name)));
+            }
+            prefix = Some(file);
+        }
+        let mut file = File::create(temp)?;
+        if let Some(prefix) = prefix {
+            let copied = io::copy(&mut prefix.take(offset), &mut file)?;
+            if copied != offset {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("{} shrank while resuming", name)));
+            }
+        }
+        Ok(file)
+    }
+
+    fn fullpath(&self, name: &str) -> io::Result<PathBuf> {
+        match validate_name(name) {
+            Ok(_) => Ok(self.root.join(name)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                         format!("{:?}", e))),
+        }
+    }
+
+    // Digests are stored under META_DIR at the same relative path as the file
+    // they belong to.
+    fn digest_path(&self, name: &str) -> PathBuf {
+        self.root.join(META_DIR).join(DIGEST_DIR).join(name)
+    }
+
+    fn read_digest(&self, name: &str) -> Option<String> {
+        fs::read_to_string(self.digest_path(name)).ok()
+    }
+
+    // An empty directory name stands for the storage root.
+    fn dirpath(&self, dir: &str) -> io::Result<PathBuf> {
+        if dir.is_empty() {
+            Ok(self.root.clone())
+        } else {
+            self.fullpath(dir)
+        }
+    }
+
+    fn relative_name(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.root) {
+            Ok(name) => String::from(name.to_string_lossy()),
+            Err(_) => String::from(path.to_string_lossy()),
+        }
+    }
+
+    fn list_dir(&self,
+                dir: &Path,
+                prefix: &str,
+                recursive: bool,
+                files: &mut Vec<ServerFile>)
+        -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path() == self.root.join(META_DIR) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let mut f = ServerFile::new();
+            f.fullpath = String::from(entry.path().to_string_lossy());
+            f.name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            f.is_dir = metadata.is_dir();
+            f.mtime = mtime(&metadata);
+            f.mode = mode(&metadata);
+            if !f.is_dir {
+                f.size = metadata.len();
+                f.digest = self.read_digest(&self.relative_name(&entry.path()));
+            }
+            let name = f.name.clone();
+            files.push(f);
+
+            if metadata.is_dir() && recursive {
+                let prefix = format!("{}/", name);
+                self.list_dir(&entry.path(), &prefix, recursive, files)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dir_usage(&self, dir: &Path, usage: &mut Usage) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path() == self.root.join(META_DIR) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.dir_usage(&entry.path(), usage)?;
+            } else {
+                usage.bytes += metadata.len();
+                usage.files += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Bytes of the interrupted uploads. The running ones are left out: their
+    // reservation already holds the space they grow into.
+    fn kept_uploads(&self) -> io::Result<u64> {
+        let upload_dir = self.root.join(META_DIR).join(UPLOAD_DIR);
+        if !upload_dir.is_dir() {
+            return Ok(0);
+        }
+        let running = self.running.lock().unwrap();
+        let mut bytes = 0;
+        for entry in fs::read_dir(upload_dir)? {
+            let entry = entry?;
+            if !running.contains(&entry.path()) {
+                bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+impl Storage for LocalStorage {
+    fn open(&self, name: &str, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.fullpath(name)?)?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(Box::new(file))
+    }
+
+    fn open_file(&self, name: &str, offset: u64) -> io::Result<Option<File>> {
+        let mut file = File::open(self.fullpath(name)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Some(file))
+    }
+
+    // Uploads are written to a temporary file in META_DIR and renamed over
+    // the final name on commit.
+    fn create(&self, name: &str, offset: u64) -> io::Result<Box<dyn Upload>> {
+        let fullpath = self.fullpath(name)?;
+        match fullpath.parent() {
+            Some(parent) if parent.is_dir() => {},
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                           format!("no directory for {}", name))),
+        }
+
+        let temp = self.upload_path(name);
+        if let Some(upload_dir) = temp.parent() {
+            fs::create_dir_all(upload_dir)?;
+        }
+        if !self.running.lock().unwrap().insert(temp.clone()) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} is being uploaded already", name)));
+        }
+        let file = match self.open_upload(name, &fullpath, &temp, offset) {
+            Ok(file) => file,
+            Err(e) => {
+                self.running.lock().unwrap().remove(&temp);
+                return Err(e);
+            },
+        };
+        Ok(Box::new(LocalUpload {
+            file: file,
+            temp: temp,
+            fullpath: fullpath,
+            digest_path: self.digest_path(name),
+            running: self.running.clone(),
+        }))
+    }
+
+    fn stat(&self, name: &str) -> io::Result<ServerFile> {
+        let fullpath = self.fullpath(name)?;
+        let metadata = fs::metadata(&fullpath)?;
+        if metadata.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} is a directory", name)));
+        }
+
+        let mut f = ServerFile::new();
+        f.fullpath = String::from(fullpath.to_string_lossy());
+        f.name = name.to_string();
+        f.size = metadata.len();
+        f.mtime = mtime(&metadata);
+        f.mode = mode(&metadata);
+        f.digest = self.read_digest(name);
+        Ok(f)
+    }
+
+    fn partial(&self, name: &str) -> io::Result<Option<u64>> {
+        self.fullpath(name)?;
+        match fs::metadata(self.upload_path(name)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, dir: &str, recursive: bool) -> io::Result<Vec<ServerFile>> {
+        let mut files = Vec::new();
+        self.list_dir(&self.dirpath(dir)?, "", recursive, &mut files)?;
+        Ok(files)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.stat(name)?;
+        fs::remove_file(self.fullpath(name)?)?;
+        let _ = fs::remove_file(self.digest_path(name));
+        Ok(())
+    }
+
+    fn mkdir(&self, name: &str) -> io::Result<()> {
+        fs::create_dir(self.fullpath(name)?)
+    }
+
+    fn rmdir(&self, name: &str) -> io::Result<()> {
+        let fullpath = self.fullpath(name)?;
+        if !fs::metadata(&fullpath)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} is not a directory", name)));
+        }
+        fs::remove_dir(fullpath)?;
+        let _ = fs::remove_dir(self.digest_path(name));
+        Ok(())
+    }
+
+    fn set_digest(&self, name: &str, digest: &str) -> io::Result<()> {
+        self.fullpath(name)?;
+        let digest_path = self.digest_path(name);
+        if let Some(parent) = digest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(digest_path, digest)
+    }
+
+    fn usage(&self) -> io::Result<Usage> {
+        let mut usage = Usage::default();
+        self.dir_usage(&self.root, &mut usage)?;
+        usage.bytes += self.kept_uploads()?;
+        Ok(usage)
+    }
+
+    fn remove_orphans(&self, age: Duration) -> io::Result<usize> {
+        let upload_dir = self.root.join(META_DIR).join(UPLOAD_DIR);
+        if !upload_dir.is_dir() {
+            return Ok(0);
+        }
+        let running = self.running.lock().unwrap();
+        let mut removed = 0;
+        for entry in fs::read_dir(upload_dir)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            let recent = SystemTime::now().duration_since(modified).map_or(true, |since| since < age);
+            if recent || running.contains(&entry.path()) {
+                continue;
+            }
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    fn free_space(&self) -> io::Result<Option<u64>> {
+        free_space(&self.root)
+    }
+}
+
+struct LocalUpload {
+    file: File,
+    temp: PathBuf,
+    fullpath: PathBuf,
+    digest_path: PathBuf,
+    running: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Write for LocalUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Upload for LocalUpload {
+    fn commit(self: Box<Self>, replace: bool) -> io::Result<()> {
+        self.file.sync_all()?;
+        if !replace {
+            // Unlike a rename, a link does not take over an existing name.
+            if let Err(e) = fs::hard_link(&self.temp, &self.fullpath) {
+                if e.kind() == io::ErrorKind::AlreadyExists {
+                    let _ = fs::remove_file(&self.temp);
+                }
+                return Err(e);
+            }
+            let _ = fs::remove_file(&self.digest_path);
+            return fs::remove_file(&self.temp);
+        }
+        let _ = fs::remove_file(&self.digest_path);
+        fs::rename(&self.temp, &self.fullpath)
+    }
+
+    fn discard(self: Box<Self>) {
+        let _ = fs::remove_file(&self.temp);
+    }
+
+    fn read_back(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(&self.temp)?))
+    }
+}
+
+// The temporary file stays behind to be resumed, or removed by
+// remove_orphans.
+impl Drop for LocalUpload {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.temp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A storage in a directory of its own, removed when dropped.
+    struct TestStorage {
+        storage: LocalStorage,
+        root: PathBuf,
+    }
+
+    impl TestStorage {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir()
+                .join(format!("srws-storage-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            TestStorage {
+                storage: LocalStorage::new(root.to_str().unwrap()),
+                root: root,
+            }
+        }
+    }
+
+    impl Drop for TestStorage {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn read(storage: &dyn Storage, name: &str) -> String {
+        let mut content = String::new();
+        storage.open(name, 0).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn plain_names_are_valid() {
+        for name in &["a", "a.txt", "dir/a.txt", "a b", ".hidden", "dir/.srws", "..a"] {
+            assert!(validate_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn escaping_and_reserved_names_are_rejected() {
+        for name in &["", "..", "../a", "dir/../a", "dir/..", "/a", "/etc/passwd", "./a",
+                      ".srws", ".srws/uploads/x", "a\0b", "a\nb", "a\rb", "a\u{7f}b"] {
+            assert!(validate_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn upload_shows_up_on_commit() {
+        let t = TestStorage::new("commit");
+        let mut upload = t.storage.create("a", 0).unwrap();
+        upload.write_all(b"hello").unwrap();
+        assert!(t.storage.stat("a").is_err());
+        upload.commit(true).unwrap();
+        assert_eq!(read(&t.storage, "a"), "hello");
+        assert_eq!(t.storage.partial("a").unwrap(), None);
+    }
+
+    #[test]
+    fn commit_without_replace_keeps_an_existing_file() {
+        let t = TestStorage::new("replace");
+        let mut upload = t.storage.create("a", 0).unwrap();
+        upload.write_all(b"new").unwrap();
+        fs::write(t.root.join("a"), "old").unwrap();
+        let e = upload.commit(false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&t.storage, "a"), "old");
+        assert_eq!(t.storage.partial("a").unwrap(), None);
+
+        let mut upload = t.storage.create("b", 0).unwrap();
+        upload.write_all(b"new").unwrap();
+        upload.commit(false).unwrap();
+        assert_eq!(read(&t.storage, "b"), "new");
+        assert_eq!(t.storage.partial("b").unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_upload_is_kept_and_resumed() {
+        let t = TestStorage::new("resume");
+        let mut upload = t.storage.create("a", 0).unwrap();
+        upload.write_all(b"hel").unwrap();
+        drop(upload);
+        assert_eq!(t.storage.partial("a").unwrap(), Some(3));
+        let f = stat_for_resume(&t.storage, "a", 0).unwrap();
+        assert_eq!((f.size, f.partial_size), (0, 3));
+
+        let mut upload = t.storage.create("a", 3).unwrap();
+        let mut prefix = String::new();
+        upload.read_back().unwrap().read_to_string(&mut prefix).unwrap();
+        assert_eq!(prefix, "hel");
+        upload.write_all(b"lo").unwrap();
+        upload.commit(true).unwrap();
+        assert_eq!(read(&t.storage, "a"), "hello");
+    }
+
+    #[test]
+    fn resume_carries_over_the_existing_file() {
+        let t = TestStorage::new("prefix");
+        fs::write(t.root.join("a"), "hello world").unwrap();
+        let mut upload = t.storage.create("a", 5).unwrap();
+        upload.write_all(b"!").unwrap();
+        upload.commit(true).unwrap();
+        assert_eq!(read(&t.storage, "a"), "hello!");
+        assert!(t.storage.create("a", 7).is_err());
+    }
+
+    #[test]
+    fn stat_digests_the_requested_prefix() {
+        let t = TestStorage::new("stat-prefix");
+        fs::write(t.root.join("a"), "hello world").unwrap();
+        let digest = |data: &[u8]| {
+            let mut checksum = Checksum::new();
+            checksum.update(data);
+            checksum.finish()
+        };
+        let prefix_digest = |prefix| stat_for_resume(&t.storage, "a", prefix).unwrap().prefix_digest;
+        assert_eq!(prefix_digest(5), Some(digest(b"hello")));
+        assert_eq!(prefix_digest(11), Some(digest(b"hello world")));
+        assert_eq!(prefix_digest(12), None);
+        assert_eq!(prefix_digest(0), None);
+    }
+
+    #[test]
+    fn discarded_upload_is_removed() {
+        let t = TestStorage::new("discard");
+        let mut upload = t.storage.create("a", 0).unwrap();
+        upload.write_all(b"bad").unwrap();
+        upload.discard();
+        assert_eq!(t.storage.partial("a").unwrap(), None);
+    }
+
+    #[test]
+    fn one_upload_of_a_name_at_a_time() {
+        let t = TestStorage::new("running");
+        let upload = t.storage.create("a", 0).unwrap();
+        assert!(t.storage.create("a", 0).is_err());
+        assert!(t.storage.create("b", 0).is_ok());
+        drop(upload);
+        assert!(t.storage.create("a", 0).is_ok());
+    }
+
+    #[test]
+    fn orphans_are_removed_only_when_old() {
+        let t = TestStorage::new("orphans");
+        let mut upload = t.storage.create("a", 0).unwrap();
+        upload.write_all(b"x").unwrap();
+        drop(upload);
+        assert_eq!(t.storage.remove_orphans(Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(t.storage.partial("a").unwrap(), Some(1));
+
+        let running = t.storage.create("b", 0).unwrap();
+        assert_eq!(t.storage.remove_orphans(Duration::from_secs(0)).unwrap(), 1);
+        assert_eq!(t.storage.partial("a").unwrap(), None);
+        assert_eq!(t.storage.partial("b").unwrap(), Some(0));
+        drop(running);
+    }
+
+    #[test]
+    fn kept_uploads_count_as_bytes_once_they_stop() {
+        let t = TestStorage::new("usage");
+        fs::write(t.root.join("a"), b"0123").unwrap();
+        let mut upload = t.storage.create("b", 0).unwrap();
+        upload.write_all(b"567").unwrap();
+        upload.flush().unwrap();
+        let usage = t.storage.usage().unwrap();
+        assert_eq!((usage.bytes, usage.files), (4, 1));
+
+        drop(upload);
+        let usage = t.storage.usage().unwrap();
+        assert_eq!((usage.bytes, usage.files), (7, 1));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{SrwscError, ErrorCode};
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tonic::transport::{Identity, ServerTlsConfig};
+use x509_parser::parse_x509_certificate;
+
+fn load_certs(path: &str)
+    -> Result<Vec<Certificate>, SrwscError> {
+    let file = File::open(path).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("cannot open certificate {}: {}", path, e))
+    })?;
+    let chain = certs(&mut BufReader::new(file)).map_err(|_| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("invalid certificate {}", path))
+    })?;
+    if chain.is_empty() {
+        return Err(SrwscError::new(ErrorCode::ErrorTls,
+                                   format!("no certificate found in {}", path)));
+    }
+    Ok(chain)
+}
+
+fn load_private_key(path: &str)
+    -> Result<PrivateKey, SrwscError> {
+    let open = || File::open(path).map(BufReader::new).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("cannot open private key {}: {}", path, e))
+    });
+
+    let mut keys = pkcs8_private_keys(&mut open()?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open()?).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(SrwscError::new(ErrorCode::ErrorTls,
+                                    format!("no private key found in {}", path))),
+    }
+}
+
+pub fn server_config(cert: &str, key: &str)
+    -> Result<Arc<ServerConfig>, SrwscError> {
+    let chain = load_certs(cert)?;
+    let key = load_private_key(key)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls, e.to_string())
+    })?;
+    Ok(Arc::new(config))
+}
+
+fn read_pem(path: &str)
+    -> Result<Vec<u8>, SrwscError> {
+    fs::read(path).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("cannot read {}: {}", path, e))
+    })
+}
+
+pub fn grpc_server_config(cert: &str,
+                          key: &str,
+                          client_ca: &Option<String>)
+    -> Result<ServerTlsConfig, SrwscError> {
+    let identity = Identity::from_pem(read_pem(cert)?, read_pem(key)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca) = client_ca {
+        config = config.client_ca_root(
+            tonic::transport::Certificate::from_pem(read_pem(ca)?));
+    }
+    Ok(config)
+}
+
+// Each non-empty line of the identity map is "identity = certificate subject",
+// e.g. "ci-bot = CN=ci.example.com, O=Example".
+pub fn load_identity_map(path: &str)
+    -> Result<HashMap<String, String>, SrwscError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        SrwscError::new(ErrorCode::ErrorTls,
+                        format!("cannot read identity map {}: {}", path, e))
+    })?;
+
+    let mut map = HashMap::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.find('=') {
+            Some(index) => {
+                let identity = line[..index].trim().to_string();
+                let subject = line[index + 1..].trim().to_string();
+                map.insert(subject, identity);
+            },
+            None => return Err(SrwscError::new(ErrorCode::ErrorTls,
+                                               format!("{}:{}: expected \"identity = subject\"",
+                                                       path, n + 1))),
+        }
+    }
+    Ok(map)
+}
+
+// Maps a DER encoded client certificate to an identity. Subjects listed in
+// the identity map win, otherwise the certificate's common name is used.
+pub fn peer_identity(der: &[u8],
+                     identity_map: &HashMap<String, String>)
+    -> Option<String> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+    let subject = cert.subject();
+    if let Some(identity) = identity_map.get(&subject.to_string()) {
+        return Some(identity.clone());
+    }
+    let common_name = subject.iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+    common_name
+}
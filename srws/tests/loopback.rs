@@ -0,0 +1,286 @@
+// Runs the server on a loopback port and puts, resumes and gets a file over
+// each protocol, speaking the protocol directly rather than through srwc.
+#![allow(clippy::redundant_field_names)]
+
+use srwsc_common::checksum::Checksum;
+use srwsc_common::transfer;
+
+use prost::Message;
+use std::fs;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod pb {
+    tonic::include_proto!("srwsc.pb");
+}
+
+use pb::srwsc_client::SrwscClient;
+use pb::{FileInfo, FileStream, SrwscRequest};
+
+const SIZE: usize = 3 * 1024 * 1024 + 17;
+
+struct Server {
+    process: Child,
+    root: PathBuf,
+    address: String,
+}
+
+impl Server {
+    fn start(server_type: &str) -> Server {
+        let root = std::env::temp_dir()
+            .join(format!("srws-loopback-{}-{}", server_type, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        // The port is free again by the time the server binds it, barring a race
+        // with another process.
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let process = Command::new(env!("CARGO_BIN_EXE_srws"))
+            .args(["start", "-t", server_type, "-a", &address, "-r", root.to_str().unwrap()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server {
+            process: process,
+            root: root,
+            address: address,
+        };
+
+        let started = Instant::now();
+        while TcpStream::connect(&server.address).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "server did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn content() -> Vec<u8> {
+    (0..SIZE).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn digest(data: &[u8]) -> String {
+    let mut checksum = Checksum::new();
+    checksum.update(data);
+    checksum.finish()
+}
+
+fn read_field(stream: &mut TcpStream) -> String {
+    let mut field = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        assert_eq!(stream.read(&mut byte).unwrap(), 1, "connection closed");
+        if byte[0] == b'\r' {
+            return String::from_utf8(field).unwrap();
+        }
+        field.push(byte[0]);
+    }
+}
+
+fn write_field(stream: &mut TcpStream, field: &str) {
+    stream.write_all(format!("{}\r", field).as_bytes()).unwrap();
+}
+
+fn send_message(stream: &mut TcpStream, msg: &str) {
+    write_field(stream, &(msg.len() + 1).to_string());
+    assert_eq!(read_field(stream), "ACK");
+    write_field(stream, msg);
+    assert_eq!(read_field(stream), "ACK");
+}
+
+fn get_message(stream: &mut TcpStream) -> String {
+    read_field(stream);
+    write_field(stream, "ACK");
+    let msg = read_field(stream);
+    write_field(stream, "ACK");
+    msg
+}
+
+fn receive_frame<M: Message + Default>(stream: &mut TcpStream) -> M {
+    let size = read_field(stream).parse::<usize>().unwrap();
+    write_field(stream, "ACK");
+    let mut bytes = vec![0u8; size];
+    stream.read_exact(&mut bytes).unwrap();
+    write_field(stream, "ACK");
+    M::decode(&bytes[..]).unwrap()
+}
+
+fn tcp_stat(address: &str, name: &str) -> FileInfo {
+    let mut stream = TcpStream::connect(address).unwrap();
+    send_message(&mut stream, &format!("stat {}", name));
+    assert_eq!(get_message(&mut stream), "file info");
+    receive_frame(&mut stream)
+}
+
+// Announces `data` as the rest of `name` from `offset`, and returns the
+// connection once the server has accepted it, or None when it refused.
+fn tcp_start_put(address: &str, name: &str, offset: usize, data: &[u8])
+    -> Option<TcpStream> {
+    let mut stream = TcpStream::connect(address).unwrap();
+    send_message(&mut stream, &format!("put {} -- {}", offset, name));
+    send_message(&mut stream, "prepare transfer file");
+    write_field(&mut stream, &data.len().to_string());
+    match read_field(&mut stream).as_ref() {
+        "ACK" => Some(stream),
+        "NAK" => None,
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+fn tcp_finish_put(mut stream: TcpStream, whole: &[u8], data: &[u8]) {
+    stream.write_all(data).unwrap();
+    send_message(&mut stream, &digest(whole));
+    assert_eq!(get_message(&mut stream), "checksum ok");
+}
+
+fn tcp_get(address: &str, name: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(address).unwrap();
+    send_message(&mut stream, &format!("get -- {}", name));
+    assert_eq!(get_message(&mut stream), "prepare transfer file");
+    let size = transfer::read_size(&mut stream).unwrap();
+    write_field(&mut stream, "ACK");
+    let mut data = vec![0u8; size as usize];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(get_message(&mut stream), digest(&data));
+    send_message(&mut stream, "checksum ok");
+    data
+}
+
+// Waits for the server to keep what it got of an interrupted upload.
+fn wait_for_partial<F: Fn() -> u64>(partial_size: F, expected: usize) {
+    let started = Instant::now();
+    while partial_size() != expected as u64 {
+        assert!(started.elapsed() < Duration::from_secs(10), "upload was not kept");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn tcp_put_resume_get() {
+    let server = Server::start("http");
+    let data = content();
+
+    tcp_finish_put(tcp_start_put(&server.address, "whole.bin", 0, &data).unwrap(), &data, &data);
+    assert_eq!(tcp_get(&server.address, "whole.bin"), data);
+    assert_eq!(tcp_stat(&server.address, "whole.bin").digest, digest(&data));
+
+    // Half of the data, and the connection goes away.
+    let half = SIZE / 2;
+    let mut stream = tcp_start_put(&server.address, "resumed.bin", 0, &data).unwrap();
+    stream.write_all(&data[..half]).unwrap();
+    drop(stream);
+    wait_for_partial(|| tcp_stat(&server.address, "resumed.bin").partial_size, half);
+
+    // The interrupted session may still hold the upload for a moment.
+    let started = Instant::now();
+    let stream = loop {
+        if let Some(stream) = tcp_start_put(&server.address, "resumed.bin", half, &data[half..]) {
+            break stream;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "upload was not resumed");
+        thread::sleep(Duration::from_millis(50));
+    };
+    tcp_finish_put(stream, &data, &data[half..]);
+    assert_eq!(tcp_stat(&server.address, "resumed.bin").partial_size, 0);
+    assert_eq!(tcp_get(&server.address, "resumed.bin"), data);
+}
+
+async fn grpc_client(address: &str) -> SrwscClient<tonic::transport::Channel> {
+    SrwscClient::connect(format!("http://{}", address)).await.unwrap()
+}
+
+async fn grpc_put(address: &str, name: &str, offset: usize, length: usize,
+                  messages: Vec<FileStream>)
+    -> Result<(), tonic::Status> {
+    let mut client = grpc_client(address).await;
+    let mut request = tonic::Request::new(futures::stream::iter(messages));
+    let metadata = request.metadata_mut();
+    metadata.insert("filename", name.parse().unwrap());
+    metadata.insert("offset", offset.to_string().parse().unwrap());
+    metadata.insert("length", length.to_string().parse().unwrap());
+    client.put(request).await.map(|_| ())
+}
+
+async fn grpc_get(address: &str, name: &str) -> Vec<u8> {
+    let mut client = grpc_client(address).await;
+    let request = SrwscRequest {
+        filename: name.to_string(),
+        offset: 0,
+        length: 0,
+    };
+    let mut stream = client.get(request).await.unwrap().into_inner();
+    let mut data = Vec::new();
+    let mut received_digest = String::new();
+    while let Some(msg) = stream.message().await.unwrap() {
+        data.extend_from_slice(&msg.data);
+        if !msg.digest.is_empty() {
+            received_digest = msg.digest;
+        }
+    }
+    assert_eq!(received_digest, digest(&data));
+    data
+}
+
+async fn grpc_partial_size(address: &str, name: &str) -> u64 {
+    let mut client = grpc_client(address).await;
+    let request = SrwscRequest {
+        filename: name.to_string(),
+        offset: 0,
+        length: 0,
+    };
+    client.stat(request).await.unwrap().into_inner().partial_size
+}
+
+fn chunks(data: &[u8], digest: Option<String>) -> Vec<FileStream> {
+    let mut messages: Vec<FileStream> = data.chunks(64 * 1024)
+        .map(|chunk| FileStream {
+            data: chunk.to_vec(),
+            digest: String::new(),
+        })
+        .collect();
+    if let Some(digest) = digest {
+        messages.push(FileStream {
+            data: Vec::new(),
+            digest: digest,
+        });
+    }
+    messages
+}
+
+#[tokio::test]
+async fn grpc_put_resume_get() {
+    let server = Server::start("grpc");
+    let data = content();
+
+    grpc_put(&server.address, "whole.bin", 0, SIZE, chunks(&data, Some(digest(&data))))
+        .await
+        .unwrap();
+    assert_eq!(grpc_get(&server.address, "whole.bin").await, data);
+
+    // The stream ends after half of the announced length.
+    let half = SIZE / 2;
+    let status = grpc_put(&server.address, "resumed.bin", 0, SIZE, chunks(&data[..half], None))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::DataLoss);
+    assert_eq!(grpc_partial_size(&server.address, "resumed.bin").await, half as u64);
+
+    grpc_put(&server.address, "resumed.bin", half, SIZE - half,
+             chunks(&data[half..], Some(digest(&data))))
+        .await
+        .unwrap();
+    assert_eq!(grpc_partial_size(&server.address, "resumed.bin").await, 0);
+    assert_eq!(grpc_get(&server.address, "resumed.bin").await, data);
+}
@@ -0,0 +1,70 @@
+use sha2::{Digest, Sha256};
+use std::io;
+use std::io::prelude::*;
+
+pub const ALGORITHM: &str = "sha256";
+
+// Digest of the complete file a transfer leaves on the receiving side. When a
+// transfer resumes, both ends feed in the prefix they already share first.
+pub struct Checksum {
+    hasher: Sha256,
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum::new()
+    }
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Checksum {
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.input(data);
+    }
+
+    // Feeds the next `len` bytes of `reader`, leaving it positioned right
+    // after them.
+    pub fn update_from<R: Read + ?Sized>(&mut self, reader: &mut R, len: u64)
+        -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        let mut remaining_data = len;
+        while remaining_data != 0 {
+            let want = if remaining_data < buf.len() as u64 {
+                remaining_data as usize
+            } else {
+                buf.len()
+            };
+            let n = reader.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("{} bytes short of the prefix",
+                                                  remaining_data)));
+            }
+            self.update(&buf[..n]);
+            remaining_data -= n as u64;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.result())
+    }
+}
+
+pub fn file_digest<R: Read + ?Sized>(reader: &mut R)
+    -> io::Result<String> {
+    let mut checksum = Checksum::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => checksum.update(&buf[..n]),
+        }
+    }
+    Ok(checksum.finish())
+}
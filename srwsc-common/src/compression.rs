@@ -0,0 +1,366 @@
+use crate::checksum::Checksum;
+use crate::transfer::CHUNK_SIZE;
+
+use flate2::write::{GzDecoder, GzEncoder};
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+
+// Optional compression of file data, chosen per transfer: the client offers
+// the algorithms it wants in order of preference and the server picks one.
+// The digest always covers the uncompressed file.
+pub const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    // The preferred algorithm first, the other one as a fallback.
+    pub fn offer(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip,zstd",
+            Compression::Zstd => "zstd,gzip",
+        }
+    }
+}
+
+// Picks the first supported algorithm of a comma separated offer.
+pub fn negotiate(offer: &str) -> Compression {
+    offer.split(',')
+        .filter_map(|name| Compression::from_name(name.trim()))
+        .next()
+        .unwrap_or(Compression::None)
+}
+
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(compression: Compression, writer: W) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(writer,
+                                                              flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(writer,
+                                                                                 ZSTD_LEVEL)?),
+        })
+    }
+
+    // Writes out what the compressor still holds and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(writer) => Ok(writer),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(writer) => writer.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(writer) => writer.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+pub enum Decoder<W: Write> {
+    None(W),
+    Gzip(GzDecoder<W>),
+    Zstd(zstd::stream::write::Decoder<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(compression: Compression, writer: W) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Decoder::None(writer),
+            Compression::Gzip => Decoder::Gzip(GzDecoder::new(writer)),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(writer)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decoder::None(writer) => Ok(writer),
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            },
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decoder::None(writer) => writer.write(buf),
+            Decoder::Gzip(decoder) => decoder.write(buf),
+            Decoder::Zstd(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::None(writer) => writer.flush(),
+            Decoder::Gzip(decoder) => decoder.flush(),
+            Decoder::Zstd(decoder) => decoder.flush(),
+        }
+    }
+}
+
+// Where received data ends up: the file, through the checksum. It refuses to
+// grow beyond `limit`, so that a small compressed stream cannot fill the disk.
+pub struct Output<'a, W: Write + ?Sized> {
+    file: &'a mut W,
+    checksum: &'a mut Checksum,
+    written: u64,
+    limit: u64,
+}
+
+impl<'a, W: Write + ?Sized> Output<'a, W> {
+    pub fn new(file: &'a mut W, checksum: &'a mut Checksum, limit: u64) -> Self {
+        Output {
+            file: file,
+            checksum: checksum,
+            written: 0,
+            limit: limit,
+        }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<'a, W: Write + ?Sized> Write for Output<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.limit - self.written {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("more than the {} bytes announced",
+                                              self.limit)));
+        }
+        self.file.write_all(buf)?;
+        self.checksum.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Compressed data has no size known in advance, so over TCP it is cut into
+// frames of at most CHUNK_SIZE bytes, each preceded by its length as 4 bytes
+// in network order. An empty frame ends the data.
+struct FrameWriter<'a, S: Write + ?Sized> {
+    stream: &'a mut S,
+    buf: Vec<u8>,
+    sent: u64,
+}
+
+impl<'a, S: Write + ?Sized> FrameWriter<'a, S> {
+    fn new(stream: &'a mut S) -> Self {
+        FrameWriter {
+            stream: stream,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            sent: 0,
+        }
+    }
+
+    fn send_frame(&mut self, len: usize) -> io::Result<()> {
+        self.stream.write_all(&(len as u32).to_be_bytes())?;
+        self.stream.write_all(&self.buf[..len])?;
+        self.buf.drain(..len);
+        self.sent += 4 + len as u64;
+        Ok(())
+    }
+
+    // Sends the last frames and returns the number of bytes put on the wire.
+    fn finish(mut self) -> io::Result<u64> {
+        if !self.buf.is_empty() {
+            self.send_frame(self.buf.len())?;
+        }
+        self.send_frame(0)?;
+        self.stream.flush()?;
+        Ok(self.sent)
+    }
+}
+
+impl<'a, S: Write + ?Sized> Write for FrameWriter<'a, S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= CHUNK_SIZE {
+            self.send_frame(CHUNK_SIZE)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Counterpart of transfer::send_data for a compressed transfer. Returns the
+// number of bytes sent on the wire.
+pub fn send_compressed<R, S>(file: &mut R,
+                             length: u64,
+                             checksum: &mut Checksum,
+                             compression: Compression,
+                             stream: &mut S)
+    -> io::Result<u64>
+    where R: Read + ?Sized,
+          S: Write + ?Sized {
+    let mut encoder = Encoder::new(compression, FrameWriter::new(stream))?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining_data = length;
+    while remaining_data != 0 {
+        let len = cmp::min(remaining_data, CHUNK_SIZE as u64) as usize;
+        let n = match file.read(&mut buf[..len]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               format!("file ended {} bytes early",
+                                                       remaining_data))),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        checksum.update(&buf[..n]);
+        encoder.write_all(&buf[..n])?;
+        remaining_data -= n as u64;
+    }
+    encoder.finish()?.finish()
+}
+
+// Counterpart of transfer::receive_data for a compressed transfer. `length` is
+// the uncompressed size; returns the number of bytes received on the wire.
+pub fn receive_compressed<S, W>(stream: &mut S,
+                                file: &mut W,
+                                length: u64,
+                                checksum: &mut Checksum,
+                                compression: Compression)
+    -> io::Result<u64>
+    where S: Read + ?Sized,
+          W: Write + ?Sized {
+    let mut decoder = Decoder::new(compression, Output::new(file, checksum, length))?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes(header) as usize;
+        received += 4 + len as u64;
+        if len == 0 {
+            break;
+        }
+        if len > CHUNK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("frame of {} bytes is too large", len)));
+        }
+        stream.read_exact(&mut buf[..len])?;
+        decoder.write_all(&buf[..len])?;
+    }
+
+    let mut output = decoder.finish()?;
+    if output.written() != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("data ended after {} of {} bytes",
+                                          output.written(), length)));
+    }
+    output.flush()?;
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_picks_the_first_supported_algorithm() {
+        assert_eq!(negotiate("zstd,gzip"), Compression::Zstd);
+        assert_eq!(negotiate("gzip,zstd"), Compression::Gzip);
+        assert_eq!(negotiate("brotli, gzip"), Compression::Gzip);
+        assert_eq!(negotiate(" zstd "), Compression::Zstd);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_none() {
+        assert_eq!(negotiate(""), Compression::None);
+        assert_eq!(negotiate("brotli,lz4"), Compression::None);
+        assert_eq!(negotiate("none,zstd"), Compression::None);
+    }
+
+    #[test]
+    fn offers_are_negotiated_to_their_preference() {
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            assert_eq!(negotiate(compression.offer()), compression);
+            assert_eq!(Compression::from_name(compression.name()), Some(compression));
+        }
+    }
+
+    #[test]
+    fn compressed_data_round_trips() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 5).map(|i| (i % 7) as u8).collect();
+        for &compression in &[Compression::Gzip, Compression::Zstd] {
+            let mut wire = Vec::new();
+            let mut sent_checksum = Checksum::new();
+            let sent = send_compressed(&mut &data[..], data.len() as u64, &mut sent_checksum,
+                                       compression, &mut wire).unwrap();
+            assert_eq!(sent, wire.len() as u64);
+
+            let mut file = Vec::new();
+            let mut received_checksum = Checksum::new();
+            let received = receive_compressed(&mut &wire[..], &mut file, data.len() as u64,
+                                              &mut received_checksum, compression).unwrap();
+            assert_eq!(received, sent);
+            assert_eq!(file, data);
+            assert_eq!(received_checksum.finish(), sent_checksum.finish());
+        }
+    }
+
+    #[test]
+    fn data_beyond_the_announced_length_is_refused() {
+        let data = vec![0u8; 1000];
+        let mut wire = Vec::new();
+        send_compressed(&mut &data[..], data.len() as u64, &mut Checksum::new(),
+                        Compression::Zstd, &mut wire).unwrap();
+        let received = receive_compressed(&mut &wire[..], &mut Vec::new(), 10,
+                                          &mut Checksum::new(), Compression::Zstd);
+        assert!(received.is_err());
+    }
+}
@@ -0,0 +1,9 @@
+// The parts of the file transfer that srws and srwc have to agree on: the
+// digest, the compression and the bulk data path of the TCP protocol.
+
+// The code spells out `field: field` in struct literals.
+#![allow(clippy::redundant_field_names)]
+
+pub mod checksum;
+pub mod compression;
+pub mod transfer;
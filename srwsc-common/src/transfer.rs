@@ -0,0 +1,255 @@
+use crate::checksum::Checksum;
+
+use std::io;
+use std::io::prelude::*;
+#[cfg(target_os = "linux")]
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+// Bulk data path of the TCP protocol. After the size handshake the file goes
+// over the wire as exactly that many raw bytes, in large chunks and without a
+// flush in between; the control messages around it keep their ACK protocol.
+//
+// On Linux, srws hands uncompressed downloads over plain TCP to the kernel
+// with sendfile, and computes the digest in a pass of its own over the file.
+// Everything else goes through user space: compression and TLS have to see
+// every byte anyway.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+// Most a single sendfile call moves on Linux.
+#[cfg(target_os = "linux")]
+const SENDFILE_SIZE: u64 = 0x7fff_f000;
+
+// Sizes are sent as "DIGITS\r" and may be longer than a control read, so they
+// are read one byte at a time up to the terminator.
+pub fn read_size<S: Read + ?Sized>(stream: &mut S)
+    -> io::Result<u64> {
+    let mut digits = String::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "connection closed before the size"));
+        }
+        match byte[0] {
+            b'\r' => break,
+            b if b.is_ascii_digit() && digits.len() < 20 => digits.push(b as char),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                           "size is not a number")),
+        }
+    }
+    digits.parse::<u64>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn send_data<R, S>(file: &mut R,
+                       length: u64,
+                       checksum: &mut Checksum,
+                       stream: &mut S)
+    -> io::Result<()>
+    where R: Read + ?Sized,
+          S: Write + ?Sized {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining_data = length;
+    while remaining_data != 0 {
+        let len = if remaining_data < CHUNK_SIZE as u64 {
+            remaining_data as usize
+        } else {
+            CHUNK_SIZE
+        };
+        let n = fill(file, &mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("file ended {} bytes early",
+                                              remaining_data)));
+        }
+        checksum.update(&buf[..n]);
+        stream.write_all(&buf[..n])?;
+        remaining_data -= n as u64;
+    }
+    stream.flush()
+}
+
+// Counterpart of send_data that copies `length` bytes from the current
+// position of `file` to `socket` in the kernel, without the checksum. The
+// socket may be non-blocking; the call then waits for room in its buffer, and
+// fails with TimedOut once the peer has not read anything for `timeout`.
+#[cfg(target_os = "linux")]
+pub fn sendfile(file: &File, length: u64, socket: &TcpStream, timeout: Duration)
+    -> io::Result<()> {
+    let mut remaining_data = length;
+    while remaining_data != 0 {
+        let len = cmp::min(remaining_data, SENDFILE_SIZE) as usize;
+        let n = unsafe {
+            libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), std::ptr::null_mut(), len)
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => {
+                    wait_writable(socket.as_raw_fd(), timeout)?;
+                    continue;
+                },
+                _ => return Err(e),
+            }
+        }
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("file ended {} bytes early",
+                                              remaining_data)));
+        }
+        remaining_data -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn wait_writable(socket: RawFd, timeout: Duration) -> io::Result<()> {
+    let mut poll_fd = libc::pollfd {
+        fd: socket,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    let millis = cmp::min(timeout.as_millis(), libc::c_int::MAX as u128) as libc::c_int;
+    loop {
+        match unsafe { libc::poll(&mut poll_fd, 1, millis) } {
+            0 => return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                           format!("peer did not read for {:?}", timeout))),
+            n if n > 0 => return Ok(()),
+            _ => {},
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+// Never reads past `length`, so whatever the peer sends next stays in the
+// stream for the next control message.
+pub fn receive_data<S, W>(stream: &mut S,
+                          file: &mut W,
+                          length: u64,
+                          checksum: &mut Checksum)
+    -> io::Result<()>
+    where S: Read + ?Sized,
+          W: Write + ?Sized {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining_data = length;
+    while remaining_data != 0 {
+        let len = if remaining_data < CHUNK_SIZE as u64 {
+            remaining_data as usize
+        } else {
+            CHUNK_SIZE
+        };
+        let n = match stream.read(&mut buf[..len]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               format!("connection closed with {} bytes \
+                                                        remaining", remaining_data))),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        checksum.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+        remaining_data -= n as u64;
+    }
+    file.flush()
+}
+
+// Reads until `buf` is full or the reader is exhausted, so that every write to
+// the socket carries a whole chunk.
+fn fill<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8])
+    -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::SeekFrom;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("srwsc-transfer-{}-{}", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    // Sends `length` bytes of the file at `path` from `offset` to a loopback
+    // peer, and returns what the peer got.
+    fn sendfile_loopback(path: &PathBuf, offset: u64, length: u64)
+        -> (io::Result<()>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            TcpStream::connect(address).unwrap().read_to_end(&mut received).unwrap();
+            received
+        });
+        let (socket, _) = listener.accept().unwrap();
+        // Like the sockets of srws.
+        socket.set_nonblocking(true).unwrap();
+        let mut file = File::open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        let sent = sendfile(&file, length, &socket, Duration::from_secs(10));
+        drop(socket);
+        (sent, receiver.join().unwrap())
+    }
+
+    #[test]
+    fn sendfile_sends_from_the_file_position() {
+        let data: Vec<u8> = (0..4 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("position", &data);
+        let (sent, received) = sendfile_loopback(&path, 10, data.len() as u64 - 10);
+        fs::remove_file(&path).unwrap();
+        sent.unwrap();
+        assert_eq!(received, &data[10..]);
+    }
+
+    #[test]
+    fn sendfile_fails_on_a_short_file() {
+        let path = temp_file("short", b"0123456789");
+        let (sent, received) = sendfile_loopback(&path, 0, 20);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(received, b"0123456789");
+    }
+
+    #[test]
+    fn sendfile_times_out_when_the_peer_stops_reading() {
+        let path = temp_file("stalled", b"");
+        // More than the socket buffers on both ends can take.
+        File::create(&path).unwrap().set_len(256 * 1024 * 1024).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let file = File::open(&path).unwrap();
+        let sent = sendfile(&file, 256 * 1024 * 1024, &socket, Duration::from_millis(200));
+        fs::remove_file(&path).unwrap();
+        drop(peer);
+        assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}